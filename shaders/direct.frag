@@ -4,6 +4,8 @@
 
 layout(location = 0) in vec3 fragPosition;
 layout(location = 1) in vec3 fragNormal;
+layout(location = 2) in vec2 fragUv;
+layout(location = 3) flat in uint fragMaterial;
 
 layout(location = 0) out vec3 fragColor;
 
@@ -14,28 +16,86 @@ layout(binding = 0) uniform restrict readonly RealTimeBuffer {
 
 layout(binding = 3) uniform sampler3D radianceTextures[LM_LAYERS * SH_CS];
 
-vec3[SH_CS] loadSHCoefs(vec3 position, int layer, vec3 origin) {   
-    vec3 texIndex = radTextureIndexAtPos(position, layer, origin);
+layout(binding = 8) buffer restrict readonly MaterialBuffer {
+    Material materials[];
+} matBuffer;
+
+layout(binding = 9) uniform sampler2DArray materialTextures;
+
+layout(binding = 10) uniform restrict readonly ClipmapBuffer {
+    ivec4 corners[LM_LAYERS]; // of the volume of every layer, see radOriginAtCorner
+    ivec4 previousCorners[LM_LAYERS]; // before the last move, the voxels in both are kept
+} clipmap;
+
+// radiance arriving from outside of the volume, see Environment in scene/environment.rs
+layout(binding = 11) uniform restrict readonly EnvironmentBuffer {
+    vec4 coefs[SH_CS];
+} environment;
+
+// voxels at the edge of every layer over which it fades into the next one, or the environment
+#define BLEND_VOXELS 8.0
+
+// white for materials without a texture, sampled outside of any branch for the implicit LOD
+vec3 sampleMaterialTexture(uint layer) {
+    vec3 color = texture(materialTextures, vec3(fragUv, layer == NO_TEXTURE ? 0 : layer)).rgb;
+    return layer == NO_TEXTURE ? vec3(1.0) : color;
+}
+
+vec3[SH_CS] loadSHCoefs(vec3 position, int layer) {
+    vec3 texIndex = radTextureIndexAtPos(position, layer);
     vec3[SH_CS] coefs;
     for (int i = 0; i < SH_CS; i++) {
-        coefs[i] = texture(radianceTextures[layer * LM_LAYERS + i], texIndex).rgb;
+        coefs[i] = textureLod(radianceTextures[layer * SH_CS + i], texIndex, 0.0).rgb; // no mips
     }
     return coefs;
 }
 
-vec3 sampleRadiance(vec3 position, vec3 dir) {
-    vec3 origin = vec3(0.0); // TODO: movable origin
-    
-    int layer = radLayerAtPos(position, origin);
-    if (layer >= LM_LAYERS) { return vec3(0.0); }
-    vec3[SH_CS] coefs = loadSHCoefs(position, layer, origin);
+vec3 sampleEnvironment(vec3 dir) {
+    vec3[SH_CS] coefs;
+    for (int i = 0; i < SH_CS; i++) {
+        coefs[i] = environment.coefs[i].rgb;
+    }
     return evaluateRGBSphericalHarmonics(dir, coefs);
 }
 
+vec3 sampleLayer(vec3 position, vec3 dir, int layer) {
+    if (layer >= LM_LAYERS) {
+        return sampleEnvironment(dir);
+    }
+    return evaluateRGBSphericalHarmonics(dir, loadSHCoefs(position, layer));
+}
+
+// weight of the next layer, which rises from 0 to 1 over the last voxels before the layer's edge
+float layerBlend(vec3 position, int layer) {
+    vec3 origin = radOriginAtCorner(clipmap.corners[layer].xyz, layer);
+    float toEdge = float(RADIANCE_SIZE / 2 - 1) - maximum(abs(position - origin)) / radUnitSizeLayer(layer);
+    return 1.0 - clamp(toEdge / BLEND_VOXELS, 0.0, 1.0);
+}
+
+vec3 sampleRadiance(vec3 position, vec3 dir) {
+    int layer = radLayerAtPos(position, clipmap.corners);
+    vec3 radiance = sampleLayer(position, dir, layer);
+    if (layer >= LM_LAYERS) {
+        return radiance;
+    }
+
+    // the next layer covers the edge of this one, which avoids seams at the cascade boundaries
+    float blend = layerBlend(position, layer);
+    if (blend > 0.0) {
+        radiance = mix(radiance, sampleLayer(position, dir, layer + 1), blend);
+    }
+    return radiance;
+}
+
 void main() {
     vec3 direction = normalize(fragPosition - rt.position);
     // normal direction is the most accurate (for diffuse lighting)
-    fragColor = sampleRadiance(fragPosition - EPSILON * direction, -fragNormal);
+    vec3 incoming = sampleRadiance(fragPosition - EPSILON * direction, -normalize(fragNormal));
+
+    Material mat = matBuffer.materials[fragMaterial];
+    vec3 reflectance = mat.reflectance * sampleMaterialTexture(mat.reflectanceTexture);
+    vec3 emittance = mat.emittance * sampleMaterialTexture(mat.emittanceTexture);
+    fragColor = reflectance * incoming + emittance;
 }
 
 // FIXME: looking straight down gives a black screen; sampling problem or rasterized rendering problem
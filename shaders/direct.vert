@@ -1,7 +1,11 @@
 #version 460
 
+#include "includes_general.glsl"
+
 layout(location = 0) out vec3 worldPosition;
 layout(location = 1) out vec3 normal;
+layout(location = 2) out vec2 uv;
+layout(location = 3) flat out uint material;
 
 layout(binding = 0) uniform restrict readonly RealTimeBuffer {
     mat4 projection_view;
@@ -16,22 +20,29 @@ layout(binding = 2) buffer restrict readonly VertexIndexBuffer {
     uint indices[];
 } vertexIndexBuffer;
 
-vec3 calculateNormal() {
-    uint i = gl_VertexIndex / 3 * 3; // rounds down to the nearest multiple of 3
+layout(binding = 4) buffer restrict readonly InstanceBuffer {
+    Instance instances[];
+} instanceBuffer;
 
-    vec3 a = vertexBuffer.vertices[vertexIndexBuffer.indices[i    ]].xyz;
-    vec3 b = vertexBuffer.vertices[vertexIndexBuffer.indices[i + 1]].xyz;
-    vec3 c = vertexBuffer.vertices[vertexIndexBuffer.indices[i + 2]].xyz;
+layout(binding = 5) buffer restrict readonly NormalBuffer {
+    vec4 normals[];
+} normalBuffer;
 
-    vec3 ab = normalize(b - a);
-    vec3 bc = normalize(c - b);
+layout(binding = 6) buffer restrict readonly UvBuffer {
+    vec2 uvs[];
+} uvBuffer;
 
-    return normalize(cross(ab, bc));
-}
+layout(binding = 7) buffer restrict readonly MaterialIndexBuffer {
+    uint materials[];
+} matIdxBuffer;
 
 void main() {
-    vec3 position = vertexBuffer.vertices[vertexIndexBuffer.indices[gl_VertexIndex]].xyz;
+    uint index = vertexIndexBuffer.indices[gl_VertexIndex];
+    Instance instance = instanceBuffer.instances[gl_InstanceIndex];
+    vec3 position = (instance.transform * vec4(vertexBuffer.vertices[index].xyz, 1.0)).xyz;
     gl_Position = rt.projection_view * vec4(position, 1.0);
     worldPosition = position;
-    normal = calculateNormal();
+    normal = normalize(instance.normalMatrix * normalBuffer.normals[index].xyz);
+    uv = uvBuffer.uvs[index];
+    material = instance.material == NO_MATERIAL ? matIdxBuffer.materials[gl_VertexIndex / 3] : instance.material;
 }
@@ -2,47 +2,99 @@
 
 #define SH_cosLobe_C0 0.886226925 // sqrt(pi)/2
 #define SH_cosLobe_C1 1.02332671 // sqrt(pi/3)
-// #define SH_cosLobe_C2 0.495415912 // sqrt(5*pi)/8
+#define SH_cosLobe_C2 0.495415912 // sqrt(5*pi)/8
 
 #define SH_norm_C0 0.28209479 // used to normalize l=0, m=0
 
+#define NO_MATERIAL 0xFFFFFFFFu // instance keeps the materials of its mesh's triangles
+#define NO_TEXTURE 0xFFFFFFFFu
+
+// textures are layers of the material texture array, which multiply the constant colors
 struct Material {
     vec3 reflectance;
+    uint reflectanceTexture;
     vec3 emittance;
+    uint emittanceTexture;
+};
+
+// placement of a mesh, which is the index range [firstIndex, firstIndex + indexCount)
+struct Instance {
+    mat4 transform;
+    mat4 inverseTransform;
+    mat3 normalMatrix; // inverse transpose of the transform
+    vec3 boundsMin; // world space
+    uint firstIndex;
+    vec3 boundsMax;
+    uint indexCount;
+    uint material;
+    uint bvhRoot; // root node of the bottom level BVH of the mesh
+};
+
+// node of the two level bounding volume hierarchy, over the instances in world space in the top
+// level and over the triangles of a mesh in its local space in the bottom levels, the first child
+// of an inner node directly follows it
+struct BvhNode {
+    vec3 boundsMin;
+    uint offset; // second child of inner nodes, first entry of the leaf list of leaves
+    vec3 boundsMax;
+    uint leafCount; // 0 for inner nodes
 };
 
 struct PackedVoxel {
     uvec2 emittance;
-    uint reflectance;
+    uint reflectanceAndCoverage;
     uint normalAndIntersections;
 };
 
+// materials are averaged over the triangles in the voxel, weighted by the area inside of it
 struct Voxel {
     vec3 emittance;
     vec3 reflectance;
     vec3 normal;
     float intersections;
+    float coverage; // area of the triangles inside the voxel relative to a face of it, up to 1
 };
 
 PackedVoxel packVoxel(Voxel v) {
     return PackedVoxel(
         uvec2(packHalf2x16(v.emittance.rg), packHalf2x16(vec2(v.emittance.b, 0.0))),
-        packUnorm4x8(vec4(v.reflectance, 0.0)),
+        packUnorm4x8(vec4(v.reflectance, v.coverage)),
         packSnorm4x8(vec4(v.normal, 1.0 / (v.intersections + 1.0)))
     );
 }
 
 Voxel unpackVoxel(PackedVoxel v) {
     vec4 normalAndIntersections = unpackSnorm4x8(v.normalAndIntersections);
+    vec4 reflectanceAndCoverage = unpackUnorm4x8(v.reflectanceAndCoverage);
 
     return Voxel(
         vec3(unpackHalf2x16(v.emittance.x), unpackHalf2x16(v.emittance.y).x),
-        unpackUnorm4x8(v.reflectance).rgb,
+        reflectanceAndCoverage.rgb,
         normalAndIntersections.xyz,
-        1.0 / normalAndIntersections.w - 1.0
+        1.0 / normalAndIntersections.w - 1.0,
+        reflectanceAndCoverage.a
     );
 }
 
+// surface normal scaled by the coverage of a voxel, which makes up the occlusion volume
+uint packBlocker(Voxel v) {
+    return packSnorm4x8(vec4(v.normal * v.coverage, 0.0));
+}
+
+vec3 unpackBlocker(uint blocker) {
+    return unpackSnorm4x8(blocker).xyz;
+}
+
+// fraction of the light leaving a voxel in direction dir that isn't blocked by its surface, which
+// only blocks light leaving through its back and fully so from 45 degrees on, like the edges
+float transmittance(vec3 blocker, vec3 dir) {
+    float coverage = length(blocker);
+    if (coverage == 0.0) {
+        return 1.0;
+    }
+    return 1.0 - coverage * clamp(-dot(blocker / coverage, dir) * 1.414213562, 0.0, 1.0);
+}
+
 vec3 rotateWithQuat(vec4 q, vec3 v) {
     vec3 t = q.w * v + cross(q.xyz, v);
     return 2.0 * cross(q.xyz, t) + v;
@@ -52,49 +104,95 @@ float maximum(vec3 v) {
     return max(max(v.x, v.y), v.z);
 }
 
-int radLayerAtPos(vec3 v, vec3 origin) {
-    const float NORM = 1.0 / (float(RADIANCE_SIZE / 2) * RADIANCE_UNIT);
-    return int(log2(max(maximum(abs(v - origin)) * NORM, 0.5)) + 1.001);
-}
-
 float radUnitSizeLayer(int layer) {
     return float(1 << layer) * RADIANCE_UNIT;
 }
 
+// the volume of every layer is the RADIANCE_SIZE voxels from its corner, in voxels of the layer,
+// which follows the camera
+vec3 radOriginAtCorner(ivec3 corner, int layer) {
+    return vec3(corner + RADIANCE_SIZE / 2) * radUnitSizeLayer(layer);
+}
+
+// voxels are stored at their world index modulo the size (a power of two), so the ones that stay
+// in the volume when it moves keep their texel
+ivec3 radTexelAtIndex(ivec3 index, ivec3 corner) {
+    return (index + corner) & (RADIANCE_SIZE - 1);
+}
+
+ivec3 radIndexAtTexel(ivec3 texel, ivec3 corner) {
+    return (texel - corner) & (RADIANCE_SIZE - 1);
+}
+
+// smallest layer whose volume contains v with a margin of a voxel for filtering, LM_LAYERS if none
+int radLayerAtPos(vec3 v, ivec4[LM_LAYERS] corners) {
+    for (int layer = 0; layer < LM_LAYERS; layer++) {
+        vec3 origin = radOriginAtCorner(corners[layer].xyz, layer);
+        if (maximum(abs(v - origin)) < float(RADIANCE_SIZE / 2 - 1) * radUnitSizeLayer(layer)) {
+            return layer;
+        }
+    }
+    return LM_LAYERS;
+}
+
 vec3 posAtRadIndex(ivec3 index, int layer, vec3 origin) {
     return origin + (vec3(index - RADIANCE_SIZE / 2) + 0.5) * radUnitSizeLayer(layer);
 }
 
-vec3 radTextureIndexAtPos(vec3 pos, int layer, vec3 origin) {
-    return (pos - origin) / float(radUnitSizeLayer(layer)) * (1.0 / float(RADIANCE_SIZE)) + 0.5;
+// wraps around with a repeating sampler, like the texels of radTexelAtIndex
+vec3 radTextureIndexAtPos(vec3 pos, int layer) {
+    return pos / (radUnitSizeLayer(layer) * float(RADIANCE_SIZE));
 }
 
-vec3 evaluateRGBSphericalHarmonics(vec3 dir, vec3[SH_CS] coefs) {
+// SH_CS is 4 for L1 and 9 for L2 spherical harmonics
+float[SH_CS] shBasis(vec3 dir) {
     float x = dir.x;
     float y = dir.y;
     float z = dir.z;
 
-    vec3 s = vec3(0.0);
+    float[SH_CS] basis;
+    basis[0] = 0.28209479;
 
-    s += coefs[0] * 0.28209479;
+    basis[1] = 0.48860251 * y;
+    basis[2] = 0.48860251 * z;
+    basis[3] = 0.48860251 * x;
 
-    s += coefs[1] * 0.48860251 * y;
-    s += coefs[2] * 0.48860251 * z;
-    s += coefs[3] * 0.48860251 * x;
+#if SH_CS == 9
+    basis[4] = 1.09254843 * x * y;
+    basis[5] = 1.09254843 * y * z;
+    basis[6] = 0.31539156 * (3 * z * z - 1);
+    basis[7] = 1.09254843 * x * z;
+    basis[8] = 0.54627421 * (x * x - y * y);
+#endif
 
-    // s += coefs[4] * 1.09254843 * x * y;
-    // s += coefs[5] * 1.09254843 * y * z;
-    // s += coefs[6] * 0.31539156 * (3 * z * z - 1);
-    // s += coefs[7] * 1.09254843 * x * z;
-    // s += coefs[8] * 0.54627421 * (x * x - y * y);
+    return basis;
+}
+
+vec3 evaluateRGBSphericalHarmonics(vec3 dir, vec3[SH_CS] coefs) {
+    float[SH_CS] basis = shBasis(dir);
 
+    vec3 s = vec3(0.0);
+    for (int i = 0; i < SH_CS; i++) {
+        s += coefs[i] * basis[i];
+    }
     return s;
 }
 
 // credit to https://ericpolman.com/2016/06/28/light-propagation-volumes/
-vec4 dirToCosineLobe(vec3 dir) {
+// the bands of the basis scaled by pi, 2pi/3 and pi/4, SH_cosLobe_C2 is the zonal one
+float[SH_CS] dirToCosineLobe(vec3 dir) {
     //dir = normalize(dir);
-    return vec4(SH_cosLobe_C0, SH_cosLobe_C1 * dir.y, SH_cosLobe_C1 * dir.z, SH_cosLobe_C1 * dir.x);
+    float[SH_CS] lobe = shBasis(dir);
+    lobe[0] = SH_cosLobe_C0;
+    for (int i = 1; i < 4; i++) {
+        lobe[i] *= SH_cosLobe_C1 / 0.48860251;
+    }
+#if SH_CS == 9
+    for (int i = 4; i < 9; i++) {
+        lobe[i] *= SH_cosLobe_C2 / (2.0 * 0.31539156);
+    }
+#endif
+    return lobe;
 }
 
 struct AABB {
@@ -102,6 +200,26 @@ struct AABB {
     vec3 halfExtents;
 };
 
+// barycentric coordinates of the point in the triangle closest to the projection of p onto its
+// plane, which are clamped to the triangle's edges
+vec3 closestBarycentric(vec3[3] tri, vec3 p) {
+    vec3 ab = tri[1] - tri[0];
+    vec3 ac = tri[2] - tri[0];
+    vec3 ap = p - tri[0];
+
+    float d00 = dot(ab, ab);
+    float d01 = dot(ab, ac);
+    float d11 = dot(ac, ac);
+    float d20 = dot(ap, ab);
+    float d21 = dot(ap, ac);
+    float denom = d00 * d11 - d01 * d01;
+
+    float v = (d11 * d20 - d01 * d21) / denom;
+    float w = (d00 * d21 - d01 * d20) / denom;
+    vec3 weights = max(vec3(1.0 - v - w, v, w), 0.0);
+    return weights / (weights.x + weights.y + weights.z);
+}
+
 bool intersectAABBTriangleSAT(vec3[3] tri, vec3 aabbHalfExtents, vec3 axis) {
     float p0 = dot(tri[0], axis);
     float p1 = dot(tri[1], axis);
@@ -161,3 +279,59 @@ bool intersectAABBTriangle(vec3[3] tri, AABB aabb, out vec3 triangleNormal) {
 
     return true;
 }
+
+// a triangle clipped by the 6 planes of a box has at most 3 + 6 vertices
+#define MAX_CLIPPED_VERTICES 9
+
+// area and center of mass of the part of the triangle inside of the box (Sutherland-Hodgman clipping)
+float clipTriangleToAABB(vec3[3] tri, AABB aabb, out vec3 center) {
+    vec3 polygon[MAX_CLIPPED_VERTICES];
+    vec3 clipped[MAX_CLIPPED_VERTICES];
+    for (int i = 0; i < 3; i++) {
+        polygon[i] = tri[i] - aabb.center;
+    }
+    int count = 3;
+    center = aabb.center;
+
+    for (int plane = 0; plane < 6; plane++) {
+        int axis = plane / 2;
+        float side = plane % 2 == 0 ? -1.0 : 1.0;
+
+        int clippedCount = 0;
+        for (int i = 0; i < count; i++) {
+            vec3 a = polygon[i];
+            vec3 b = polygon[(i + 1) % count];
+            // negative inside of the plane
+            float distA = side * a[axis] - aabb.halfExtents[axis];
+            float distB = side * b[axis] - aabb.halfExtents[axis];
+
+            if (distA <= 0.0) {
+                clipped[clippedCount++] = a;
+            }
+            if ((distA <= 0.0) != (distB <= 0.0)) {
+                clipped[clippedCount++] = mix(a, b, distA / (distA - distB));
+            }
+        }
+
+        count = clippedCount;
+        if (count < 3) {
+            return 0.0;
+        }
+        polygon = clipped;
+    }
+
+    // triangle fan around the first vertex
+    vec3 areaVector = vec3(0.0);
+    vec3 weightedCenter = vec3(0.0);
+    for (int i = 1; i < count - 1; i++) {
+        vec3 fanArea = cross(polygon[i] - polygon[0], polygon[i + 1] - polygon[0]);
+        areaVector += fanArea;
+        weightedCenter += length(fanArea) * (polygon[0] + polygon[i] + polygon[i + 1]) / 3.0;
+    }
+
+    float area = 0.5 * length(areaVector);
+    if (area > 0.0) {
+        center = aabb.center + weightedCenter / (2.0 * area);
+    }
+    return area;
+}
@@ -5,11 +5,9 @@
 #include "includes_general.glsl"
 #include "sh_rotation.glsl"
 
-layout(constant_id = 0) const int OFFSET_X = 0;
-layout(constant_id = 1) const int OFFSET_Y = 0;
-layout(constant_id = 2) const int OFFSET_Z = 0;
-
-const ivec3 OFFSET = ivec3(OFFSET_X, OFFSET_Y, OFFSET_Z);
+// whether workgroups load their voxels and the neighbours around them into shared memory once,
+// instead of every invocation loading its neighbours from the images
+layout(constant_id = 0) const bool TILED = true;
 
 layout(local_size_x = 4, local_size_y = 4, local_size_z = 4) in;
 
@@ -17,22 +15,102 @@ layout(binding = 0) buffer RadianceBuffer {
     PackedVoxel voxels[LM_LAYERS][RADIANCE_SIZE][RADIANCE_SIZE][RADIANCE_SIZE];
 } cache;
 
-layout(binding = 1, rgba16f) uniform image3D radianceImages[LM_LAYERS * SH_CS];
+// the previous iteration, which is only read so that the result doesn't depend on the order in
+// which voxels are updated
+layout(binding = 1, rgba16f) uniform readonly image3D radianceImages[LM_LAYERS * SH_CS];
+
+layout(binding = 2) uniform restrict readonly ClipmapBuffer {
+    ivec4 corners[LM_LAYERS]; // of the volume of every layer, see radOriginAtCorner
+    ivec4 previousCorners[LM_LAYERS]; // before the last move, the voxels in both are kept
+} clipmap;
+
+// blockers of the voxels, see packBlocker
+layout(binding = 3) buffer restrict readonly OcclusionBuffer {
+    uint blockers[LM_LAYERS][RADIANCE_SIZE][RADIANCE_SIZE][RADIANCE_SIZE];
+} occlusion;
+
+// the next iteration, the sets are swapped after every iteration
+layout(binding = 4, rgba16f) uniform writeonly image3D nextRadianceImages[LM_LAYERS * SH_CS];
+
+bool insideVolume(ivec3 index) {
+    return all(greaterThanEqual(index, ivec3(0))) && all(lessThan(index, ivec3(RADIANCE_SIZE)));
+}
+
+// zero outside of the volume
+vec3 loadBlocker(ivec3 index, int layer) {
+    ivec3 texel = radTexelAtIndex(index, clipmap.corners[layer].xyz);
+    return insideVolume(index) ? unpackBlocker(occlusion.blockers[layer][texel.x][texel.y][texel.z]) : vec3(0.0);
+}
 
+// zero outside of the volume
 vec3[SH_CS] loadSHCoefs(ivec3 index, int layer) {
+    bool inside = insideVolume(index);
+    ivec3 texel = radTexelAtIndex(index, clipmap.corners[layer].xyz);
+
     vec3[SH_CS] coefs;
     for (int i = 0; i < SH_CS; i++) {
-        coefs[i] = imageLoad(radianceImages[layer * LM_LAYERS + i], index).rgb;
+        coefs[i] = inside ? imageLoad(radianceImages[layer * SH_CS + i], texel).rgb : vec3(0.0);
     }
     return coefs;
 }
 
 void storeSHCoefs(ivec3 index, int layer, vec3[SH_CS] coefs) {
+    ivec3 texel = radTexelAtIndex(index, clipmap.corners[layer].xyz);
     for (int i = 0; i < SH_CS; i++) {
-        imageStore(radianceImages[layer * LM_LAYERS + i], index, vec4(coefs[i], 0.0));
+        imageStore(nextRadianceImages[layer * SH_CS + i], texel, vec4(coefs[i], 0.0));
     }
 }
 
+// the workgroup and a voxel of its neighbours on every side
+const int TILE_SIZE = 4 + 2;
+
+// coefficients and blockers of the texels of the tile, which are stored without checking whether
+// they are inside of the volume, as that depends on the invocation reading them
+shared vec3 tileCoefs[TILE_SIZE][TILE_SIZE][TILE_SIZE][SH_CS];
+shared uint tileBlockers[TILE_SIZE][TILE_SIZE][TILE_SIZE];
+
+// firstTexel is the texel of the workgroup's first invocation
+void loadTile(ivec3 firstTexel, int layer) {
+    const uint INVOCATIONS = gl_WorkGroupSize.x * gl_WorkGroupSize.y * gl_WorkGroupSize.z;
+
+    for (uint i = gl_LocalInvocationIndex; i < TILE_SIZE * TILE_SIZE * TILE_SIZE; i += INVOCATIONS) {
+        ivec3 t = ivec3(i % TILE_SIZE, i / TILE_SIZE % TILE_SIZE, i / (TILE_SIZE * TILE_SIZE));
+        ivec3 texel = (firstTexel + t - 1) & (RADIANCE_SIZE - 1);
+
+        for (int c = 0; c < SH_CS; c++) {
+            tileCoefs[t.x][t.y][t.z][c] = imageLoad(radianceImages[layer * SH_CS + c], texel).rgb;
+        }
+        tileBlockers[t.x][t.y][t.z] = occlusion.blockers[layer][texel.x][texel.y][texel.z];
+    }
+
+    memoryBarrierShared();
+    barrier();
+}
+
+// zero outside of the volume
+vec3 loadNeighbourBlocker(ivec3 iil, int layer, ivec3 offset) {
+    if (!TILED) {
+        return loadBlocker(iil + offset, layer);
+    }
+    ivec3 t = ivec3(gl_LocalInvocationID) + 1 + offset;
+    return insideVolume(iil + offset) ? unpackBlocker(tileBlockers[t.x][t.y][t.z]) : vec3(0.0);
+}
+
+// zero outside of the volume
+vec3[SH_CS] loadNeighbourSHCoefs(ivec3 iil, int layer, ivec3 offset) {
+    if (!TILED) {
+        return loadSHCoefs(iil + offset, layer);
+    }
+    bool inside = insideVolume(iil + offset);
+    ivec3 t = ivec3(gl_LocalInvocationID) + 1 + offset;
+
+    vec3[SH_CS] coefs;
+    for (int i = 0; i < SH_CS; i++) {
+        coefs[i] = inside ? tileCoefs[t.x][t.y][t.z][i] : vec3(0.0);
+    }
+    return coefs;
+}
+
 void mulAssign(inout vec3[SH_CS] dst, float multiplier) {
     for (int i = 0; i < SH_CS; i++) {
         dst[i] *= multiplier;
@@ -45,7 +123,7 @@ void madAssign(inout vec3[SH_CS] dst, float multiplier, vec3[SH_CS] additive) {
     }
 }
 
-vec3 dot_coefs(vec4 a, vec3[SH_CS] b) {
+vec3 dot_coefs(float[SH_CS] a, vec3[SH_CS] b) {
     vec3 result = a[0] * b[0];
     for (int i = 1; i < SH_CS; i++) {
         result += a[i] * b[i];
@@ -54,125 +132,63 @@ vec3 dot_coefs(vec4 a, vec3[SH_CS] b) {
 }
 
 // sparse second order SH * second order SH multiplication cropped to a second order SH
+// adds the radiance of the neighbour at offset, which is sent along a cosine lobe towards this
+// voxel unless the neighbour's surface is in the way
+void propagateFrom(inout vec3[SH_CS] coefs, ivec3 iil, int layer, ivec3 offset, float weight) {
+    weight *= transmittance(loadNeighbourBlocker(iil, layer, offset), normalize(vec3(-offset)));
+    vec3[SH_CS] tCoefs = loadNeighbourSHCoefs(iil, layer, offset);
+    madAssign(coefs, SH_cosLobe_C0 * weight, tCoefs);
+    coefs[1] += (float(offset.y) * SH_cosLobe_C1 * weight) * tCoefs[0];
+    coefs[2] += (float(offset.z) * SH_cosLobe_C1 * weight) * tCoefs[0];
+    coefs[3] += (float(offset.x) * SH_cosLobe_C1 * weight) * tCoefs[0];
+
+#if SH_CS == 9
+    // the second band is symmetric, so the lobe's direction doesn't need flipping
+    float[SH_CS] cosLobe = dirToCosineLobe(normalize(vec3(offset)));
+    for (int i = 4; i < SH_CS; i++) {
+        coefs[i] += (cosLobe[i] * weight) * tCoefs[0];
+    }
+#endif
+}
+
 // Von Neumann neighborhood (direct neighbors)
 void propagateVonNeumann(inout vec3[SH_CS] coefs, ivec3 iil, int layer, float normalizer) {
-    vec3[SH_CS] tCoefs;
-    // -X
-    tCoefs = loadSHCoefs(ivec3(iil.x-1, iil.yz), layer);
-    madAssign(coefs, SH_cosLobe_C0 * normalizer, tCoefs);
-    coefs[3] += -(SH_cosLobe_C1 * normalizer) * tCoefs[0];
-
-    // +X
-    tCoefs = loadSHCoefs(ivec3(iil.x+1, iil.yz), layer);
-    madAssign(coefs, SH_cosLobe_C0 * normalizer, tCoefs);
-    coefs[3] += (SH_cosLobe_C1 * normalizer) * tCoefs[0];
-
-    // -Y
-    tCoefs = loadSHCoefs(ivec3(iil.x, iil.y-1, iil.z), layer);
-    madAssign(coefs, SH_cosLobe_C0 * normalizer, tCoefs);
-    coefs[1] += -(SH_cosLobe_C1 * normalizer) * tCoefs[0];
-
-    // +Y
-    tCoefs = loadSHCoefs(ivec3(iil.x, iil.y+1, iil.z), layer);
-    madAssign(coefs, SH_cosLobe_C0 * normalizer, tCoefs);
-    coefs[1] += (SH_cosLobe_C1 * normalizer) * tCoefs[0];
-
-    // -Z
-    tCoefs = loadSHCoefs(ivec3(iil.xy, iil.z-1), layer);
-    madAssign(coefs, SH_cosLobe_C0 * normalizer, tCoefs);
-    coefs[2] += -(SH_cosLobe_C1 * normalizer) * tCoefs[0];
-
-    // +Z
-    tCoefs = loadSHCoefs(ivec3(iil.xy, iil.z+1), layer);
-    madAssign(coefs, SH_cosLobe_C0 * normalizer, tCoefs);
-    coefs[2] += (SH_cosLobe_C1 * normalizer) * tCoefs[0];
+    propagateFrom(coefs, iil, layer, ivec3(-1,  0,  0), normalizer);
+    propagateFrom(coefs, iil, layer, ivec3( 1,  0,  0), normalizer);
+    propagateFrom(coefs, iil, layer, ivec3( 0, -1,  0), normalizer);
+    propagateFrom(coefs, iil, layer, ivec3( 0,  1,  0), normalizer);
+    propagateFrom(coefs, iil, layer, ivec3( 0,  0, -1), normalizer);
+    propagateFrom(coefs, iil, layer, ivec3( 0,  0,  1), normalizer);
 }
 
 void propagateEdges(inout vec3[SH_CS] coefs, ivec3 iil, int layer, float normalizer) {
     const float INV_LENGTH = 1.0 / 1.414213562; // 1.0 / sqrt(2.0)
     float weight = normalizer * INV_LENGTH;
-    vec3[SH_CS] tCoefs;
-
-    // -X, -Y
-    tCoefs = loadSHCoefs(iil + ivec3(-1, -1,  0), layer);
-    madAssign(coefs, SH_cosLobe_C0 * weight, tCoefs);
-    coefs[3] += -(SH_cosLobe_C1 * weight) * tCoefs[0];
-    coefs[1] += -(SH_cosLobe_C1 * weight) * tCoefs[0];
-
-    // -X, +Y
-    tCoefs = loadSHCoefs(iil + ivec3(-1,  1,  0), layer);
-    madAssign(coefs, SH_cosLobe_C0 * weight, tCoefs);
-    coefs[3] += -(SH_cosLobe_C1 * weight) * tCoefs[0];
-    coefs[1] +=  (SH_cosLobe_C1 * weight) * tCoefs[0];
-
-    // +X, -Y
-    tCoefs = loadSHCoefs(iil + ivec3( 1, -1,  0), layer);
-    madAssign(coefs, SH_cosLobe_C0 * weight, tCoefs);
-    coefs[3] +=  (SH_cosLobe_C1 * weight) * tCoefs[0];
-    coefs[1] += -(SH_cosLobe_C1 * weight) * tCoefs[0];
-
-    // +X, +Y
-    tCoefs = loadSHCoefs(iil + ivec3( 1,  1,  0), layer);
-    madAssign(coefs, SH_cosLobe_C0 * weight, tCoefs);
-    coefs[3] +=  (SH_cosLobe_C1 * weight) * tCoefs[0];
-    coefs[1] +=  (SH_cosLobe_C1 * weight) * tCoefs[0];
-
-    // -X, -Z
-    tCoefs = loadSHCoefs(iil + ivec3(-1,  0, -1), layer);
-    madAssign(coefs, SH_cosLobe_C0 * weight, tCoefs);
-    coefs[3] += -(SH_cosLobe_C1 * weight) * tCoefs[0];
-    coefs[2] += -(SH_cosLobe_C1 * weight) * tCoefs[0];
-
-    // -X, +Z
-    tCoefs = loadSHCoefs(iil + ivec3(-1,  0,  1), layer);
-    madAssign(coefs, SH_cosLobe_C0 * weight, tCoefs);
-    coefs[3] += -(SH_cosLobe_C1 * weight) * tCoefs[0];
-    coefs[2] +=  (SH_cosLobe_C1 * weight) * tCoefs[0];
-
-    // +X, -Z
-    tCoefs = loadSHCoefs(iil + ivec3( 1,  0, -1), layer);
-    madAssign(coefs, SH_cosLobe_C0 * weight, tCoefs);
-    coefs[3] +=  (SH_cosLobe_C1 * weight) * tCoefs[0];
-    coefs[2] += -(SH_cosLobe_C1 * weight) * tCoefs[0];
 
-    // +X, +Z
-    tCoefs = loadSHCoefs(iil + ivec3( 1,  0,  1), layer);
-    madAssign(coefs, SH_cosLobe_C0 * weight, tCoefs);
-    coefs[3] +=  (SH_cosLobe_C1 * weight) * tCoefs[0];
-    coefs[2] +=  (SH_cosLobe_C1 * weight) * tCoefs[0];
-
-    // -Y, -Z
-    tCoefs = loadSHCoefs(iil + ivec3( 0, -1, -1), layer);
-    madAssign(coefs, SH_cosLobe_C0 * weight, tCoefs);
-    coefs[1] += -(SH_cosLobe_C1 * weight) * tCoefs[0];
-    coefs[2] += -(SH_cosLobe_C1 * weight) * tCoefs[0];
-
-    // -Y, +Z
-    tCoefs = loadSHCoefs(iil + ivec3( 0, -1,  1), layer);
-    madAssign(coefs, SH_cosLobe_C0 * weight, tCoefs);
-    coefs[1] += -(SH_cosLobe_C1 * weight) * tCoefs[0];
-    coefs[2] +=  (SH_cosLobe_C1 * weight) * tCoefs[0];
-
-    // +Y, -Z
-    tCoefs = loadSHCoefs(iil + ivec3( 0,  1, -1), layer);
-    madAssign(coefs, SH_cosLobe_C0 * weight, tCoefs);
-    coefs[1] +=  (SH_cosLobe_C1 * weight) * tCoefs[0];
-    coefs[2] += -(SH_cosLobe_C1 * weight) * tCoefs[0];
-
-    // +Y, +Z
-    tCoefs = loadSHCoefs(iil + ivec3( 0,  1,  1), layer);
-    madAssign(coefs, SH_cosLobe_C0 * weight, tCoefs);
-    coefs[1] +=  (SH_cosLobe_C1 * weight) * tCoefs[0];
-    coefs[2] +=  (SH_cosLobe_C1 * weight) * tCoefs[0];
+    for (int a = -1; a <= 1; a += 2) {
+        for (int b = -1; b <= 1; b += 2) {
+            propagateFrom(coefs, iil, layer, ivec3(a, b, 0), weight);
+            propagateFrom(coefs, iil, layer, ivec3(a, 0, b), weight);
+            propagateFrom(coefs, iil, layer, ivec3(0, a, b), weight);
+        }
+    }
 }
 
 void main() {
     const int LAYER = int(gl_GlobalInvocationID.x / RADIANCE_SIZE);
+    const ivec3 TEXEL = ivec3(gl_GlobalInvocationID % RADIANCE_SIZE);
     // index in layer
-    const ivec3 IIL = ivec3(gl_GlobalInvocationID % RADIANCE_SIZE + (gl_WorkGroupSize * (gl_WorkGroupID + OFFSET)));
+    const ivec3 IIL = radIndexAtTexel(TEXEL, clipmap.corners[LAYER].xyz);
+
+    if (TILED) {
+        loadTile(TEXEL - ivec3(gl_LocalInvocationID), LAYER);
+    }
 
     // stores coefs as array of RGB channels
-    vec3[SH_CS] coefs = vec3[](vec3(0.0), vec3(0.0), vec3(0.0), vec3(0.0));
+    vec3[SH_CS] coefs;
+    for (int i = 0; i < SH_CS; i++) {
+        coefs[i] = vec3(0.0);
+    }
 
     const float BASE_FALLOFF = 0.2765;
     float layer_falloff = pow(0.95, LAYER);
@@ -181,21 +197,23 @@ void main() {
     propagateVonNeumann(coefs, IIL, LAYER, normalizer);
     propagateEdges(coefs, IIL, LAYER, normalizer);
 
-    Voxel voxel = unpackVoxel(cache.voxels[LAYER][IIL.x][IIL.y][IIL.z]);
+    Voxel voxel = unpackVoxel(cache.voxels[LAYER][TEXEL.x][TEXEL.y][TEXEL.z]);
 
     // TODO: get a surface cache to handle diffuse reflections
+    // partially covered voxels reflect and emit proportionally less
     if (voxel.intersections > 0.0) {
-        vec4 cosLobe = dirToCosineLobe(voxel.normal);
-        vec3 s = voxel.reflectance * max(vec3(0.0), dot_coefs(cosLobe, coefs));
-        coefs[0] = s *  cosLobe[0];
-        coefs[1] = s * -cosLobe[1]; // opposite direction
-        coefs[2] = s * -cosLobe[2];
-        coefs[3] = s * -cosLobe[3];
+        float[SH_CS] cosLobe = dirToCosineLobe(voxel.normal);
+        vec3 s = voxel.coverage * voxel.reflectance * max(vec3(0.0), dot_coefs(cosLobe, coefs));
+        for (int i = 0; i < SH_CS; i++) {
+            coefs[i] = s * cosLobe[i];
+        }
+        // opposite direction, which only flips the odd first band
+        coefs[1] = -coefs[1];
+        coefs[2] = -coefs[2];
+        coefs[3] = -coefs[3];
     }
 
-    coefs[0] += voxel.emittance;
+    coefs[0] += voxel.coverage * voxel.emittance;
 
-    // TODO: load all radiance voxels that need be read at once at the start of the program and use the barrier there instead of here
-    barrier(); // makes writing thread-safe, other workgroup members might otherwise read this voxel's radiance while writing
     storeSHCoefs(IIL, LAYER, coefs);
 }
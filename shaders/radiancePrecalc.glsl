@@ -16,42 +16,242 @@ layout(binding = 2) buffer restrict readonly MaterialIndexBuffer {
     uint materials[];
 } matIdxBuffer;
 
-layout(binding = 3) uniform restrict readonly MaterialBuffer {
-    Material materials[MAX_MATERIALS];
+layout(binding = 3) buffer restrict readonly MaterialBuffer {
+    Material materials[];
 } matBuffer;
 
 layout(binding = 4) buffer writeonly RadianceBuffer {
     PackedVoxel voxels[LM_LAYERS][RADIANCE_SIZE][RADIANCE_SIZE][RADIANCE_SIZE];
 } cache;
 
-Voxel calculateIntersect(vec3 position, int layer) {
-    Voxel voxel = Voxel(vec3(0.0), vec3(0.0), vec3(0.0), 0.0);
-    
-    float unit = radUnitSizeLayer(layer);
-    AABB aabb = AABB(position, vec3(unit * 0.5 + EPSILON));
-
-    for (uint i = 0; i < vertexIndexBuffer.indices.length(); i += 3) {
-        vec3[3] tri;
-        tri[0] = vertexBuffer.vertices[vertexIndexBuffer.indices[i    ]].xyz;
-        tri[1] = vertexBuffer.vertices[vertexIndexBuffer.indices[i + 1]].xyz;
-        tri[2] = vertexBuffer.vertices[vertexIndexBuffer.indices[i + 2]].xyz;
-
-        // TODO: use the surface area of the triangle inside the voxel to determine the weight
-        vec3 normal;
-        bool intersects = intersectAABBTriangle(tri, aabb, normal);
-        if (intersects) {
-            Material mat = matBuffer.materials[matIdxBuffer.materials[i / 3]];
-            voxel.emittance += mat.emittance;
-            voxel.reflectance += mat.reflectance;
-            voxel.normal += normal;
-            voxel.intersections += 1.0;
+layout(binding = 5) buffer restrict readonly InstanceBuffer {
+    Instance instances[];
+} instanceBuffer;
+
+layout(binding = 6) buffer restrict readonly NormalBuffer {
+    vec4 normals[];
+} normalBuffer;
+
+layout(binding = 7) buffer restrict readonly UvBuffer {
+    vec2 uvs[];
+} uvBuffer;
+
+layout(binding = 8) uniform sampler2DArray materialTextures;
+
+// the average color of the texture over roughly the footprint of a voxel
+vec3 sampleMaterialTexture(uint layer, vec2 uv, float lod) {
+    if (layer == NO_TEXTURE) {
+        return vec3(1.0);
+    }
+    return textureLod(materialTextures, vec3(uv, layer), lod).rgb;
+}
+
+// mip level at which one texel covers `unit` world units of the triangle
+float textureLodOfTriangle(vec3[3] tri, vec2[3] uvs, float unit) {
+    float worldArea = length(cross(tri[1] - tri[0], tri[2] - tri[0]));
+    vec2 uvA = uvs[1] - uvs[0];
+    vec2 uvB = uvs[2] - uvs[0];
+    vec2 size = vec2(textureSize(materialTextures, 0).xy);
+    float texelArea = abs(uvA.x * uvB.y - uvA.y * uvB.x) * size.x * size.y;
+    return log2(max(unit * sqrt(texelArea / max(worldArea, EPSILON)), 1.0));
+}
+
+layout(binding = 9) buffer restrict readonly BvhNodeBuffer {
+    BvhNode nodes[];
+} bvh;
+
+// instance and first vertex index of every triangle, the brick lists refer to triangles by their
+// index in this buffer
+layout(binding = 10) buffer restrict readonly TriangleBuffer {
+    uvec2 triangles[];
+} triangleBuffer;
+
+// instance indices in the leaves of the top level, first vertex indices in the bottom levels
+layout(binding = 17) buffer restrict readonly BvhLeafBuffer {
+    uint leaves[];
+} bvhLeaves;
+
+// enough for any balanced tree, which is what the CPU builds, per level
+#define BVH_STACK_SIZE 32
+
+#define BRICKS (RADIANCE_SIZE / BRICK_SIZE)
+
+// triangles of every brick, written by triangleBinning.glsl
+layout(binding = 11) buffer restrict readonly BrickCountBuffer {
+    uint counts[LM_LAYERS][BRICKS][BRICKS][BRICKS];
+} brickCounts;
+
+layout(binding = 12) buffer restrict readonly BrickOffsetBuffer {
+    uint offsets[LM_LAYERS][BRICKS][BRICKS][BRICKS];
+} brickOffsets;
+
+layout(binding = 13) buffer restrict readonly BrickTriangleBuffer {
+    uint triangles[BRICK_LIST_CAPACITY];
+} brickTriangles;
+
+layout(binding = 14) uniform restrict readonly ClipmapBuffer {
+    ivec4 corners[LM_LAYERS]; // of the volume of every layer, see radOriginAtCorner
+    ivec4 previousCorners[LM_LAYERS]; // before the last move, the voxels in both are kept
+} clipmap;
+
+// both sets of images, cleared where voxels are replaced, as their radiance belongs to the other
+// side of the volume
+layout(binding = 15, rgba16f) uniform writeonly image3D radianceImages[2 * LM_LAYERS * SH_CS];
+
+// blockers of the voxels, see packBlocker
+layout(binding = 16) buffer restrict writeonly OcclusionBuffer {
+    uint blockers[LM_LAYERS][RADIANCE_SIZE][RADIANCE_SIZE][RADIANCE_SIZE];
+} occlusion;
+
+bool overlapsAABB(vec3 boundsMin, vec3 boundsMax, AABB aabb) {
+    return all(lessThanEqual(boundsMin, aabb.center + aabb.halfExtents)) &&
+           all(greaterThanEqual(boundsMax, aabb.center - aabb.halfExtents));
+}
+
+void addTriangle(inout Voxel voxel, AABB aabb, float unit, Instance instance, uint i) {
+    uvec3 indices = uvec3(vertexIndexBuffer.indices[i], vertexIndexBuffer.indices[i + 1], vertexIndexBuffer.indices[i + 2]);
+
+    vec3[3] tri;
+    tri[0] = (instance.transform * vec4(vertexBuffer.vertices[indices.x].xyz, 1.0)).xyz;
+    tri[1] = (instance.transform * vec4(vertexBuffer.vertices[indices.y].xyz, 1.0)).xyz;
+    tri[2] = (instance.transform * vec4(vertexBuffer.vertices[indices.z].xyz, 1.0)).xyz;
+
+    vec3 faceNormal;
+    if (!intersectAABBTriangle(tri, aabb, faceNormal)) {
+        return;
+    }
+
+    // triangles only touching the voxel don't contribute
+    vec3 center;
+    float area = clipTriangleToAABB(tri, aabb, center);
+    if (area <= 0.0) {
+        return;
+    }
+
+    uint matIdx = instance.material == NO_MATERIAL ? matIdxBuffer.materials[i / 3] : instance.material;
+    Material mat = matBuffer.materials[matIdx];
+
+    // vertex attributes interpolated at the center of the part inside of the voxel
+    vec3 weights = closestBarycentric(tri, center);
+    vec3 normal = weights.x * normalBuffer.normals[indices.x].xyz
+                + weights.y * normalBuffer.normals[indices.y].xyz
+                + weights.z * normalBuffer.normals[indices.z].xyz;
+
+    vec2[3] uvs = vec2[3](uvBuffer.uvs[indices.x], uvBuffer.uvs[indices.y], uvBuffer.uvs[indices.z]);
+    vec2 uv = weights.x * uvs[0] + weights.y * uvs[1] + weights.z * uvs[2];
+    float lod = textureLodOfTriangle(tri, uvs, unit);
+
+    voxel.emittance += area * mat.emittance * sampleMaterialTexture(mat.emittanceTexture, uv, lod);
+    voxel.reflectance += area * mat.reflectance * sampleMaterialTexture(mat.reflectanceTexture, uv, lod);
+    voxel.normal += area * normalize(instance.normalMatrix * normal);
+    voxel.intersections += 1.0;
+    voxel.coverage += area; // total area until normalized
+}
+
+void addListedTriangle(inout Voxel voxel, AABB aabb, float unit, uint t) {
+    uvec2 triangle = triangleBuffer.triangles[t];
+    addTriangle(voxel, aabb, unit, instanceBuffer.instances[triangle.x], triangle.y);
+}
+
+// tests the triangles of the voxel's brick, returns false if its list did not fit in the buffer
+bool intersectBrick(inout Voxel voxel, AABB aabb, float unit, int layer, ivec3 brick) {
+    uint offset = brickOffsets.offsets[layer][brick.x][brick.y][brick.z];
+    uint count = brickCounts.counts[layer][brick.x][brick.y][brick.z];
+    if (offset + count > BRICK_LIST_CAPACITY) {
+        return false;
+    }
+
+    for (uint j = offset; j < offset + count; j++) {
+        addListedTriangle(voxel, aabb, unit, brickTriangles.triangles[j]);
+    }
+    return true;
+}
+
+// traverses the bottom level of the instance's mesh in its local space
+void intersectMesh(inout Voxel voxel, AABB aabb, float unit, Instance instance) {
+    // bounds of the voxel in local space
+    mat3 inverseLinear = mat3(instance.inverseTransform);
+    AABB localAABB = AABB(
+        (instance.inverseTransform * vec4(aabb.center, 1.0)).xyz,
+        abs(inverseLinear[0]) * aabb.halfExtents.x +
+        abs(inverseLinear[1]) * aabb.halfExtents.y +
+        abs(inverseLinear[2]) * aabb.halfExtents.z
+    );
+
+    // depth first traversal, the second children are visited later
+    uint stack[BVH_STACK_SIZE];
+    uint stackSize = 0;
+    uint nodeIdx = instance.bvhRoot;
+
+    while (true) {
+        BvhNode node = bvh.nodes[nodeIdx];
+
+        if (overlapsAABB(node.boundsMin, node.boundsMax, localAABB)) {
+            if (node.leafCount == 0) {
+                stack[stackSize++] = node.offset;
+                nodeIdx++;
+                continue;
+            }
+
+            // the triangles themselves are tested in world space, like the brick lists
+            for (uint t = node.offset; t < node.offset + node.leafCount; t++) {
+                addTriangle(voxel, aabb, unit, instance, bvhLeaves.leaves[t]);
+            }
+        }
+
+        if (stackSize == 0) {
+            break;
         }
+        nodeIdx = stack[--stackSize];
     }
+}
+
+// traverses the top level over the instances
+void intersectBvh(inout Voxel voxel, AABB aabb, float unit) {
+    uint stack[BVH_STACK_SIZE];
+    uint stackSize = 0;
+    uint nodeIdx = 0;
+
+    while (true) {
+        BvhNode node = bvh.nodes[nodeIdx];
+
+        if (overlapsAABB(node.boundsMin, node.boundsMax, aabb)) {
+            if (node.leafCount == 0) {
+                stack[stackSize++] = node.offset;
+                nodeIdx++;
+                continue;
+            }
 
-    if (voxel.intersections > 1.0) {
-        voxel.emittance /= voxel.intersections;
-        voxel.reflectance /= voxel.intersections;
+            for (uint l = node.offset; l < node.offset + node.leafCount; l++) {
+                Instance instance = instanceBuffer.instances[bvhLeaves.leaves[l]];
+                if (overlapsAABB(instance.boundsMin, instance.boundsMax, aabb)) {
+                    intersectMesh(voxel, aabb, unit, instance);
+                }
+            }
+        }
+
+        if (stackSize == 0) {
+            break;
+        }
+        nodeIdx = stack[--stackSize];
+    }
+}
+
+Voxel calculateIntersect(ivec3 index, int layer, vec3 origin) {
+    Voxel voxel = Voxel(vec3(0.0), vec3(0.0), vec3(0.0), 0.0, 0.0);
+
+    float unit = radUnitSizeLayer(layer);
+    AABB aabb = AABB(posAtRadIndex(index, layer, origin), vec3(unit * 0.5 + EPSILON));
+
+    if (!intersectBrick(voxel, aabb, unit, layer, index / BRICK_SIZE)) {
+        intersectBvh(voxel, aabb, unit);
+    }
+
+    if (voxel.coverage > 0.0) {
+        voxel.emittance /= voxel.coverage;
+        voxel.reflectance /= voxel.coverage;
         voxel.normal = normalize(voxel.normal);
+        voxel.coverage = min(voxel.coverage / (unit * unit), 1.0);
     }
 
     return voxel;
@@ -59,11 +259,24 @@ Voxel calculateIntersect(vec3 position, int layer) {
 
 void main() {
     const int LAYER = int(gl_GlobalInvocationID.x / RADIANCE_SIZE);
-    const ivec3 IIL = ivec3(gl_GlobalInvocationID.x % RADIANCE_SIZE, gl_GlobalInvocationID.yz);
+    const ivec3 TEXEL = ivec3(gl_GlobalInvocationID.x % RADIANCE_SIZE, gl_GlobalInvocationID.yz);
+
+    ivec3 corner = clipmap.corners[LAYER].xyz;
+    ivec3 iil = radIndexAtTexel(TEXEL, corner);
 
-    vec3 origin = vec3(0.0); // TODO: movable origin
-    vec3 position = posAtRadIndex(IIL, LAYER, origin);
+    // only the voxels the volume moved onto are voxelized
+    ivec3 previousIil = iil + corner - clipmap.previousCorners[LAYER].xyz;
+    if (all(greaterThanEqual(previousIil, ivec3(0))) && all(lessThan(previousIil, ivec3(RADIANCE_SIZE)))) {
+        return;
+    }
+
+    Voxel voxel = calculateIntersect(iil, LAYER, radOriginAtCorner(corner, LAYER));
+    cache.voxels[LAYER][TEXEL.x][TEXEL.y][TEXEL.z] = packVoxel(voxel);
+    occlusion.blockers[LAYER][TEXEL.x][TEXEL.y][TEXEL.z] = packBlocker(voxel);
 
-    Voxel voxel = calculateIntersect(position, LAYER); // bottleneck // TODO: object acceleration structure
-    cache.voxels[LAYER][IIL.x][IIL.y][IIL.z] = packVoxel(voxel);
+    for (int set = 0; set < 2; set++) {
+        for (int i = 0; i < SH_CS; i++) {
+            imageStore(radianceImages[(set * LM_LAYERS + LAYER) * SH_CS + i], TEXEL, vec4(0.0));
+        }
+    }
 }
@@ -0,0 +1,160 @@
+#version 460
+
+#include "includes_general.glsl"
+
+// lists every triangle in the bricks of every layer it intersects, in three passes, but only in
+// the bricks with voxels that the volume moved onto, as the precalc only voxelizes those:
+// 0: counts the triangles of every brick
+// 1: turns the counts into offsets into the list buffer (single workgroup)
+// 2: writes the triangles into the lists
+layout(constant_id = 0) const int PASS = 0;
+
+layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+#define BRICKS (RADIANCE_SIZE / BRICK_SIZE) // per axis and layer
+#define BRICK_COUNT (LM_LAYERS * BRICKS * BRICKS * BRICKS)
+
+layout(binding = 0) buffer restrict readonly VertexBuffer {
+    vec4 vertices[];
+} vertexBuffer;
+
+layout(binding = 1) buffer restrict readonly VertexIndexBuffer {
+    uint indices[];
+} vertexIndexBuffer;
+
+layout(binding = 2) buffer restrict readonly InstanceBuffer {
+    Instance instances[];
+} instanceBuffer;
+
+// instance and first vertex index of every triangle, the lists refer to triangles by their index
+// in this buffer
+layout(binding = 3) buffer restrict readonly TriangleBuffer {
+    uvec2 triangles[];
+} triangleBuffer;
+
+layout(binding = 4) buffer restrict BrickCountBuffer {
+    uint counts[BRICK_COUNT];
+} brickCounts;
+
+layout(binding = 5) buffer restrict BrickOffsetBuffer {
+    uint offsets[BRICK_COUNT];
+} brickOffsets;
+
+layout(binding = 6) buffer restrict writeonly BrickTriangleBuffer {
+    uint triangles[BRICK_LIST_CAPACITY];
+} brickTriangles;
+
+layout(binding = 7) uniform restrict readonly ClipmapBuffer {
+    ivec4 corners[LM_LAYERS]; // of the volume of every layer, see radOriginAtCorner
+    ivec4 previousCorners[LM_LAYERS]; // before the last move, the voxels in both are kept
+} clipmap;
+
+uint brickIndex(int layer, ivec3 brick) {
+    return ((layer * BRICKS + brick.x) * BRICKS + brick.y) * BRICKS + brick.z;
+}
+
+// whether the brick has voxels outside of the volume before the last move, see the precalc
+bool brickIsExposed(int layer, ivec3 brick) {
+    ivec3 moved = clipmap.corners[layer].xyz - clipmap.previousCorners[layer].xyz;
+    ivec3 first = brick * BRICK_SIZE + moved; // of the brick's voxels in the previous volume
+    return any(lessThan(first, ivec3(0))) || any(greaterThan(first + BRICK_SIZE, ivec3(RADIANCE_SIZE)));
+}
+
+void binTriangle(uint t) {
+    uvec2 triangle = triangleBuffer.triangles[t];
+    Instance instance = instanceBuffer.instances[triangle.x];
+    uint i = triangle.y;
+
+    vec3[3] tri;
+    for (int v = 0; v < 3; v++) {
+        uint index = vertexIndexBuffer.indices[i + v];
+        tri[v] = (instance.transform * vec4(vertexBuffer.vertices[index].xyz, 1.0)).xyz;
+    }
+    vec3 triMin = min(tri[0], min(tri[1], tri[2])) - EPSILON;
+    vec3 triMax = max(tri[0], max(tri[1], tri[2])) + EPSILON;
+
+    for (int layer = 0; layer < LM_LAYERS; layer++) {
+        // layers that didn't move have nothing to voxelize
+        if (clipmap.corners[layer] == clipmap.previousCorners[layer]) {
+            continue;
+        }
+
+        vec3 origin = radOriginAtCorner(clipmap.corners[layer].xyz, layer);
+        float unit = radUnitSizeLayer(layer);
+        float brickUnit = unit * float(BRICK_SIZE);
+
+        // inverse of posAtRadIndex, in bricks
+        ivec3 minBrick = ivec3(floor((triMin - origin) / brickUnit + float(BRICKS / 2)));
+        ivec3 maxBrick = ivec3(floor((triMax - origin) / brickUnit + float(BRICKS / 2)));
+        if (any(lessThan(maxBrick, ivec3(0))) || any(greaterThanEqual(minBrick, ivec3(BRICKS)))) {
+            continue;
+        }
+        minBrick = max(minBrick, 0);
+        maxBrick = min(maxBrick, BRICKS - 1);
+
+        for (int x = minBrick.x; x <= maxBrick.x; x++) {
+            for (int y = minBrick.y; y <= maxBrick.y; y++) {
+                for (int z = minBrick.z; z <= maxBrick.z; z++) {
+                    ivec3 brick = ivec3(x, y, z);
+                    if (!brickIsExposed(layer, brick)) {
+                        continue;
+                    }
+
+                    vec3 center = origin + (vec3(brick - BRICKS / 2) + 0.5) * brickUnit;
+                    // same margin as the voxels of calculateIntersect
+                    AABB aabb = AABB(center, vec3(brickUnit * 0.5 + EPSILON));
+
+                    vec3 faceNormal;
+                    if (!intersectAABBTriangle(tri, aabb, faceNormal)) {
+                        continue;
+                    }
+
+                    uint b = brickIndex(layer, brick);
+                    uint slot = atomicAdd(brickCounts.counts[b], 1);
+                    if (PASS == 2) {
+                        slot += brickOffsets.offsets[b];
+                        // lists that don't fit fall back to the BVH
+                        if (slot < BRICK_LIST_CAPACITY) {
+                            brickTriangles.triangles[slot] = t;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#define SCAN_PER_THREAD (BRICK_COUNT / 64)
+
+shared uint threadSums[64];
+
+// exclusive prefix sum of the counts, which are reset for the fill pass to count again
+void scanCounts() {
+    uint first = gl_LocalInvocationIndex * SCAN_PER_THREAD;
+
+    uint sum = 0;
+    for (uint b = first; b < first + SCAN_PER_THREAD; b++) {
+        sum += brickCounts.counts[b];
+    }
+    threadSums[gl_LocalInvocationIndex] = sum;
+    barrier();
+
+    uint offset = 0;
+    for (uint j = 0; j < gl_LocalInvocationIndex; j++) {
+        offset += threadSums[j];
+    }
+
+    for (uint b = first; b < first + SCAN_PER_THREAD; b++) {
+        brickOffsets.offsets[b] = offset;
+        offset += brickCounts.counts[b];
+        brickCounts.counts[b] = 0;
+    }
+}
+
+void main() {
+    if (PASS == 1) {
+        scanCounts();
+    } else if (gl_GlobalInvocationID.x < triangleBuffer.triangles.length()) {
+        binTriangle(gl_GlobalInvocationID.x);
+    }
+}
@@ -0,0 +1,508 @@
+//! Radiance bakes, the voxel cache and the spherical harmonics of a radiance volume stored on
+//! disk so that later launches can skip the precalc and the iterations it needs to converge
+//!
+//! A bake is keyed by a hash of the scene, the defines and the shaders that voxelize and propagate
+//! it, and is rejected when the key differs.
+
+use std::{
+    fmt,
+    fs::File,
+    hash::Hasher,
+    io::{self, BufReader, BufWriter, Read, Write},
+    mem::size_of,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use glam::*;
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{
+        AutoCommandBufferBuilder, CommandBufferUsage, CopyBufferInfo, CopyBufferToImageInfo,
+        CopyImageToBufferInfo, PrimaryAutoCommandBuffer, PrimaryCommandBufferAbstract,
+    },
+    device::Queue,
+    memory::allocator::{AllocationCreateInfo, MemoryUsage},
+    sync::GpuFuture,
+};
+
+use crate::{
+    allocator::Allocators,
+    buffer::Buffers,
+    clipmap::Clipmap,
+    image::{RadianceImages, RADIANCE_FORMAT},
+    scene::SceneParts,
+    shaders::{self, LM_LAYERS, RADIANCE_SIZE, RADIANCE_UNIT, SH_CS},
+};
+
+/// Iterations of the propagation before the volume is baked, enough for light to cross every
+/// layer a few times
+pub const ITERATIONS: u64 = 512;
+
+const MAGIC: [u8; 8] = *b"BNDBAKE\0";
+
+/// Incremented whenever the layout of the file changes
+pub const VERSION: u32 = 1;
+
+/// Shaders that the baked voxels and radiance depend on
+const SHADER_SOURCES: [&str; 5] = [
+    include_str!("../shaders/includes_general.glsl"),
+    include_str!("../shaders/sh_rotation.glsl"),
+    include_str!("../shaders/triangleBinning.glsl"),
+    include_str!("../shaders/radiancePrecalc.glsl"),
+    include_str!("../shaders/radiance.glsl"),
+];
+
+/// 64 bit FNV-1a, which unlike `DefaultHasher` gives the same hash in every run
+#[derive(Clone, Copy, Debug)]
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Key of the bakes of `scene`
+pub fn key(scene: &SceneParts) -> u64 {
+    let mut hasher = Fnv1a::default();
+
+    hasher.write_u32(LM_LAYERS);
+    hasher.write_u32(RADIANCE_SIZE);
+    hasher.write_u32(RADIANCE_UNIT.to_bits());
+    hasher.write_u32(SH_CS);
+    for source in SHADER_SOURCES {
+        hasher.write(source.as_bytes());
+    }
+
+    hasher.write(bytemuck::cast_slice(&scene.vertices));
+    hasher.write(bytemuck::cast_slice(&scene.normals));
+    hasher.write(bytemuck::cast_slice(&scene.uvs));
+    hasher.write(bytemuck::cast_slice(&scene.vertex_idxs));
+    hasher.write(bytemuck::cast_slice(&scene.material_idxs));
+    for material in &scene.materials {
+        hasher.write(bytemuck::cast_slice(&material.reflectance));
+        hasher.write_u32(material.reflectanceTexture);
+        hasher.write(bytemuck::cast_slice(&material.emittance));
+        hasher.write_u32(material.emittanceTexture);
+    }
+    for texture in &scene.textures {
+        hasher.write_u32(texture.width());
+        hasher.write_u32(texture.height());
+        hasher.write(texture.as_raw());
+    }
+    for instance in &scene.instances {
+        hasher.write(bytemuck::cast_slice(&instance.transform));
+        hasher.write_u32(instance.firstIndex);
+        hasher.write_u32(instance.indexCount);
+        hasher.write_u32(instance.material);
+    }
+    hasher.write(bytemuck::cast_slice(&scene.environment.buffer().coefs));
+
+    hasher.finish()
+}
+
+#[derive(Debug)]
+pub enum BakeError {
+    Io(io::Error),
+    NotABake,
+    /// written by a different version of the engine
+    Version(u32),
+    /// baked with a different scene, defines or shaders
+    Stale,
+    /// sections with the wrong size for the volume
+    Corrupt,
+}
+
+impl fmt::Display for BakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{}", err),
+            Self::NotABake => write!(f, "not a radiance bake"),
+            Self::Version(version) => {
+                write!(f, "bake version {} instead of {}", version, VERSION)
+            }
+            Self::Stale => write!(f, "baked with a different scene or shaders"),
+            Self::Corrupt => write!(f, "bake doesn't fit the radiance volume"),
+        }
+    }
+}
+
+impl std::error::Error for BakeError {}
+
+impl From<io::Error> for BakeError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => Self::Corrupt,
+            _ => Self::Io(err),
+        }
+    }
+}
+
+/// Where and with which key bakes of the loaded scene are stored
+#[derive(Clone, Debug)]
+pub struct BakeFile {
+    pub path: PathBuf,
+    pub key: u64,
+}
+
+impl BakeFile {
+    /// Bake next to the scene file
+    pub fn new(scene_path: &Path, scene: &SceneParts) -> Self {
+        Self {
+            path: scene_path.with_extension("bake"),
+            key: key(scene),
+        }
+    }
+
+    pub fn load(&self) -> Result<Bake, BakeError> {
+        let bake = Bake::read(BufReader::new(File::open(&self.path)?), self.key)?;
+        match bake.fits_volume() {
+            true => Ok(bake),
+            false => Err(BakeError::Corrupt),
+        }
+    }
+
+    pub fn save(&self, bake: &Bake) -> Result<(), BakeError> {
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        bake.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Radiance volume as stored on disk
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bake {
+    pub key: u64,
+    /// of the clipmap that the volume was baked at
+    pub corners: [IVec3; LM_LAYERS as usize],
+    /// contents of the `RadianceBuffer`
+    pub voxels: Vec<u8>,
+    /// contents of the `OcclusionBuffer`
+    pub blockers: Vec<u8>,
+    /// texels of every image of a set of `RadianceImages`, in order
+    pub radiance: Vec<u8>,
+}
+
+impl Bake {
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&self.key.to_le_bytes())?;
+        for corner in self.corners {
+            for component in corner.to_array() {
+                writer.write_all(&component.to_le_bytes())?;
+            }
+        }
+        for section in [&self.voxels, &self.blockers, &self.radiance] {
+            writer.write_all(&(section.len() as u64).to_le_bytes())?;
+            writer.write_all(section)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a bake, which is rejected before its sections are read if its key isn't `key`
+    pub fn read(mut reader: impl Read, key: u64) -> Result<Self, BakeError> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(BakeError::NotABake);
+        }
+
+        let version = u32::from_le_bytes(read_array(&mut reader)?);
+        if version != VERSION {
+            return Err(BakeError::Version(version));
+        }
+        if u64::from_le_bytes(read_array(&mut reader)?) != key {
+            return Err(BakeError::Stale);
+        }
+
+        let mut corners = [IVec3::ZERO; LM_LAYERS as usize];
+        for corner in &mut corners {
+            for i in 0..3 {
+                corner[i] = i32::from_le_bytes(read_array(&mut reader)?);
+            }
+        }
+
+        let mut sections = std::array::from_fn::<_, 3, _>(|_| Vec::new());
+        for section in &mut sections {
+            let len = u64::from_le_bytes(read_array(&mut reader)?);
+            // not allocated up front, so that corrupt lengths end in an error instead
+            reader.by_ref().take(len).read_to_end(section)?;
+            if section.len() as u64 != len {
+                return Err(BakeError::Corrupt);
+            }
+        }
+        let [voxels, blockers, radiance] = sections;
+
+        Ok(Self {
+            key,
+            corners,
+            voxels,
+            blockers,
+            radiance,
+        })
+    }
+
+    fn fits_volume(&self) -> bool {
+        let voxels = (LM_LAYERS * RADIANCE_SIZE.pow(3)) as usize;
+        self.voxels.len() == size_of::<shaders::RadianceBuffer>()
+            && self.blockers.len() == voxels * size_of::<u32>()
+            && self.radiance.len() == voxels * (SH_CS as usize) * texel_size() as usize
+    }
+
+    /// Reads back the volume, which the GPU has to be done with
+    ///
+    /// `set` is the set of radiance images written by the last iteration.
+    pub fn read_back(
+        key: u64,
+        allocators: Arc<Allocators>,
+        queue: Arc<Queue>,
+        buffers: &Buffers,
+        images: &RadianceImages,
+        set: usize,
+        clipmap: &Clipmap,
+    ) -> Self {
+        let voxels = download(&allocators, buffers.radiance.size());
+        let blockers = download(&allocators, buffers.occlusion.size());
+        let radiance = download(&allocators, image_size() * images.set(set).len() as u64);
+
+        let mut builder = AutoCommandBufferBuilder::primary(
+            &allocators.command_buffer,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+
+        builder
+            .copy_buffer(CopyBufferInfo::buffers(
+                buffers.radiance.clone(),
+                voxels.clone(),
+            ))
+            .unwrap()
+            .copy_buffer(CopyBufferInfo::buffers(
+                buffers.occlusion.clone(),
+                blockers.clone(),
+            ))
+            .unwrap();
+        for (i, image) in images.set(set).iter().enumerate() {
+            builder
+                .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(
+                    image.clone(),
+                    image_slice(&radiance, i),
+                ))
+                .unwrap();
+        }
+
+        execute(builder, queue);
+
+        Self {
+            key,
+            corners: clipmap.corners(),
+            voxels: downloaded(voxels),
+            blockers: downloaded(blockers),
+            radiance: downloaded(radiance),
+        }
+    }
+
+    /// Uploads the volume into both sets of radiance images
+    pub fn restore(
+        &self,
+        allocators: Arc<Allocators>,
+        queue: Arc<Queue>,
+        buffers: &Buffers,
+        images: &RadianceImages,
+    ) {
+        let mut builder = AutoCommandBufferBuilder::primary(
+            &allocators.command_buffer,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+
+        let radiance = upload(&allocators, &self.radiance);
+        builder
+            .copy_buffer(CopyBufferInfo::buffers(
+                upload(&allocators, &self.voxels),
+                buffers.radiance.clone(),
+            ))
+            .unwrap()
+            .copy_buffer(CopyBufferInfo::buffers(
+                upload(&allocators, &self.blockers),
+                buffers.occlusion.clone(),
+            ))
+            .unwrap();
+        for set in 0..2 {
+            for (i, image) in images.set(set).iter().enumerate() {
+                builder
+                    .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+                        image_slice(&radiance, i),
+                        image.clone(),
+                    ))
+                    .unwrap();
+            }
+        }
+
+        execute(builder, queue);
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn texel_size() -> u64 {
+    RADIANCE_FORMAT.block_size().unwrap()
+}
+
+fn image_size() -> u64 {
+    RADIANCE_SIZE.pow(3) as u64 * texel_size()
+}
+
+/// Texels of the `i`th image in `buffer`
+fn image_slice(buffer: &Subbuffer<[u8]>, i: usize) -> Subbuffer<[u8]> {
+    let start = i as u64 * image_size();
+    buffer.clone().slice(start..start + image_size())
+}
+
+fn download(allocators: &Allocators, len: u64) -> Subbuffer<[u8]> {
+    Buffer::new_slice(
+        &allocators.memory,
+        BufferCreateInfo {
+            usage: BufferUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            usage: MemoryUsage::Download,
+            ..Default::default()
+        },
+        len,
+    )
+    .unwrap()
+}
+
+fn downloaded(buffer: Subbuffer<[u8]>) -> Vec<u8> {
+    let bytes = buffer.read().unwrap().to_vec();
+    bytes
+}
+
+fn upload(allocators: &Allocators, bytes: &[u8]) -> Subbuffer<[u8]> {
+    Buffer::from_iter(
+        &allocators.memory,
+        BufferCreateInfo {
+            usage: BufferUsage::TRANSFER_SRC,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            usage: MemoryUsage::Upload,
+            ..Default::default()
+        },
+        bytes.iter().copied(),
+    )
+    .unwrap()
+}
+
+fn execute(builder: AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>, queue: Arc<Queue>) {
+    builder
+        .build()
+        .unwrap()
+        .execute(queue)
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bake() -> Bake {
+        Bake {
+            key: 0x1234_5678_9abc_def0,
+            corners: std::array::from_fn(|layer| IVec3::new(layer as i32, -64, 7)),
+            voxels: (0..100).collect(),
+            blockers: vec![3; 12],
+            radiance: (0..=255).rev().collect(),
+        }
+    }
+
+    fn written(bake: &Bake) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bake.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn fnv1a_matches_the_reference_values() {
+        let hash = |bytes: &[u8]| {
+            let mut hasher = Fnv1a::default();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf29ce484222325);
+        assert_eq!(hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(hash(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn bakes_survive_a_round_trip() {
+        let bake = bake();
+        let read = Bake::read(written(&bake).as_slice(), bake.key).unwrap();
+        assert_eq!(read, bake);
+    }
+
+    #[test]
+    fn other_bakes_are_rejected() {
+        let bake = bake();
+        let bytes = written(&bake);
+
+        assert!(matches!(
+            Bake::read(bytes.as_slice(), bake.key + 1),
+            Err(BakeError::Stale)
+        ));
+
+        let mut version = bytes.clone();
+        version[MAGIC.len()] += 1;
+        assert!(matches!(
+            Bake::read(version.as_slice(), bake.key),
+            Err(BakeError::Version(2))
+        ));
+
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert!(matches!(
+            Bake::read(magic.as_slice(), bake.key),
+            Err(BakeError::NotABake)
+        ));
+    }
+
+    #[test]
+    fn truncated_bakes_are_rejected() {
+        let bake = bake();
+        let bytes = written(&bake);
+        for len in [4, bytes.len() / 2, bytes.len() - 1] {
+            assert!(matches!(
+                Bake::read(&bytes[..len], bake.key),
+                Err(BakeError::Corrupt)
+            ));
+        }
+    }
+}
@@ -14,22 +14,47 @@ use vulkano::{
 
 use crate::{
     allocator::Allocators,
-    scene::{self},
+    clipmap::Clipmap,
+    image::MaterialTextures,
+    scene::{Environment, MeshDraw, SceneParts},
     shaders,
 };
 
 #[derive(Clone)]
 pub struct Buffers {
     pub real_time: Subbuffer<shaders::RealTimeBuffer>,
+    pub clipmap: Subbuffer<shaders::ClipmapBuffer>,
+    pub environment: Subbuffer<shaders::EnvironmentBuffer>,
     pub vertex: Subbuffer<[[f32; 4]]>,
+    pub normal: Subbuffer<[[f32; 4]]>,
+    pub uv: Subbuffer<[[f32; 2]]>,
     pub vertex_idxs: Subbuffer<[u32]>,
     pub material_idxs: Subbuffer<[u32]>,
-    pub material: Subbuffer<shaders::MaterialBuffer>,
+    pub material: Subbuffer<[shaders::Material]>,
+    pub textures: MaterialTextures,
+    pub instances: Subbuffer<[Padded<shaders::Instance, 8>]>,
+    /// instanced draw calls of the direct pass
+    pub draws: Vec<MeshDraw>,
+    pub bvh_nodes: Subbuffer<[shaders::BvhNode]>,
+    pub bvh_leaves: Subbuffer<[u32]>,
+    /// instance and first vertex index of every triangle, which the brick lists refer to
+    pub triangles: Subbuffer<[[u32; 2]]>,
+    /// triangles per brick, see `triangleBinning.glsl`
+    pub brick_counts: Subbuffer<[u32]>,
+    pub brick_offsets: Subbuffer<[u32]>,
+    pub brick_triangles: Subbuffer<[u32]>,
     pub radiance: Subbuffer<[u8]>,
+    /// blocker of every voxel in `radiance`, see `packBlocker`
+    pub occlusion: Subbuffer<[u32]>,
 }
 
 impl Buffers {
-    pub fn new(allocators: Arc<Allocators>, queue: Arc<Queue>) -> Self {
+    pub fn new(
+        allocators: Arc<Allocators>,
+        queue: Arc<Queue>,
+        mut scene_parts: SceneParts,
+        clipmap: &Clipmap,
+    ) -> Self {
         let mut builder = AutoCommandBufferBuilder::primary(
             &allocators.command_buffer,
             queue.queue_family_index(),
@@ -37,20 +62,46 @@ impl Buffers {
         )
         .unwrap();
 
-        let (vertex, vertex_idxs, material_idxs, material) =
-            scene(allocators.clone(), &mut builder);
+        let draws = scene_parts.draws.clone();
+        let textures = MaterialTextures::new(
+            allocators.clone(),
+            &mut builder,
+            std::mem::take(&mut scene_parts.textures),
+        );
+        let bvh = std::mem::take(&mut scene_parts.bvh);
+        let triangles = std::mem::take(&mut scene_parts.triangles);
+        let environment = environment_buffer(allocators.clone(), &scene_parts.environment);
+        let (vertex, normal, uv, vertex_idxs, material_idxs, material, instances) =
+            scene(allocators.clone(), &mut builder, scene_parts);
 
         let buffers = Self {
             real_time: real_time_buffer(allocators.clone()),
+            clipmap: clipmap_buffer(allocators.clone(), clipmap),
+            environment,
             vertex,
+            normal,
+            uv,
             vertex_idxs,
             material_idxs,
             material,
+            textures,
+            instances,
+            draws,
+            bvh_nodes: storage(allocators.clone(), &mut builder, bvh.nodes),
+            bvh_leaves: storage(allocators.clone(), &mut builder, bvh.leaves),
+            triangles: storage(allocators.clone(), &mut builder, triangles),
+            brick_counts: device_local(allocators.clone(), shaders::BRICK_COUNT as u64),
+            brick_offsets: device_local(allocators.clone(), shaders::BRICK_COUNT as u64),
+            brick_triangles: device_local(allocators.clone(), shaders::BRICK_LIST_CAPACITY as u64),
             radiance: zeroed(
                 allocators.clone(),
                 &mut builder,
                 size_of::<shaders::RadianceBuffer>() as u64,
-                BufferUsage::STORAGE_BUFFER,
+                BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
+            ),
+            occlusion: device_local(
+                allocators.clone(),
+                (shaders::LM_LAYERS * shaders::RADIANCE_SIZE.pow(3)) as u64,
             ),
         };
 
@@ -68,13 +119,17 @@ impl Buffers {
     }
 }
 
-fn stage_with_data<T: BufferContents>(
+fn stage_with_iter<T, I>(
     allocators: Arc<Allocators>,
     cmb_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
-    buffer: Subbuffer<T>,
-    data: T,
-) {
-    let staging = Buffer::from_data(
+    buffer: Subbuffer<[T]>,
+    iter: I,
+) where
+    T: BufferContents,
+    I: IntoIterator<Item = T>,
+    I::IntoIter: ExactSizeIterator,
+{
+    let staging = Buffer::from_iter(
         &allocators.memory,
         BufferCreateInfo {
             usage: BufferUsage::TRANSFER_SRC,
@@ -84,7 +139,7 @@ fn stage_with_data<T: BufferContents>(
             usage: MemoryUsage::Upload,
             ..Default::default()
         },
-        data,
+        iter,
     )
     .unwrap();
 
@@ -93,33 +148,48 @@ fn stage_with_data<T: BufferContents>(
         .unwrap();
 }
 
-fn stage_with_iter<T, I>(
+/// Device local storage buffer filled with `data`
+fn storage<T: BufferContents>(
     allocators: Arc<Allocators>,
     cmb_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
-    buffer: Subbuffer<[T]>,
-    iter: I,
-) where
-    T: BufferContents,
-    I: IntoIterator<Item = T>,
-    I::IntoIter: ExactSizeIterator,
-{
-    let staging = Buffer::from_iter(
+    data: Vec<T>,
+) -> Subbuffer<[T]> {
+    let buffer = Buffer::new_slice(
         &allocators.memory,
         BufferCreateInfo {
-            usage: BufferUsage::TRANSFER_SRC,
+            usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_DST,
             ..Default::default()
         },
         AllocationCreateInfo {
-            usage: MemoryUsage::Upload,
+            usage: MemoryUsage::DeviceOnly,
             ..Default::default()
         },
-        iter,
+        data.len() as u64,
     )
     .unwrap();
 
-    cmb_builder
-        .copy_buffer(CopyBufferInfo::buffers(staging, buffer))
-        .unwrap();
+    stage_with_iter(allocators, cmb_builder, buffer.clone(), data);
+
+    buffer
+}
+
+/// Uninitialized device local storage buffer, written by shaders or copied from bakes
+fn device_local<T: BufferContents>(allocators: Arc<Allocators>, len: u64) -> Subbuffer<[T]> {
+    Buffer::new_slice(
+        &allocators.memory,
+        BufferCreateInfo {
+            usage: BufferUsage::STORAGE_BUFFER
+                | BufferUsage::TRANSFER_SRC
+                | BufferUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            usage: MemoryUsage::DeviceOnly,
+            ..Default::default()
+        },
+        len,
+    )
+    .unwrap()
 }
 
 fn zeroed(
@@ -168,27 +238,75 @@ fn real_time_buffer(allocators: Arc<Allocators>) -> Subbuffer<shaders::RealTimeB
     .unwrap()
 }
 
+fn clipmap_buffer(
+    allocators: Arc<Allocators>,
+    clipmap: &Clipmap,
+) -> Subbuffer<shaders::ClipmapBuffer> {
+    Buffer::from_data(
+        &allocators.memory,
+        BufferCreateInfo {
+            usage: BufferUsage::UNIFORM_BUFFER,
+            ..BufferCreateInfo::default()
+        },
+        AllocationCreateInfo {
+            usage: MemoryUsage::Upload,
+            ..AllocationCreateInfo::default()
+        },
+        clipmap.buffer(),
+    )
+    .unwrap()
+}
+
+fn environment_buffer(
+    allocators: Arc<Allocators>,
+    environment: &Environment,
+) -> Subbuffer<shaders::EnvironmentBuffer> {
+    Buffer::from_data(
+        &allocators.memory,
+        BufferCreateInfo {
+            usage: BufferUsage::UNIFORM_BUFFER,
+            ..BufferCreateInfo::default()
+        },
+        AllocationCreateInfo {
+            usage: MemoryUsage::Upload,
+            ..AllocationCreateInfo::default()
+        },
+        environment.buffer(),
+    )
+    .unwrap()
+}
+
 fn scene(
     allocators: Arc<Allocators>,
     cmb_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    scene_parts: SceneParts,
 ) -> (
     Subbuffer<[[f32; 4]]>,
+    Subbuffer<[[f32; 4]]>,
+    Subbuffer<[[f32; 2]]>,
     Subbuffer<[u32]>,
     Subbuffer<[u32]>,
-    Subbuffer<shaders::MaterialBuffer>,
+    Subbuffer<[shaders::Material]>,
+    Subbuffer<[Padded<shaders::Instance, 8>]>,
 ) {
-    let (vertex_data, vertex_idx_data, material_idx_data, material_data) = scene::load();
-    let vertex_buffer = vertices(allocators.clone(), cmb_builder, vertex_data);
-    let vertex_index_buffer = vertex_indices(allocators.clone(), cmb_builder, vertex_idx_data);
+    let vertex_buffer = vertices(allocators.clone(), cmb_builder, scene_parts.vertices);
+    let normal_buffer = vertices(allocators.clone(), cmb_builder, scene_parts.normals);
+    let uv_buffer = uvs(allocators.clone(), cmb_builder, scene_parts.uvs);
+    let vertex_index_buffer =
+        vertex_indices(allocators.clone(), cmb_builder, scene_parts.vertex_idxs);
     let material_index_buffer =
-        material_indices(allocators.clone(), cmb_builder, material_idx_data);
-    let material_buffer = materials(allocators.clone(), cmb_builder, material_data);
+        material_indices(allocators.clone(), cmb_builder, scene_parts.material_idxs);
+    let material_buffer = materials(allocators.clone(), cmb_builder, scene_parts.materials);
+    let instance_buffer = instances(allocators.clone(), cmb_builder, scene_parts.instances);
 
     (
         vertex_buffer,
+        normal_buffer,
+        uv_buffer,
         vertex_index_buffer,
         material_index_buffer,
         material_buffer,
+        instance_buffer,
     )
 }
 
@@ -218,6 +336,30 @@ fn vertices(
     buffer
 }
 
+fn uvs(
+    allocators: Arc<Allocators>,
+    cmb_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    uvs: Vec<[f32; 2]>,
+) -> Subbuffer<[[f32; 2]]> {
+    let buffer = Buffer::new_slice(
+        &allocators.memory,
+        BufferCreateInfo {
+            usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            usage: MemoryUsage::DeviceOnly,
+            ..Default::default()
+        },
+        uvs.len() as u64,
+    )
+    .unwrap();
+
+    stage_with_iter(allocators, cmb_builder, buffer.clone(), uvs);
+
+    buffer
+}
+
 fn vertex_indices(
     allocators: Arc<Allocators>,
     cmb_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
@@ -272,30 +414,51 @@ fn materials(
     allocators: Arc<Allocators>,
     cmb_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
     materials: Vec<shaders::Material>,
-) -> Subbuffer<shaders::MaterialBuffer> {
-    let material_data = shaders::MaterialBuffer {
-        materials: materials
-            .into_iter()
-            .map(<Padded<shaders::Material, 4> as From<shaders::Material>>::from)
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap(),
-    };
-
-    let buffer = Buffer::new_sized(
+) -> Subbuffer<[shaders::Material]> {
+    let buffer = Buffer::new_slice(
         &allocators.memory,
         BufferCreateInfo {
-            usage: BufferUsage::UNIFORM_BUFFER | BufferUsage::TRANSFER_DST,
+            usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            usage: MemoryUsage::DeviceOnly,
+            ..Default::default()
+        },
+        materials.len() as u64,
+    )
+    .unwrap();
+
+    stage_with_iter(allocators, cmb_builder, buffer.clone(), materials);
+
+    buffer
+}
+
+fn instances(
+    allocators: Arc<Allocators>,
+    cmb_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    instances: Vec<shaders::Instance>,
+) -> Subbuffer<[Padded<shaders::Instance, 8>]> {
+    let buffer = Buffer::new_slice(
+        &allocators.memory,
+        BufferCreateInfo {
+            usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_DST,
             ..Default::default()
         },
         AllocationCreateInfo {
             usage: MemoryUsage::DeviceOnly,
             ..Default::default()
         },
+        instances.len() as u64,
     )
     .unwrap();
 
-    stage_with_data(allocators, cmb_builder, buffer.clone(), material_data);
+    stage_with_iter(
+        allocators,
+        cmb_builder,
+        buffer.clone(),
+        instances.into_iter().map(Padded::from),
+    );
 
     buffer
 }
@@ -0,0 +1,101 @@
+use glam::*;
+
+use crate::shaders::{self, LM_LAYERS, RADIANCE_SIZE, RADIANCE_UNIT};
+
+/// Volume of every layer of the radiance cache, which is centred on the camera and snapped to the
+/// layer's voxel size
+///
+/// The voxels are addressed toroidally, see `radTexelAtIndex`, so moving the volume only
+/// voxelizes the newly exposed slabs and keeps the radiance of the rest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clipmap {
+    /// first voxel of every layer, in voxels of the layer
+    corners: [IVec3; LM_LAYERS as usize],
+    /// corners before the last move
+    previous_corners: [IVec3; LM_LAYERS as usize],
+}
+
+impl Clipmap {
+    /// Clipmap around `position` which has no previous volume, so that everything is voxelized
+    pub fn new(position: Vec3) -> Self {
+        let corners = corners_at(position);
+        Self {
+            corners,
+            previous_corners: corners.map(|corner| corner + RADIANCE_SIZE as i32),
+        }
+    }
+
+    /// Clipmap of a restored volume, which has nothing left to voxelize
+    pub fn restored(corners: [IVec3; LM_LAYERS as usize]) -> Self {
+        Self {
+            corners,
+            previous_corners: corners,
+        }
+    }
+
+    pub fn corners(&self) -> [IVec3; LM_LAYERS as usize] {
+        self.corners
+    }
+
+    /// Centres the volume on `position`, returns whether any layer moved and has to be voxelized
+    pub fn follow(&mut self, position: Vec3) -> bool {
+        let corners = corners_at(position);
+        if corners == self.corners {
+            return false;
+        }
+        self.previous_corners = self.corners;
+        self.corners = corners;
+        true
+    }
+
+    pub fn buffer(&self) -> shaders::ClipmapBuffer {
+        shaders::ClipmapBuffer {
+            corners: self.corners.map(|corner| corner.extend(0).to_array()),
+            previousCorners: self
+                .previous_corners
+                .map(|corner| corner.extend(0).to_array()),
+        }
+    }
+}
+
+pub fn unit_size(layer: u32) -> f32 {
+    (1 << layer) as f32 * RADIANCE_UNIT
+}
+
+/// Corners of volumes centred on the voxel boundary nearest to `position`
+fn corners_at(position: Vec3) -> [IVec3; LM_LAYERS as usize] {
+    std::array::from_fn(|layer| {
+        (position / unit_size(layer as u32)).round().as_ivec3() - RADIANCE_SIZE as i32 / 2
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volumes_are_snapped_to_their_voxels() {
+        let mut clipmap = Clipmap::new(Vec3::ZERO);
+        assert_eq!(
+            clipmap.corners,
+            [IVec3::splat(-(RADIANCE_SIZE as i32) / 2); LM_LAYERS as usize]
+        );
+
+        // less than half of a voxel of the finest layer
+        assert!(!clipmap.follow(Vec3::new(0.9, -0.9, 0.0)));
+
+        assert!(clipmap.follow(Vec3::new(1.1, 0.0, 0.0)));
+        assert_eq!(clipmap.corners[0].x, 1 - RADIANCE_SIZE as i32 / 2);
+        assert_eq!(clipmap.corners[1], clipmap.previous_corners[1]);
+        assert_ne!(clipmap.corners[0], clipmap.previous_corners[0]);
+    }
+
+    #[test]
+    fn new_clipmaps_have_no_previous_volume() {
+        let clipmap = Clipmap::new(Vec3::new(1e3, -20.0, 5.0));
+        for (corner, previous) in clipmap.corners.iter().zip(clipmap.previous_corners) {
+            let offset = (previous - *corner).abs();
+            assert!(offset.cmpge(IVec3::splat(RADIANCE_SIZE as i32)).any());
+        }
+    }
+}
@@ -1,20 +1,27 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use vulkano::{
     command_buffer::{
         AutoCommandBufferBuilder, BlitImageInfo, CommandBufferUsage, PrimaryAutoCommandBuffer,
         RenderPassBeginInfo, SubpassContents,
     },
-    device::Queue,
+    device::{DeviceOwned, Queue},
     format::ClearValue,
     pipeline::{Pipeline, PipelineBindPoint},
+    query::{QueryPool, QueryPoolCreateInfo, QueryResultFlags, QueryType},
     render_pass::Framebuffer,
     sampler::Filter,
+    sync::PipelineStage,
 };
 
 use crate::{
-    allocator::Allocators, buffer::Buffers, descriptor_sets::DescriptorSets, image::Images,
-    pipeline::Pipelines, shaders::RADIANCE_SIZE, LM_LAYERS,
+    allocator::Allocators,
+    buffer::Buffers,
+    descriptor_sets::{DescriptorSets, VolumeDescriptorSets},
+    image::Images,
+    pipeline::{Pipelines, RadianceKernel, VolumePipelines},
+    shaders::RADIANCE_SIZE,
+    LM_LAYERS,
 };
 
 #[derive(Clone)]
@@ -51,29 +58,11 @@ impl CommandBuffers {
     }
 }
 
-#[derive(Clone, Debug)]
-pub enum PathTraceState {
-    Precalc,
-    Radiance(usize),
-}
-
-impl PathTraceState {
-    fn next(&mut self) -> Self {
-        let old = self.clone();
-        match old {
-            Self::Precalc => *self = Self::Radiance(0),
-            Self::Radiance(frame) => *self = Self::Radiance(frame + 1),
-        }
-        old
-    }
-}
-
 #[derive(Clone)]
 pub struct PathtraceCommandBuffers {
-    pub precalc: Arc<PrimaryAutoCommandBuffer>,
-    pub radiance: Vec<Arc<PrimaryAutoCommandBuffer>>,
-    pub direct: Arc<PrimaryAutoCommandBuffer>,
-    state: PathTraceState,
+    pub volume: VolumeCommandBuffers,
+    /// direct pass sampling either set of radiance images
+    pub direct: [Arc<PrimaryAutoCommandBuffer>; 2],
 }
 
 impl PathtraceCommandBuffers {
@@ -85,18 +74,12 @@ impl PathtraceCommandBuffers {
         descriptor_sets: DescriptorSets,
         buffers: Buffers,
     ) -> PathtraceCommandBuffers {
-        let precalc = Self::radiance_precalc(
-            allocators.clone(),
-            queue.clone(),
-            pipelines.clone(),
-            descriptor_sets.clone(),
-        );
-
-        let radiance = Self::radiance(
+        let volume = VolumeCommandBuffers::new(
             allocators.clone(),
             queue.clone(),
-            pipelines.clone(),
-            descriptor_sets.clone(),
+            &pipelines.volume,
+            &descriptor_sets.volume,
+            &buffers,
         );
 
         let direct = Self::direct(
@@ -108,19 +91,15 @@ impl PathtraceCommandBuffers {
             buffers,
         );
 
-        PathtraceCommandBuffers {
-            precalc,
-            radiance,
-            direct,
-            state: PathTraceState::Precalc,
-        }
+        PathtraceCommandBuffers { volume, direct }
     }
 
-    pub fn next(&mut self) -> Arc<PrimaryAutoCommandBuffer> {
-        match self.state.next() {
-            PathTraceState::Precalc => self.precalc.clone(),
-            PathTraceState::Radiance(frame) => self.radiance[frame % self.radiance.len()].clone(),
-        }
+    /// Command buffers of the next frame, an iteration of the volume and the direct pass which
+    /// renders with its result
+    pub fn next(&mut self) -> Vec<Arc<PrimaryAutoCommandBuffer>> {
+        let mut cmbs = self.volume.next();
+        cmbs.push(self.direct[self.volume.set()].clone());
+        cmbs
     }
 
     pub fn direct(
@@ -130,51 +109,234 @@ impl PathtraceCommandBuffers {
         pipelines: Pipelines,
         descriptor_sets: DescriptorSets,
         buffers: Buffers,
-    ) -> Arc<PrimaryAutoCommandBuffer> {
-        let mut builder = AutoCommandBufferBuilder::primary(
-            &allocators.command_buffer,
-            queue.queue_family_index(),
-            CommandBufferUsage::MultipleSubmit,
-        )
-        .unwrap();
-
-        builder
-            .begin_render_pass(
-                RenderPassBeginInfo {
-                    clear_values: vec![
-                        Some(ClearValue::Float([0.0; 4])),
-                        Some(ClearValue::Depth(1e20)),
-                    ],
-                    ..RenderPassBeginInfo::framebuffer(frame_buffer)
-                },
-                SubpassContents::Inline,
-            )
-            .unwrap()
-            .bind_pipeline_graphics(pipelines.direct.clone())
-            .bind_descriptor_sets(
-                PipelineBindPoint::Graphics,
-                pipelines.direct.layout().clone(),
-                0,
-                descriptor_sets.direct.clone(),
-            )
-            .draw( // INFO: this will break if the index/vertex count changes
-                buffers.vertex_idxs.len() as u32,
-                1,
-                0,
-                0,
+    ) -> [Arc<PrimaryAutoCommandBuffer>; 2] {
+        std::array::from_fn(|set| {
+            let mut builder = AutoCommandBufferBuilder::primary(
+                &allocators.command_buffer,
+                queue.queue_family_index(),
+                CommandBufferUsage::MultipleSubmit,
             )
-            .unwrap()
-            .end_render_pass()
             .unwrap();
 
-        Arc::new(builder.build().unwrap())
+            builder
+                .begin_render_pass(
+                    RenderPassBeginInfo {
+                        clear_values: vec![
+                            Some(ClearValue::Float([0.0; 4])),
+                            Some(ClearValue::Depth(1e20)),
+                        ],
+                        ..RenderPassBeginInfo::framebuffer(frame_buffer.clone())
+                    },
+                    SubpassContents::Inline,
+                )
+                .unwrap()
+                .bind_pipeline_graphics(pipelines.direct.clone())
+                .bind_descriptor_sets(
+                    PipelineBindPoint::Graphics,
+                    pipelines.direct.layout().clone(),
+                    0,
+                    descriptor_sets.direct[set].clone(),
+                );
+
+            // the draws are recorded once, so the command buffers have to be recreated along with
+            // the buffers when the scene changes
+            for draw in &buffers.draws {
+                builder
+                    .draw(
+                        draw.index_count,
+                        draw.instance_count,
+                        draw.first_index,
+                        draw.first_instance,
+                    )
+                    .unwrap();
+            }
+
+            builder.end_render_pass().unwrap();
+
+            Arc::new(builder.build().unwrap())
+        })
+    }
+}
+
+/// Progress of the propagation over the voxels of the current clipmap corners
+///
+/// Moving the volume changes the corners right away but its voxels only in the precalc of the
+/// next iteration, so until then the volume holds data of the previous corners.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Propagation {
+    /// whether the radiance volume moved since the last precalc
+    precalc_pending: bool,
+    /// iterations since the last precalc
+    iterations: u64,
+}
+
+impl Default for Propagation {
+    fn default() -> Self {
+        Self {
+            precalc_pending: true,
+            iterations: 0,
+        }
+    }
+}
+
+impl Propagation {
+    /// Counts an iteration, returns whether it runs the precalc first
+    pub fn next(&mut self) -> bool {
+        let precalc = std::mem::take(&mut self.precalc_pending);
+        if precalc {
+            self.iterations = 0;
+        }
+        self.iterations += 1;
+        precalc
+    }
+
+    pub fn revoxelize(&mut self) {
+        self.precalc_pending = true;
+    }
+
+    pub fn restored(&mut self) {
+        self.precalc_pending = false;
+    }
+
+    /// Iterations propagated over the voxels of the current corners, zero while they still hold
+    /// the voxels of the previous corners
+    pub fn iterations(&self) -> u64 {
+        match self.precalc_pending {
+            true => 0,
+            false => self.iterations,
+        }
+    }
+}
+
+/// Command buffers that voxelize and propagate the radiance volume, which need no window
+#[derive(Clone)]
+pub struct VolumeCommandBuffers {
+    pub precalc: Arc<PrimaryAutoCommandBuffer>,
+    /// iteration of the radiance propagation from either set of radiance images into the other,
+    /// for every `RadianceKernel`
+    pub radiance: [[Arc<PrimaryAutoCommandBuffer>; 2]; 2],
+    propagation: Propagation,
+    /// set of radiance images that the last iteration wrote and the next one reads
+    set: usize,
+    pub kernel: RadianceKernel,
+    /// written around the dispatch of the radiance propagation, if the queue supports them
+    timestamps: Option<Arc<QueryPool>>,
+    /// kernel of the last iteration, which wrote the timestamps
+    timed_kernel: Option<RadianceKernel>,
+}
+
+impl VolumeCommandBuffers {
+    pub fn new(
+        allocators: Arc<Allocators>,
+        queue: Arc<Queue>,
+        pipelines: &VolumePipelines,
+        descriptor_sets: &VolumeDescriptorSets,
+        buffers: &Buffers,
+    ) -> Self {
+        let precalc = Self::radiance_precalc(
+            allocators.clone(),
+            queue.clone(),
+            pipelines,
+            descriptor_sets,
+            buffers,
+        );
+
+        let device = queue.device();
+        let timestamps = device.physical_device().queue_family_properties()
+            [queue.queue_family_index() as usize]
+            .timestamp_valid_bits
+            .map(|_| {
+                QueryPool::new(
+                    device.clone(),
+                    QueryPoolCreateInfo {
+                        query_count: 2,
+                        ..QueryPoolCreateInfo::query_type(QueryType::Timestamp)
+                    },
+                )
+                .unwrap()
+            });
+
+        let radiance = Self::radiance(
+            allocators,
+            queue,
+            pipelines,
+            descriptor_sets,
+            timestamps.as_ref(),
+        );
+
+        Self {
+            precalc,
+            radiance,
+            propagation: Propagation::default(),
+            set: 0,
+            kernel: RadianceKernel::default(),
+            timestamps,
+            timed_kernel: None,
+        }
+    }
+
+    /// Command buffers of the next iteration, the precalc only runs after the volume moved
+    pub fn next(&mut self) -> Vec<Arc<PrimaryAutoCommandBuffer>> {
+        let mut cmbs = Vec::new();
+        if self.propagation.next() {
+            cmbs.push(self.precalc.clone());
+        }
+        cmbs.push(self.radiance[self.kernel as usize][self.set].clone());
+        self.set = 1 - self.set;
+        self.timed_kernel = Some(self.kernel);
+        cmbs
+    }
+
+    /// Voxelizes the part of the volume that it moved onto in the next iteration
+    pub fn revoxelize(&mut self) {
+        self.propagation.revoxelize();
+    }
+
+    /// Skips the precalc until the volume moves, as it was restored from a bake
+    pub fn restored(&mut self) {
+        self.propagation.restored();
+    }
+
+    /// Iterations propagated over the voxels of the current corners, see `Propagation`
+    pub fn iterations(&self) -> u64 {
+        self.propagation.iterations()
+    }
+
+    /// Set of radiance images written by the last iteration
+    pub fn set(&self) -> usize {
+        self.set
+    }
+
+    /// Kernel and GPU time of the radiance propagation of the last iteration, or `None` if it
+    /// hasn't finished or the queue has no timestamps
+    pub fn radiance_time(&self) -> Option<(RadianceKernel, Duration)> {
+        let timestamps = self.timestamps.as_ref()?;
+        let kernel = self.timed_kernel?;
+
+        let mut ticks = [0u64; 2];
+        let available = timestamps
+            .queries_range(0..2)
+            .unwrap()
+            .get_results(&mut ticks, QueryResultFlags::empty())
+            .unwrap();
+        if !available {
+            return None;
+        }
+        let period = timestamps
+            .device()
+            .physical_device()
+            .properties()
+            .timestamp_period;
+        let nanos = ticks[1].saturating_sub(ticks[0]) as f64 * period as f64;
+        Some((kernel, Duration::from_nanos(nanos as u64)))
     }
 
     fn radiance_precalc(
         allocators: Arc<Allocators>,
         queue: Arc<Queue>,
-        pipelines: Pipelines,
-        descriptor_sets: DescriptorSets,
+        pipelines: &VolumePipelines,
+        descriptor_sets: &VolumeDescriptorSets,
+        buffers: &Buffers,
     ) -> Arc<PrimaryAutoCommandBuffer> {
         let dispatch = [
             RADIANCE_SIZE / 4 * LM_LAYERS,
@@ -189,7 +351,30 @@ impl PathtraceCommandBuffers {
         )
         .unwrap();
 
-        // radiance precalc
+        // triangle binning into the bricks that the volume moved onto, the counts are reset for
+        // every run of the precalc
+        let triangle_groups = (buffers.triangles.len() as u32 + 63) / 64;
+        builder
+            .fill_buffer(buffers.brick_counts.clone(), 0)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                pipelines.triangle_binning[0].layout().clone(),
+                0,
+                descriptor_sets.triangle_binning.clone(),
+            );
+        for (pass, pipeline) in pipelines.triangle_binning.iter().enumerate() {
+            let groups = match pass {
+                1 => 1, // the offsets are computed by a single workgroup
+                _ => triangle_groups,
+            };
+            builder
+                .bind_pipeline_compute(pipeline.clone())
+                .dispatch([groups, 1, 1])
+                .unwrap();
+        }
+
+        // radiance precalc, which skips the voxels that were already in the volume
         builder
             .bind_pipeline_compute(pipelines.radiance_precalc.clone())
             .bind_descriptor_sets(
@@ -204,42 +389,62 @@ impl PathtraceCommandBuffers {
         Arc::new(builder.build().unwrap())
     }
 
-    pub fn radiance(
+    fn radiance(
         allocators: Arc<Allocators>,
         queue: Arc<Queue>,
-        pipelines: Pipelines,
-        descriptor_sets: DescriptorSets,
-    ) -> Vec<Arc<PrimaryAutoCommandBuffer>> {
+        pipelines: &VolumePipelines,
+        descriptor_sets: &VolumeDescriptorSets,
+        timestamps: Option<&Arc<QueryPool>>,
+    ) -> [[Arc<PrimaryAutoCommandBuffer>; 2]; 2] {
         let dispatch = [
-            RADIANCE_SIZE / 4 / 2 * LM_LAYERS,
-            RADIANCE_SIZE / 4 / 2, // halved for rendering 1/8 of the scene per frame
-            RADIANCE_SIZE / 4 / 2,
+            RADIANCE_SIZE / 4 * LM_LAYERS,
+            RADIANCE_SIZE / 4,
+            RADIANCE_SIZE / 4,
         ];
 
-        let mut cmbs = Vec::new();
-        for i in 0..8 {
-            let mut builder = AutoCommandBufferBuilder::primary(
-                &allocators.command_buffer,
-                queue.queue_family_index(),
-                CommandBufferUsage::MultipleSubmit,
-            )
-            .unwrap();
-
-            // radiance
-            builder
-                .bind_pipeline_compute(pipelines.radiance[i].clone())
-                .bind_descriptor_sets(
-                    PipelineBindPoint::Compute,
-                    pipelines.radiance[i].layout().clone(),
-                    0,
-                    descriptor_sets.radiance.clone(),
+        RadianceKernel::ALL.map(|kernel| {
+            let pipeline = pipelines.radiance[kernel as usize].clone();
+            std::array::from_fn(|set| {
+                let mut builder = AutoCommandBufferBuilder::primary(
+                    &allocators.command_buffer,
+                    queue.queue_family_index(),
+                    CommandBufferUsage::MultipleSubmit,
                 )
-                .dispatch(dispatch)
                 .unwrap();
 
-            cmbs.push(Arc::new(builder.build().unwrap()));
-        }
-        cmbs
+                // the queries are reset in every submission, before they are written again
+                if let Some(timestamps) = timestamps {
+                    unsafe {
+                        builder
+                            .reset_query_pool(timestamps.clone(), 0..2)
+                            .unwrap()
+                            .write_timestamp(timestamps.clone(), 0, PipelineStage::TopOfPipe)
+                            .unwrap();
+                    }
+                }
+
+                builder
+                    .bind_pipeline_compute(pipeline.clone())
+                    .bind_descriptor_sets(
+                        PipelineBindPoint::Compute,
+                        pipeline.layout().clone(),
+                        0,
+                        descriptor_sets.radiance[set].clone(),
+                    )
+                    .dispatch(dispatch)
+                    .unwrap();
+
+                if let Some(timestamps) = timestamps {
+                    unsafe {
+                        builder
+                            .write_timestamp(timestamps.clone(), 1, PipelineStage::BottomOfPipe)
+                            .unwrap();
+                    }
+                }
+
+                Arc::new(builder.build().unwrap())
+            })
+        })
     }
 }
 
@@ -271,3 +476,44 @@ pub fn swapchain(
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use glam::*;
+
+    use super::*;
+    use crate::{clipmap::Clipmap, shaders::RADIANCE_UNIT};
+
+    #[test]
+    fn iterations_restart_when_the_corners_move() {
+        let mut clipmap = Clipmap::new(Vec3::ZERO);
+        let mut propagation = Propagation::default();
+        assert_eq!(propagation.iterations(), 0);
+
+        assert!(propagation.next());
+        for _ in 1..100 {
+            assert!(!propagation.next());
+        }
+        assert_eq!(propagation.iterations(), 100);
+
+        // the corners move before the precalc of the next iteration voxelizes them
+        assert!(clipmap.follow(Vec3::splat(RADIANCE_UNIT)));
+        propagation.revoxelize();
+        assert_eq!(propagation.iterations(), 0);
+
+        assert!(propagation.next());
+        assert_eq!(propagation.iterations(), 1);
+
+        assert!(!clipmap.follow(Vec3::splat(RADIANCE_UNIT)));
+        assert!(!propagation.next());
+        assert_eq!(propagation.iterations(), 2);
+    }
+
+    #[test]
+    fn restored_volumes_skip_the_precalc() {
+        let mut propagation = Propagation::default();
+        propagation.restored();
+        assert!(!propagation.next());
+        assert_eq!(propagation.iterations(), 1);
+    }
+}
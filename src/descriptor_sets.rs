@@ -1,8 +1,8 @@
 use crate::allocator::Allocators;
 use crate::buffer::Buffers;
-use crate::image::Images;
+use crate::image::{Images, RadianceImages};
 
-use crate::pipeline::Pipelines;
+use crate::pipeline::{Pipelines, VolumePipelines};
 
 use vulkano::pipeline::Pipeline;
 
@@ -14,9 +14,9 @@ use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct DescriptorSets {
-    pub direct: Arc<PersistentDescriptorSet>,
-    pub radiance: Arc<PersistentDescriptorSet>,
-    pub radiance_precalc: Arc<PersistentDescriptorSet>,
+    /// sampling either set of radiance images
+    pub direct: [Arc<PersistentDescriptorSet>; 2],
+    pub volume: VolumeDescriptorSets,
 }
 
 impl DescriptorSets {
@@ -26,6 +26,63 @@ impl DescriptorSets {
         buffers: Buffers,
         images: Images,
     ) -> DescriptorSets {
+        let direct = std::array::from_fn(|set| {
+            PersistentDescriptorSet::new(
+                &allocators.descriptor_set,
+                pipelines.direct.layout().set_layouts()[0].clone(),
+                [
+                    WriteDescriptorSet::buffer(0, buffers.real_time.clone()),
+                    WriteDescriptorSet::buffer(1, buffers.vertex.clone()),
+                    WriteDescriptorSet::buffer(2, buffers.vertex_idxs.clone()),
+                    WriteDescriptorSet::image_view_sampler_array(
+                        3,
+                        0,
+                        images.radiance.combined_image_samplers(set),
+                    ),
+                    WriteDescriptorSet::buffer(4, buffers.instances.clone()),
+                    WriteDescriptorSet::buffer(5, buffers.normal.clone()),
+                    WriteDescriptorSet::buffer(6, buffers.uv.clone()),
+                    WriteDescriptorSet::buffer(7, buffers.material_idxs.clone()),
+                    WriteDescriptorSet::buffer(8, buffers.material.clone()),
+                    WriteDescriptorSet::image_view_sampler(
+                        9,
+                        buffers.textures.view.clone(),
+                        buffers.textures.sampler.clone(),
+                    ),
+                    WriteDescriptorSet::buffer(10, buffers.clipmap.clone()),
+                    WriteDescriptorSet::buffer(11, buffers.environment.clone()),
+                ],
+            )
+            .unwrap()
+        });
+
+        DescriptorSets {
+            direct,
+            volume: VolumeDescriptorSets::new(
+                allocators,
+                &pipelines.volume,
+                &buffers,
+                &images.radiance,
+            ),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct VolumeDescriptorSets {
+    /// reading either set of radiance images and writing the other
+    pub radiance: [Arc<PersistentDescriptorSet>; 2],
+    pub radiance_precalc: Arc<PersistentDescriptorSet>,
+    pub triangle_binning: Arc<PersistentDescriptorSet>,
+}
+
+impl VolumeDescriptorSets {
+    pub fn new(
+        allocators: Arc<Allocators>,
+        pipelines: &VolumePipelines,
+        buffers: &Buffers,
+        images: &RadianceImages,
+    ) -> Self {
         let image_views = images.views(); // TODO: change image usage here to optimize
 
         let radiance_precalc = PersistentDescriptorSet::new(
@@ -37,40 +94,70 @@ impl DescriptorSets {
                 WriteDescriptorSet::buffer(2, buffers.material_idxs.clone()),
                 WriteDescriptorSet::buffer(3, buffers.material.clone()),
                 WriteDescriptorSet::buffer(4, buffers.radiance.clone()),
-            ],
-        )
-        .unwrap();
-
-        let direct = PersistentDescriptorSet::new(
-            &allocators.descriptor_set,
-            pipelines.direct.layout().set_layouts()[0].clone(),
-            [
-                WriteDescriptorSet::buffer(0, buffers.real_time.clone()),
-                WriteDescriptorSet::buffer(1, buffers.vertex.clone()),
-                WriteDescriptorSet::buffer(2, buffers.vertex_idxs.clone()),
-                WriteDescriptorSet::image_view_sampler_array(
-                    3,
+                WriteDescriptorSet::buffer(5, buffers.instances.clone()),
+                WriteDescriptorSet::buffer(6, buffers.normal.clone()),
+                WriteDescriptorSet::buffer(7, buffers.uv.clone()),
+                WriteDescriptorSet::image_view_sampler(
+                    8,
+                    buffers.textures.view.clone(),
+                    buffers.textures.sampler.clone(),
+                ),
+                WriteDescriptorSet::buffer(9, buffers.bvh_nodes.clone()),
+                WriteDescriptorSet::buffer(10, buffers.triangles.clone()),
+                WriteDescriptorSet::buffer(11, buffers.brick_counts.clone()),
+                WriteDescriptorSet::buffer(12, buffers.brick_offsets.clone()),
+                WriteDescriptorSet::buffer(13, buffers.brick_triangles.clone()),
+                WriteDescriptorSet::buffer(14, buffers.clipmap.clone()),
+                WriteDescriptorSet::image_view_array(
+                    15,
                     0,
-                    images.radiance.combined_image_samplers(),
+                    image_views.iter().flat_map(|views| views.storage.clone()),
                 ),
+                WriteDescriptorSet::buffer(16, buffers.occlusion.clone()),
+                WriteDescriptorSet::buffer(17, buffers.bvh_leaves.clone()),
             ],
         )
         .unwrap();
 
-        let radiance = PersistentDescriptorSet::new(
+        let triangle_binning = PersistentDescriptorSet::new(
             &allocators.descriptor_set,
-            pipelines.radiance[0].layout().set_layouts()[0].clone(),
+            pipelines.triangle_binning[0].layout().set_layouts()[0].clone(),
             [
-                WriteDescriptorSet::buffer(0, buffers.radiance.clone()),
-                WriteDescriptorSet::image_view_array(1, 0, image_views.radiance.storage.clone()),
+                WriteDescriptorSet::buffer(0, buffers.vertex.clone()),
+                WriteDescriptorSet::buffer(1, buffers.vertex_idxs.clone()),
+                WriteDescriptorSet::buffer(2, buffers.instances.clone()),
+                WriteDescriptorSet::buffer(3, buffers.triangles.clone()),
+                WriteDescriptorSet::buffer(4, buffers.brick_counts.clone()),
+                WriteDescriptorSet::buffer(5, buffers.brick_offsets.clone()),
+                WriteDescriptorSet::buffer(6, buffers.brick_triangles.clone()),
+                WriteDescriptorSet::buffer(7, buffers.clipmap.clone()),
             ],
         )
         .unwrap();
 
-        DescriptorSets {
-            direct,
+        let radiance = std::array::from_fn(|set| {
+            PersistentDescriptorSet::new(
+                &allocators.descriptor_set,
+                pipelines.radiance[0].layout().set_layouts()[0].clone(),
+                [
+                    WriteDescriptorSet::buffer(0, buffers.radiance.clone()),
+                    WriteDescriptorSet::image_view_array(1, 0, image_views[set].storage.clone()),
+                    WriteDescriptorSet::buffer(2, buffers.clipmap.clone()),
+                    WriteDescriptorSet::buffer(3, buffers.occlusion.clone()),
+                    WriteDescriptorSet::image_view_array(
+                        4,
+                        0,
+                        image_views[1 - set].storage.clone(),
+                    ),
+                ],
+            )
+            .unwrap()
+        });
+
+        Self {
             radiance,
             radiance_precalc,
+            triangle_binning,
         }
     }
 }
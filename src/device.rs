@@ -9,28 +9,47 @@ use vulkano::{
     swapchain::Surface,
 };
 
+/// Physical device with a compute queue that can present to `surface`, if there is one, which
+/// prefers discrete GPUs but falls back to software drivers
 pub fn select_physical_device<'a>(
     instance: Arc<Instance>,
-    surface: &'a Surface,
+    surface: Option<&'a Surface>,
     extensions: &'a DeviceExtensions,
     features: &'a Features,
 ) -> (Arc<PhysicalDevice>, u32) {
     instance
         .enumerate_physical_devices()
         .unwrap()
-        .filter(|p| p.properties().device_type == PhysicalDeviceType::DiscreteGpu)
         .filter(|p| p.supported_extensions().contains(extensions))
         .filter(|p| p.supported_features().contains(features))
-        .find_map(|p| {
+        .filter_map(|p| {
             p.queue_family_properties()
                 .iter()
                 .position(|q| q.queue_flags.contains(QueueFlags::COMPUTE))
                 .map(|q| (p, q as u32))
-                .filter(|(p, q)| p.surface_support(*q, surface).unwrap_or(false))
+                .filter(|(p, q)| match surface {
+                    Some(surface) => p.surface_support(*q, surface).unwrap_or(false),
+                    None => true,
+                })
+        })
+        .min_by_key(|(p, _)| match p.properties().device_type {
+            PhysicalDeviceType::DiscreteGpu => 0,
+            PhysicalDeviceType::IntegratedGpu => 1,
+            PhysicalDeviceType::VirtualGpu => 2,
+            PhysicalDeviceType::Cpu => 3,
+            _ => 4,
         })
         .unwrap()
 }
 
+/// Features that the shaders need
+pub fn required_features() -> Features {
+    Features {
+        shader_buffer_float32_atomic_add: true,
+        ..Features::empty()
+    }
+}
+
 pub fn create_device(
     physical_device: Arc<PhysicalDevice>,
     extensions: DeviceExtensions,
@@ -1,11 +1,11 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use fps_counter::FPSCounter;
 use glam::*;
 use winit::window::{CursorGrabMode, Fullscreen, Window};
 use winit_event_helper::{Callbacks, EventHelper, KeyCode};
 
-use crate::state::State;
+use crate::{bake::BakeFile, pipeline::RadianceKernel, scene::SceneParts, state::State};
 
 mod rotation {
     use glam::Vec3;
@@ -15,9 +15,9 @@ mod rotation {
     pub const RIGHT: Vec3 = Vec3::new(1.0, 0.0, 0.0);
 }
 
-pub fn create(window: Arc<Window>) -> EventHelper<Data> {
+pub fn create(window: Arc<Window>, scene: SceneParts) -> EventHelper<Data> {
     EventHelper::new(Data {
-        state: State::new(window.clone()),
+        state: State::new(window.clone(), scene),
         window,
         window_frozen: false,
         window_resized: false,
@@ -30,6 +30,8 @@ pub fn create(window: Arc<Window>) -> EventHelper<Data> {
         rotation_multiplier: 1.0,
         fps_counter: FPSCounter::new(),
         frame_counter: 0,
+        pending_bake: None,
+        radiance_times: [Duration::ZERO; 2],
     })
 }
 
@@ -50,6 +52,10 @@ pub struct Data {
     pub rotation_multiplier: f32,
     pub fps_counter: FPSCounter,
     pub frame_counter: u64,
+    /// written after `bake::ITERATIONS` frames, unless the volume was restored from it
+    pub pending_bake: Option<BakeFile>,
+    /// running average of the GPU time of the radiance propagation per `RadianceKernel`
+    pub radiance_times: [Duration; 2],
 }
 
 impl Data {
@@ -57,6 +63,19 @@ impl Data {
         Quat::from_rotation_z(-self.rotation.x) * Quat::from_rotation_x(self.rotation.y)
     }
 
+    /// Adds the GPU time of the last radiance propagation to the average of its kernel
+    pub fn time_radiance(&mut self) {
+        let Some((kernel, time)) = self.state.command_buffers.pathtraces.volume.radiance_time()
+        else {
+            return;
+        };
+        let average = &mut self.radiance_times[kernel as usize];
+        *average = match average.is_zero() {
+            true => time,
+            false => average.mul_f64(0.95) + time.mul_f64(0.05),
+        };
+    }
+
     pub fn delta_position(&self) -> Vec3 {
         let rotation = self.rotation();
 
@@ -122,6 +141,20 @@ pub fn callbacks() -> Callbacks<Data> {
         }
     });
 
+    // DEBUG
+    callbacks.window.inputs.just_pressed(KeyCode::K, |eh| {
+        let volume = &mut eh.state.command_buffers.pathtraces.volume;
+        volume.kernel = volume.kernel.toggled();
+        let kernel = volume.kernel;
+        let times = RadianceKernel::ALL
+            .map(|kernel| {
+                let time = eh.radiance_times[kernel as usize];
+                format!("{:?}: {:.3} ms", kernel, time.as_secs_f64() * 1e3)
+            })
+            .join(", ");
+        println!("{:?} ({})", kernel, times);
+    });
+
     // DEBUG
     callbacks.window.inputs.just_pressed(KeyCode::Minus, |eh| {
         if eh.data.window.inputs.pressed(KeyCode::RAlt) {
@@ -0,0 +1,360 @@
+//! The `bake` subcommand, which bakes the radiance volume of a scene without a window so that it
+//! also runs on machines without a display or with a software Vulkan driver
+
+use std::{error::Error, ffi::OsString, path::PathBuf, str::FromStr, sync::Arc};
+
+use glam::*;
+use vulkano::{
+    device::{DeviceExtensions, Queue},
+    sync::{self, GpuFuture},
+};
+
+use crate::{
+    allocator::Allocators,
+    bake::{Bake, BakeFile},
+    buffer::Buffers,
+    clipmap::Clipmap,
+    command_buffer::VolumeCommandBuffers,
+    descriptor_sets::VolumeDescriptorSets,
+    device::{create_device, required_features, select_physical_device},
+    half::f16_to_f32,
+    image::RadianceImages,
+    instance::create_instance,
+    pipeline::VolumePipelines,
+    scene::{self, MeshCleanup, SceneParts},
+    shaders::{Shaders, LM_LAYERS, SH_CS},
+};
+
+#[cfg(debug_assertions)]
+use crate::instance::create_debug_messenger;
+#[cfg(debug_assertions)]
+use vulkano::instance::debug::DebugUtilsMessenger;
+
+pub const USAGE: &str = "usage: bound_engine bake [scene] [--iterations <count> | --tolerance <relative change>] [--output <path>]";
+
+/// Relative change of the total radiance between checks below which the volume has converged
+const DEFAULT_TOLERANCE: f64 = 1e-3;
+
+/// Iterations between the convergence checks, which read back the whole volume
+const CHECK_INTERVAL: u64 = 32;
+
+/// Iterations after which baking stops even if the volume hasn't converged
+const MAX_ITERATIONS: u64 = 8192;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BakeOptions {
+    pub scene: PathBuf,
+    /// next to the scene if `None`
+    pub output: Option<PathBuf>,
+    /// fixed number of iterations, or iterating until convergence if `None`
+    pub iterations: Option<u64>,
+    pub tolerance: f64,
+}
+
+impl BakeOptions {
+    /// Parses the arguments after `bake`
+    pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Self, String> {
+        let mut options = Self {
+            scene: scene::default_scene(),
+            output: None,
+            iterations: None,
+            tolerance: DEFAULT_TOLERANCE,
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--iterations") => {
+                    options.iterations = Some(value(&mut args, "--iterations")?);
+                }
+                Some("--tolerance") => options.tolerance = value(&mut args, "--tolerance")?,
+                Some("--output") => {
+                    let path = args.next().ok_or("--output needs a path")?;
+                    options.output = Some(PathBuf::from(path));
+                }
+                Some(flag) if flag.starts_with("--") => {
+                    return Err(format!("unknown option {}", flag));
+                }
+                _ => options.scene = PathBuf::from(arg),
+            }
+        }
+
+        if options.iterations == Some(0) {
+            return Err("--iterations needs at least one iteration".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn value<T: FromStr>(args: &mut impl Iterator<Item = OsString>, name: &str) -> Result<T, String> {
+    args.next()
+        .and_then(|value| value.to_str()?.parse().ok())
+        .ok_or_else(|| format!("{} needs a number", name))
+}
+
+/// Everything that voxelizes and propagates a radiance volume centred on the origin, without a
+/// window
+pub struct HeadlessVolume {
+    pub allocators: Arc<Allocators>,
+    pub queue: Arc<Queue>,
+    pub clipmap: Clipmap,
+    pub buffers: Buffers,
+    pub images: RadianceImages,
+    pub command_buffers: VolumeCommandBuffers,
+    #[cfg(debug_assertions)]
+    _debugger: DebugUtilsMessenger,
+}
+
+impl HeadlessVolume {
+    pub fn new(scene: SceneParts) -> Self {
+        let instance = create_instance(false);
+        #[cfg(debug_assertions)]
+        let _debugger = create_debug_messenger(instance.clone());
+
+        let device_extensions = DeviceExtensions::empty();
+        let device_features = required_features();
+        let (physical_device, queue_family_index) =
+            select_physical_device(instance, None, &device_extensions, &device_features);
+
+        let (device, queue) = create_device(
+            physical_device,
+            device_extensions,
+            device_features,
+            queue_family_index,
+        );
+
+        let allocators = Allocators::new(device.clone());
+        let clipmap = Clipmap::new(Vec3::ZERO);
+        let buffers = Buffers::new(allocators.clone(), queue.clone(), scene, &clipmap);
+        let images = RadianceImages::new(device.clone(), allocators.clone());
+        let shaders = Shaders::load(device.clone());
+        let pipelines = VolumePipelines::new(device, &shaders);
+        let descriptor_sets =
+            VolumeDescriptorSets::new(allocators.clone(), &pipelines, &buffers, &images);
+        let command_buffers = VolumeCommandBuffers::new(
+            allocators.clone(),
+            queue.clone(),
+            &pipelines,
+            &descriptor_sets,
+            &buffers,
+        );
+
+        Self {
+            allocators,
+            queue,
+            clipmap,
+            buffers,
+            images,
+            command_buffers,
+            #[cfg(debug_assertions)]
+            _debugger,
+        }
+    }
+
+    /// Runs the next iteration, including the precalc in the first, and waits for it
+    pub fn iterate(&mut self) {
+        let mut future = sync::now(self.queue.device().clone()).boxed();
+        for command_buffer in self.command_buffers.next() {
+            future = future
+                .then_execute(self.queue.clone(), command_buffer)
+                .unwrap()
+                .boxed();
+        }
+        future
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+    }
+
+    /// The volume as written by the last iteration
+    pub fn read_back(&self, key: u64) -> Bake {
+        Bake::read_back(
+            key,
+            self.allocators.clone(),
+            self.queue.clone(),
+            &self.buffers,
+            &self.images,
+            self.command_buffers.set(),
+            &self.clipmap,
+        )
+    }
+}
+
+/// Voxelizes the scene, propagates the radiance and writes the bake
+pub fn bake(options: &BakeOptions) -> Result<(), Box<dyn Error>> {
+    let scene = scene::load(&options.scene, &MeshCleanup::default())?;
+    let mut bake_file = BakeFile::new(&options.scene, &scene);
+    if let Some(output) = &options.output {
+        bake_file.path = output.clone();
+    }
+
+    let mut volume = HeadlessVolume::new(scene);
+    println!(
+        "baking on {}",
+        volume
+            .queue
+            .device()
+            .physical_device()
+            .properties()
+            .device_name
+    );
+
+    let mut iteration = 0;
+    let mut previous_radiance = None;
+    let bake = loop {
+        volume.iterate();
+        iteration += 1;
+
+        match options.iterations {
+            Some(iterations) if iteration == iterations => break volume.read_back(bake_file.key),
+            Some(_) => (),
+            None if iteration % CHECK_INTERVAL == 0 || iteration == MAX_ITERATIONS => {
+                let bake = volume.read_back(bake_file.key);
+                let radiance = total_radiance(&bake);
+                println!("iteration {}: total radiance {}", iteration, radiance);
+
+                let converged = previous_radiance.is_some_and(|previous: f64| {
+                    (radiance - previous).abs() <= options.tolerance * radiance.abs()
+                });
+                if converged {
+                    break bake;
+                }
+                if iteration == MAX_ITERATIONS {
+                    eprintln!("warning: not converged after {} iterations", iteration);
+                    break bake;
+                }
+                previous_radiance = Some(radiance);
+            }
+            None => (),
+        }
+    };
+
+    bake_file.save(&bake)?;
+    println!(
+        "wrote {} after {} iterations",
+        bake_file.path.display(),
+        iteration
+    );
+    Ok(())
+}
+
+/// Sum of the red, green and blue first coefficients of every voxel, which converges with the
+/// volume
+fn total_radiance(bake: &Bake) -> f64 {
+    let image_len = bake.radiance.len() / (LM_LAYERS * SH_CS) as usize;
+    bake.radiance
+        .chunks_exact(image_len)
+        .step_by(SH_CS as usize)
+        .flat_map(|image| image.chunks_exact(8)) // R16G16B16A16_SFLOAT
+        .map(|texel| {
+            (0..3)
+                .map(|c| f16_to_f32(u16::from_le_bytes([texel[2 * c], texel[2 * c + 1]])) as f64)
+                .sum::<f64>()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::RadianceKernel;
+
+    fn parse(args: &[&str]) -> Result<BakeOptions, String> {
+        BakeOptions::parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        let options = parse(&["scene.ron", "--iterations", "64", "--output", "out.bake"]).unwrap();
+        assert_eq!(options.scene, PathBuf::from("scene.ron"));
+        assert_eq!(options.iterations, Some(64));
+        assert_eq!(options.output, Some(PathBuf::from("out.bake")));
+
+        let options = parse(&["--tolerance", "0.01"]).unwrap();
+        assert_eq!(options.scene, scene::default_scene());
+        assert_eq!(options.iterations, None);
+        assert_eq!(options.tolerance, 0.01);
+
+        assert!(parse(&["--iterations"]).is_err());
+        assert!(parse(&["--iterations", "0"]).is_err());
+        assert!(parse(&["--tolerance", "a lot"]).is_err());
+        assert!(parse(&["--fast"]).is_err());
+    }
+
+    #[test]
+    fn total_radiance_only_counts_the_first_coefficients() {
+        let image_len = 2 * 8;
+        let mut radiance = vec![0; image_len * (LM_LAYERS * SH_CS) as usize];
+        let one = 0x3c00u16.to_le_bytes();
+        for (i, image) in radiance.chunks_exact_mut(image_len).enumerate() {
+            // red and alpha of the first texel
+            image[..2].copy_from_slice(&one);
+            image[6..8].copy_from_slice(&one);
+            if i % SH_CS as usize == 0 {
+                // blue of the second texel
+                image[12..14].copy_from_slice(&one);
+            }
+        }
+
+        let bake = Bake {
+            key: 0,
+            corners: [IVec3::ZERO; LM_LAYERS as usize],
+            voxels: Vec::new(),
+            blockers: Vec::new(),
+            radiance,
+        };
+        assert_eq!(total_radiance(&bake), 2.0 * LM_LAYERS as f64);
+    }
+
+    /// Run with `cargo test -- --ignored` on a machine with a Vulkan driver, which also prints the
+    /// GPU time of both kernels
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn kernels_give_the_same_radiance() {
+        const ITERATIONS: u32 = 64;
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/test.ron");
+
+        let radiance = RadianceKernel::ALL.map(|kernel| {
+            let scene = scene::load(&path, &MeshCleanup::default()).unwrap();
+            let mut volume = HeadlessVolume::new(scene);
+            volume.command_buffers.kernel = kernel;
+
+            let mut time = std::time::Duration::ZERO;
+            for _ in 0..ITERATIONS {
+                volume.iterate();
+                time += volume
+                    .command_buffers
+                    .radiance_time()
+                    .map_or(Default::default(), |(_, time)| time);
+            }
+            println!("{:?}: {:?} per iteration", kernel, time / ITERATIONS);
+
+            volume.read_back(0).radiance
+        });
+
+        let channels = |bytes: &[u8]| {
+            bytes
+                .chunks_exact(2)
+                .map(|half| f16_to_f32(u16::from_le_bytes([half[0], half[1]])))
+                .collect::<Vec<_>>()
+        };
+        let (simple, tiled) = (channels(&radiance[0]), channels(&radiance[1]));
+        assert!(simple.iter().any(|&c| c != 0.0));
+
+        let (i, difference) = simple
+            .iter()
+            .zip(&tiled)
+            .map(|(a, b)| (a - b).abs() / a.abs().max(b.abs()).max(1e-3))
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        assert!(
+            difference <= 1e-2,
+            "channel {}: {} with the simple kernel, {} with the tiled one",
+            i,
+            simple[i],
+            tiled[i]
+        );
+    }
+}
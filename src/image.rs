@@ -1,11 +1,14 @@
 use std::sync::Arc;
 
+use ::image::{imageops::FilterType, Rgba, RgbaImage};
 use vulkano::{
-    device::Device,
+    command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer},
+    device::{Device, DeviceOwned},
     format::Format,
     image::{
         view::{ImageView, ImageViewCreateInfo},
-        ImageCreateFlags, ImageDimensions, ImageUsage, ImageViewAbstract, SwapchainImage,
+        ImageCreateFlags, ImageDimensions, ImageUsage, ImageViewAbstract, ImmutableImage,
+        MipmapsCount, SwapchainImage,
     },
     sampler::{BorderColor, Sampler, SamplerAddressMode, SamplerCreateInfo},
 };
@@ -80,9 +83,13 @@ pub fn create_depth(allocators: Arc<Allocators>, window: Arc<Window>) -> Arc<Cus
     .unwrap()
 }
 
+/// Format of the radiance images
+pub const RADIANCE_FORMAT: Format = Format::R16G16B16A16_SFLOAT;
+
 #[derive(Clone)]
 pub struct RadianceImages {
-    images: Vec<Arc<CustomImage>>,
+    /// two sets of images, every iteration of the propagation reads one and writes the other
+    images: [Vec<Arc<CustomImage>>; 2],
     sampler: Arc<Sampler>,
 }
 
@@ -95,23 +102,30 @@ impl RadianceImages {
         };
 
         // image for every layer and every spherical harmonic coefficient
-        let images = (0..(SH_CS * LM_LAYERS))
-            .map(|_| {
-                CustomImage::with_usage(
-                    &allocators.memory,
-                    dimensions,
-                    Format::R16G16B16A16_SFLOAT,
-                    ImageUsage::STORAGE | ImageUsage::SAMPLED,
-                    ImageCreateFlags::empty(),
-                )
-                .unwrap()
-            })
-            .collect::<Vec<_>>();
+        let images = std::array::from_fn(|_| {
+            (0..(SH_CS * LM_LAYERS))
+                .map(|_| {
+                    CustomImage::with_usage(
+                        &allocators.memory,
+                        dimensions,
+                        RADIANCE_FORMAT,
+                        // transfers for bakes
+                        ImageUsage::STORAGE
+                            | ImageUsage::SAMPLED
+                            | ImageUsage::TRANSFER_SRC
+                            | ImageUsage::TRANSFER_DST,
+                        ImageCreateFlags::empty(),
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<_>>()
+        });
 
         let sampler = Sampler::new(
             device.clone(),
             SamplerCreateInfo {
-                address_mode: [SamplerAddressMode::ClampToEdge; 3],
+                // the volume is stored toroidally, see `radTexelAtIndex`
+                address_mode: [SamplerAddressMode::Repeat; 3],
                 border_color: BorderColor::FloatTransparentBlack,
                 ..SamplerCreateInfo::simple_repeat_linear_no_mipmap()
             },
@@ -121,8 +135,11 @@ impl RadianceImages {
         Self { images, sampler }
     }
 
-    pub fn combined_image_samplers(&self) -> Vec<(Arc<dyn ImageViewAbstract>, Arc<Sampler>)> {
-        self.views()
+    pub fn combined_image_samplers(
+        &self,
+        set: usize,
+    ) -> Vec<(Arc<dyn ImageViewAbstract>, Arc<Sampler>)> {
+        RadianceImageViews::from_images(&self.images[set])
             .sampled
             .iter()
             .cloned()
@@ -130,8 +147,78 @@ impl RadianceImages {
             .collect::<Vec<_>>()
     }
 
-    pub fn views(&self) -> RadianceImageViews {
-        RadianceImageViews::from_images(&self.images)
+    /// Images of a set, indexed by `layer * SH_CS + coefficient`
+    pub fn set(&self, set: usize) -> &[Arc<CustomImage>] {
+        &self.images[set]
+    }
+
+    pub fn views(&self) -> [RadianceImageViews; 2] {
+        [0, 1].map(|set| RadianceImageViews::from_images(&self.images[set]))
+    }
+}
+
+/// Textures larger than this are scaled down
+const MAX_TEXTURE_SIZE: u32 = 2048;
+
+/// Material textures as the layers of one mipmapped image, all scaled to the size of the
+/// largest one
+#[derive(Clone)]
+pub struct MaterialTextures {
+    pub view: Arc<ImageView<ImmutableImage>>,
+    pub sampler: Arc<Sampler>,
+}
+
+impl MaterialTextures {
+    pub fn new(
+        allocators: Arc<Allocators>,
+        cmb_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        mut textures: Vec<RgbaImage>,
+    ) -> Self {
+        // the image can't be empty
+        if textures.is_empty() {
+            textures.push(RgbaImage::from_pixel(1, 1, Rgba([255; 4])));
+        }
+
+        let width = textures.iter().map(|t| t.width()).max().unwrap();
+        let height = textures.iter().map(|t| t.height()).max().unwrap();
+        let (width, height) = (width.min(MAX_TEXTURE_SIZE), height.min(MAX_TEXTURE_SIZE));
+
+        let pixels = textures
+            .iter()
+            .flat_map(|texture| {
+                if texture.dimensions() == (width, height) {
+                    texture.as_raw().clone()
+                } else {
+                    ::image::imageops::resize(texture, width, height, FilterType::Triangle)
+                        .into_raw()
+                }
+            })
+            .collect::<Vec<u8>>();
+
+        let image = ImmutableImage::from_iter(
+            &allocators.memory,
+            pixels,
+            ImageDimensions::Dim2d {
+                width,
+                height,
+                array_layers: textures.len() as u32,
+            },
+            MipmapsCount::Log2,
+            Format::R8G8B8A8_SRGB,
+            cmb_builder,
+        )
+        .unwrap();
+
+        let sampler = Sampler::new(
+            allocators.memory.device().clone(),
+            SamplerCreateInfo::simple_repeat_linear(),
+        )
+        .unwrap();
+
+        Self {
+            view: ImageView::new_default(image).unwrap(),
+            sampler,
+        }
     }
 }
 
@@ -139,7 +226,7 @@ impl RadianceImages {
 pub struct ImageViewCollection {
     pub render: Arc<ImageView<CustomImage>>,
     pub depth: Arc<ImageView<CustomImage>>,
-    pub radiance: RadianceImageViews,
+    pub radiance: [RadianceImageViews; 2],
 }
 
 #[derive(Clone)]
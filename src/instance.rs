@@ -1,13 +1,22 @@
 use std::sync::Arc;
 
+#[cfg(debug_assertions)]
+use vulkano::instance::debug::{
+    DebugUtilsMessageSeverity, DebugUtilsMessageType, DebugUtilsMessenger,
+    DebugUtilsMessengerCreateInfo,
+};
 use vulkano::{
     instance::{Instance, InstanceCreateInfo, InstanceExtensions},
     Version, VulkanLibrary,
 };
 
-pub fn create_instance() -> Arc<Instance> {
+/// Instance which can create surfaces for windows if `windowed`
+pub fn create_instance(windowed: bool) -> Arc<Instance> {
     let library = VulkanLibrary::new().unwrap();
-    let required_extensions = vulkano_win::required_extensions(&library);
+    let required_extensions = match windowed {
+        true => vulkano_win::required_extensions(&library),
+        false => InstanceExtensions::empty(),
+    };
 
     #[cfg(debug_assertions)]
     let required_extensions = InstanceExtensions {
@@ -25,3 +34,26 @@ pub fn create_instance() -> Arc<Instance> {
     )
     .unwrap()
 }
+
+/// Prints the messages of the validation layers
+#[cfg(debug_assertions)]
+pub fn create_debug_messenger(instance: Arc<Instance>) -> DebugUtilsMessenger {
+    unsafe {
+        DebugUtilsMessenger::new(
+            // TODO: add message_type and message_severity marker to print output
+            instance,
+            DebugUtilsMessengerCreateInfo {
+                message_severity: DebugUtilsMessageSeverity::INFO
+                    | DebugUtilsMessageSeverity::WARNING
+                    | DebugUtilsMessageSeverity::ERROR,
+                message_type: DebugUtilsMessageType::GENERAL
+                    | DebugUtilsMessageType::VALIDATION
+                    | DebugUtilsMessageType::PERFORMANCE,
+                ..DebugUtilsMessengerCreateInfo::user_callback(Arc::new(|msg| {
+                    println!("[DEBUG]: {:?}", msg.description);
+                }))
+            },
+        )
+    }
+    .unwrap()
+}
@@ -1,6 +1,7 @@
 use glam::*;
-use std::{f32::consts::PI, sync::Arc};
+use std::{f32::consts::PI, io, path::PathBuf, sync::Arc};
 
+use bake::{BakeError, BakeFile};
 use shaders::LM_LAYERS;
 use vulkano::{
     swapchain::{AcquireError, SwapchainPresentInfo},
@@ -14,17 +15,24 @@ use winit::{
 use winit_event_helper::KeyCode;
 
 mod allocator;
+mod bake;
 mod buffer;
+mod clipmap;
 mod command_buffer;
 mod descriptor_sets;
 mod device;
 mod event_helper;
 mod fences;
+mod half;
+mod headless;
 mod image;
 mod instance;
 mod pipeline;
+#[cfg(test)]
+mod reference;
 mod render_pass;
 mod scene;
+mod sh;
 mod shaders;
 mod state;
 mod swapchain;
@@ -33,6 +41,28 @@ mod swapchain;
 const FOV: f32 = 1.0;
 
 fn main() {
+    let mut args = std::env::args_os().skip(1).peekable();
+    if args.next_if(|arg| arg == "bake").is_some() {
+        let options = headless::BakeOptions::parse(args).unwrap_or_else(|err| {
+            eprintln!("{}\n{}", err, headless::USAGE);
+            std::process::exit(1);
+        });
+        if let Err(err) = headless::bake(&options) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let scene_path = args
+        .next()
+        .map(PathBuf::from)
+        .unwrap_or_else(scene::default_scene);
+    let scene = scene::load(&scene_path, &scene::MeshCleanup::default()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+
     let event_loop = EventLoop::new();
 
     let window = Arc::new(
@@ -47,7 +77,19 @@ fn main() {
     );
     window.set_cursor_visible(false);
 
-    let mut eh = event_helper::create(window);
+    let bake_file = BakeFile::new(&scene_path, &scene);
+
+    let mut eh = event_helper::create(window, scene);
+
+    match bake_file.load() {
+        Ok(bake) => eh.state.restore(&bake),
+        Err(err) => {
+            if !matches!(&err, BakeError::Io(err) if err.kind() == io::ErrorKind::NotFound) {
+                eprintln!("not using {}: {}", bake_file.path.display(), err);
+            }
+            eh.pending_bake = Some(bake_file);
+        }
+    }
 
     let callbacks = event_helper::callbacks();
 
@@ -113,6 +155,9 @@ fn main() {
         let position = Vec3::from_array(eh.state.real_time_data.position) + eh.delta_position();
 
         eh.state.real_time_data.position = position.to_array().into();
+        if eh.state.clipmap.follow(position) {
+            eh.state.command_buffers.pathtraces.volume.revoxelize();
+        }
         eh.delta_position = Vec3::ZERO;
         eh.state.real_time_data.projection_view = state::projection_view_matrix(
             position,
@@ -131,10 +176,23 @@ fn main() {
         if let Some(previous_future) = eh.state.fences.previous() {
             previous_future.wait(None).unwrap();
         }
+        eh.time_radiance();
+
+        // only bakes once the radiance propagated over the voxels of the current corners, as the
+        // volume may have moved this frame while its voxels are only updated by the next precalc
+        if eh.state.command_buffers.pathtraces.volume.iterations() >= bake::ITERATIONS {
+            if let Some(bake_file) = eh.pending_bake.take() {
+                let bake = eh.state.bake(bake_file.key);
+                if let Err(err) = bake_file.save(&bake) {
+                    eprintln!("failed to write {}: {}", bake_file.path.display(), err);
+                }
+            }
+        }
 
         eh.frame_counter += 1;
 
         *eh.state.buffers.real_time.write().unwrap() = eh.state.real_time_data;
+        *eh.state.buffers.clipmap.write().unwrap() = eh.state.clipmap.buffer();
 
         let (image_index, suboptimal, image_future) =
             match vulkano::swapchain::acquire_next_image(eh.state.swapchain.clone(), None) {
@@ -150,17 +208,15 @@ fn main() {
             image_fence.wait(None).unwrap();
         }
 
-        let future = sync::now(eh.state.device.clone())
-            .then_execute(
-                eh.state.queue.clone(),
-                eh.state.command_buffers.pathtraces.next(),
-            )
-            .unwrap()
-            .then_execute(
-                eh.state.queue.clone(),
-                eh.state.command_buffers.pathtraces.direct.clone(),
-            )
-            .unwrap()
+        let mut pathtrace_future = sync::now(eh.state.device.clone()).boxed();
+        for command_buffer in eh.state.command_buffers.pathtraces.next() {
+            pathtrace_future = pathtrace_future
+                .then_execute(eh.state.queue.clone(), command_buffer)
+                .unwrap()
+                .boxed();
+        }
+
+        let future = pathtrace_future
             .then_execute(
                 eh.state.queue.clone(),
                 eh.state.command_buffers.swapchains[image_index as usize].clone(),
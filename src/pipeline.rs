@@ -61,11 +61,32 @@ where
         .unwrap()
 }
 
+/// Kernel of the radiance propagation, both give the same results, which the ignored test
+/// `kernels_give_the_same_radiance` checks on a GPU
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RadianceKernel {
+    /// every invocation loads its neighbours from the images
+    Simple,
+    /// workgroups load their voxels and the neighbours around them into shared memory once
+    #[default]
+    Tiled,
+}
+
+impl RadianceKernel {
+    pub const ALL: [Self; 2] = [Self::Simple, Self::Tiled];
+
+    pub fn toggled(self) -> Self {
+        match self {
+            Self::Simple => Self::Tiled,
+            Self::Tiled => Self::Simple,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Pipelines {
     pub direct: Arc<GraphicsPipeline>,
-    pub radiance: Vec<Arc<ComputePipeline>>,
-    pub radiance_precalc: Arc<ComputePipeline>,
+    pub volume: VolumePipelines,
 }
 
 impl Pipelines {
@@ -87,29 +108,50 @@ impl Pipelines {
             (),
         );
 
-        let mut radiance = vec![];
-        for x in 0..2 {
-            for y in 0..2 {
-                for z in 0..2 {
-                    radiance.push(compute(
-                        device.clone(),
-                        shaders.radiance.clone(),
-                        &shaders::RadianceSpecializationConstants {
-                            OFFSET_X: x,
-                            OFFSET_Y: y,
-                            OFFSET_Z: z,
-                        },
-                    ));
-                }
-            }
+        Self {
+            direct,
+            volume: VolumePipelines::new(device, &shaders),
         }
+    }
+}
 
+/// Compute pipelines that voxelize and propagate the radiance volume, which need no window
+#[derive(Clone)]
+pub struct VolumePipelines {
+    /// indexed by `RadianceKernel`
+    pub radiance: [Arc<ComputePipeline>; 2],
+    pub radiance_precalc: Arc<ComputePipeline>,
+    /// counting, offset and filling pass of the triangle binning
+    pub triangle_binning: Vec<Arc<ComputePipeline>>,
+}
+
+impl VolumePipelines {
+    pub fn new(device: Arc<Device>, shaders: &Shaders) -> Self {
+        let radiance = RadianceKernel::ALL.map(|kernel| {
+            compute(
+                device.clone(),
+                shaders.radiance.clone(),
+                &shaders::RadianceSpecializationConstants {
+                    TILED: (kernel == RadianceKernel::Tiled) as u32,
+                },
+            )
+        });
         let radiance_precalc = compute(device.clone(), shaders.radiance_precalc.clone(), &());
 
+        let triangle_binning = (0..3)
+            .map(|pass| {
+                compute(
+                    device.clone(),
+                    shaders.triangle_binning.clone(),
+                    &shaders::TriangleBinningSpecializationConstants { PASS: pass },
+                )
+            })
+            .collect();
+
         Self {
-            direct,
             radiance,
             radiance_precalc,
+            triangle_binning,
         }
     }
 }
@@ -0,0 +1,7 @@
+//! CPU mirrors of the shaders, which the tests use to check them without a GPU
+//!
+//! The functions follow the GLSL code closely and keep its names in snake case, so the two can be
+//! compared side by side. Changes to one should be made to the other.
+
+pub mod propagation;
+pub mod voxelizer;
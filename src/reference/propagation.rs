@@ -0,0 +1,420 @@
+//! Mirror of one iteration of the radiance propagation in `radiance.glsl`
+//!
+//! Like the shader, every voxel reads the previous iteration, so both step through the same
+//! sequence of volumes.
+
+use glam::*;
+
+use crate::sh::{self, COS_LOBE_C0, COS_LOBE_C1, L2, NORM_C0};
+use crate::shaders::SH_CS;
+
+use super::voxelizer::Voxel;
+
+/// Falloff of the radiance per iteration and voxel, before `layer_falloff`
+pub const BASE_FALLOFF: f32 = 0.2765;
+
+/// RGB coefficients of the L1 or L2 spherical harmonics, in the order of
+/// `evaluateRGBSphericalHarmonics`
+pub type Coefs = [Vec3; SH_CS as usize];
+
+pub fn layer_falloff(layer: u32) -> f32 {
+    0.95f32.powi(layer as i32)
+}
+
+pub fn normalizer(layer: u32) -> f32 {
+    NORM_C0 * BASE_FALLOFF * layer_falloff(layer)
+}
+
+/// The coefficients of one layer, a cube of `size` voxels per axis
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grid {
+    pub size: i32,
+    pub coefs: Vec<Coefs>,
+    /// occlusion volume, see `blocker`
+    pub blockers: Vec<Vec3>,
+}
+
+impl Grid {
+    pub fn new(size: i32) -> Self {
+        Self {
+            size,
+            coefs: vec![[Vec3::ZERO; SH_CS as usize]; size.pow(3) as usize],
+            blockers: vec![Vec3::ZERO; size.pow(3) as usize],
+        }
+    }
+
+    fn index(&self, iil: IVec3) -> Option<usize> {
+        let inside = iil.cmpge(IVec3::ZERO).all() && iil.cmplt(IVec3::splat(self.size)).all();
+        inside.then(|| ((iil.x * self.size + iil.y) * self.size + iil.z) as usize)
+    }
+
+    /// Loads outside of the grid return zero, like out of bounds image loads
+    pub fn load_sh_coefs(&self, iil: IVec3) -> Coefs {
+        match self.index(iil) {
+            Some(index) => self.coefs[index],
+            None => [Vec3::ZERO; SH_CS as usize],
+        }
+    }
+
+    /// Zero outside of the grid
+    pub fn load_blocker(&self, iil: IVec3) -> Vec3 {
+        self.index(iil)
+            .map_or(Vec3::ZERO, |index| self.blockers[index])
+    }
+
+    pub fn store_sh_coefs(&mut self, iil: IVec3, coefs: Coefs) {
+        if let Some(index) = self.index(iil) {
+            self.coefs[index] = coefs;
+        }
+    }
+
+    /// Sum of the constant coefficients, which is proportional to the total radiance
+    pub fn total_radiance(&self) -> Vec3 {
+        self.coefs.iter().map(|coefs| coefs[0]).sum()
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item = IVec3> {
+        let size = self.size;
+        (0..size).flat_map(move |x| {
+            (0..size).flat_map(move |y| (0..size).map(move |z| IVec3::new(x, y, z)))
+        })
+    }
+}
+
+/// Like `packBlocker` without the quantization
+pub fn blocker(voxel: &Voxel) -> Vec3 {
+    voxel.normal * voxel.coverage
+}
+
+pub fn transmittance(blocker: Vec3, dir: Vec3) -> f32 {
+    let coverage = blocker.length();
+    if coverage == 0.0 {
+        return 1.0;
+    }
+    1.0 - coverage * (-(blocker / coverage).dot(dir) * 2f32.sqrt()).clamp(0.0, 1.0)
+}
+
+fn mad_assign(dst: &mut Coefs, multiplier: f32, additive: &Coefs) {
+    for (dst, additive) in dst.iter_mut().zip(additive) {
+        *dst += *additive * multiplier;
+    }
+}
+
+pub fn dot_coefs(a: &[f32; SH_CS as usize], b: &Coefs) -> Vec3 {
+    a.iter().zip(b).map(|(a, b)| *a * *b).sum()
+}
+
+pub fn dir_to_cosine_lobe(dir: Vec3) -> [f32; SH_CS as usize] {
+    sh::dir_to_cosine_lobe(dir)
+}
+
+/// Adds the radiance of the neighbour at `offset`, which is sent along a cosine lobe towards
+/// this voxel unless the neighbour's surface is in the way
+fn propagate_from(coefs: &mut Coefs, grid: &Grid, iil: IVec3, offset: IVec3, weight: f32) {
+    let weight = weight
+        * transmittance(
+            grid.load_blocker(iil + offset),
+            -offset.as_vec3().normalize(),
+        );
+    let t_coefs = grid.load_sh_coefs(iil + offset);
+    mad_assign(coefs, COS_LOBE_C0 * weight, &t_coefs);
+
+    // coefficients of the y, z and x directions
+    for (coef, sign) in [(3, offset.x), (1, offset.y), (2, offset.z)] {
+        if sign != 0 {
+            coefs[coef] += (sign as f32 * COS_LOBE_C1 * weight) * t_coefs[0];
+        }
+    }
+
+    if SH_CS as usize == L2 {
+        // the second band is symmetric, so the lobe's direction doesn't need flipping
+        let cos_lobe = dir_to_cosine_lobe(offset.as_vec3().normalize());
+        for i in 4..L2 {
+            coefs[i] += (cos_lobe[i] * weight) * t_coefs[0];
+        }
+    }
+}
+
+/// Direct neighbours
+pub fn propagate_von_neumann(coefs: &mut Coefs, grid: &Grid, iil: IVec3, normalizer: f32) {
+    for offset in [
+        IVec3::NEG_X,
+        IVec3::X,
+        IVec3::NEG_Y,
+        IVec3::Y,
+        IVec3::NEG_Z,
+        IVec3::Z,
+    ] {
+        propagate_from(coefs, grid, iil, offset, normalizer);
+    }
+}
+
+/// Neighbours sharing an edge, which are further away
+pub fn propagate_edges(coefs: &mut Coefs, grid: &Grid, iil: IVec3, normalizer: f32) {
+    let weight = normalizer / 2f32.sqrt();
+
+    for (a, b) in [
+        (IVec3::X, IVec3::Y),
+        (IVec3::X, IVec3::Z),
+        (IVec3::Y, IVec3::Z),
+    ] {
+        for (sign_a, sign_b) in [(-1, -1), (-1, 1), (1, -1), (1, 1)] {
+            propagate_from(coefs, grid, iil, sign_a * a + sign_b * b, weight);
+        }
+    }
+}
+
+/// New coefficients of the voxel at `iil`, like `main`
+pub fn radiance(grid: &Grid, voxel: Voxel, iil: IVec3, layer: u32) -> Coefs {
+    let mut coefs = [Vec3::ZERO; SH_CS as usize];
+
+    let normalizer = normalizer(layer);
+    propagate_von_neumann(&mut coefs, grid, iil, normalizer);
+    propagate_edges(&mut coefs, grid, iil, normalizer);
+
+    // partially covered voxels reflect and emit proportionally less
+    if voxel.intersections > 0.0 {
+        let cos_lobe = dir_to_cosine_lobe(voxel.normal);
+        let s = voxel.coverage * voxel.reflectance * dot_coefs(&cos_lobe, &coefs).max(Vec3::ZERO);
+        coefs = cos_lobe.map(|cos_lobe| s * cos_lobe);
+        // opposite direction, which only flips the odd first band
+        for coef in &mut coefs[1..4] {
+            *coef = -*coef;
+        }
+    }
+
+    coefs[0] += voxel.coverage * voxel.emittance;
+    coefs
+}
+
+/// One iteration over the whole grid, `voxels` are indexed like the grid and make up its blockers
+pub fn iterate(grid: &Grid, voxels: &[Voxel], layer: u32) -> Grid {
+    let grid = &Grid {
+        blockers: voxels.iter().map(blocker).collect(),
+        ..grid.clone()
+    };
+    let mut next = Grid::new(grid.size);
+    for (iil, voxel) in grid.positions().zip(voxels) {
+        next.store_sh_coefs(iil, radiance(grid, *voxel, iil, layer));
+    }
+    next.blockers = grid.blockers.clone();
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shaders::LM_LAYERS;
+
+    const SIZE: i32 = 9;
+    const CENTER: IVec3 = IVec3::splat(SIZE / 2);
+
+    fn empty() -> Vec<Voxel> {
+        vec![Voxel::default(); SIZE.pow(3) as usize]
+    }
+
+    fn point_light() -> Grid {
+        let mut grid = Grid::new(SIZE);
+        let mut coefs = [Vec3::ZERO; SH_CS as usize];
+        coefs[0] = Vec3::ONE;
+        grid.store_sh_coefs(CENTER, coefs);
+        grid
+    }
+
+    #[test]
+    fn propagation_roughly_conserves_energy() {
+        let before = point_light().total_radiance().x;
+
+        // the finest layer gains about 0.13% per iteration, the coarser ones lose energy
+        let gain = iterate(&point_light(), &empty(), 0).total_radiance().x / before;
+        assert!((gain - 1.0).abs() < 0.01, "gain {}", gain);
+
+        for layer in 1..LM_LAYERS {
+            let gain = iterate(&point_light(), &empty(), layer).total_radiance().x / before;
+            assert!(gain < 1.0, "gain {} in layer {}", gain, layer);
+        }
+    }
+
+    #[test]
+    fn propagation_is_symmetric() {
+        let grid = iterate(&point_light(), &empty(), 0);
+
+        // coefficient of each axis, in the order of the neighbours
+        for (axis, coef) in [(IVec3::X, 3), (IVec3::Y, 1), (IVec3::Z, 2)] {
+            let positive = grid.load_sh_coefs(CENTER + axis);
+            let negative = grid.load_sh_coefs(CENTER - axis);
+
+            assert_eq!(positive[0], negative[0]);
+            assert_eq!(positive[0], grid.load_sh_coefs(CENTER + IVec3::X)[0]);
+            // light arrives from the center, against the axis
+            assert!(positive[coef].x < 0.0);
+            assert_eq!(positive[coef], -negative[coef]);
+        }
+
+        let edge = grid.load_sh_coefs(CENTER + IVec3::new(1, -1, 0));
+        assert_eq!(edge[0], grid.load_sh_coefs(CENTER + IVec3::new(0, 1, 1))[0]);
+        assert!(edge[0].x < grid.load_sh_coefs(CENTER + IVec3::X)[0].x);
+        // nothing reaches the corners in one iteration
+        assert_eq!(grid.load_sh_coefs(CENTER + IVec3::ONE)[0], Vec3::ZERO);
+    }
+
+    #[test]
+    fn constant_emitters_converge() {
+        let mut voxels = empty();
+        let emitter = Voxel {
+            emittance: Vec3::ONE,
+            normal: Vec3::Z,
+            intersections: 1.0,
+            coverage: 1.0,
+            ..Default::default()
+        };
+        let index = Grid::new(SIZE)
+            .positions()
+            .position(|iil| iil == CENTER)
+            .unwrap();
+        voxels[index] = emitter;
+
+        let mut grid = Grid::new(SIZE);
+        let mut change = f32::INFINITY;
+        for _ in 0..200 {
+            let next = iterate(&grid, &voxels, 1);
+            let new_change = (next.total_radiance() - grid.total_radiance()).abs().x;
+            // until it reaches the rounding errors
+            if change > 1e-3 {
+                assert!(new_change < change);
+            }
+            change = new_change;
+            grid = next;
+        }
+        assert!(change < 1e-4 * grid.total_radiance().x);
+
+        // the black emitter absorbs what it receives
+        assert_eq!(grid.load_sh_coefs(CENTER)[0], Vec3::ONE);
+        assert_eq!(grid.load_sh_coefs(CENTER)[1], Vec3::ZERO);
+    }
+
+    #[test]
+    fn surfaces_reflect_along_their_normal() {
+        let mut grid = Grid::new(SIZE);
+        let mut coefs = [Vec3::ZERO; SH_CS as usize];
+        coefs[0] = Vec3::ONE;
+        // light arriving from +Z
+        coefs[2] = Vec3::splat(0.5);
+        grid.store_sh_coefs(CENTER + IVec3::Z, coefs);
+
+        let mirror = Voxel {
+            reflectance: Vec3::splat(0.5),
+            normal: Vec3::Z,
+            intersections: 1.0,
+            coverage: 1.0,
+            ..Default::default()
+        };
+        let reflected = radiance(&grid, mirror, CENTER, 0);
+        assert!(reflected[0].x > 0.0);
+        // sent back towards +Z, so the next voxel receives it from -Z
+        assert!(reflected[2].x < 0.0);
+        assert_eq!(reflected[1], Vec3::ZERO);
+        assert_eq!(reflected[3], Vec3::ZERO);
+
+        let half = radiance(
+            &grid,
+            Voxel {
+                coverage: 0.5,
+                ..mirror
+            },
+            CENTER,
+            0,
+        );
+        assert!((half[0] - 0.5 * reflected[0]).abs().max_element() < 1e-6);
+    }
+
+    #[test]
+    fn reflections_follow_the_cosine_lobe() {
+        let mut grid = Grid::new(SIZE);
+        let mut coefs = [Vec3::ZERO; SH_CS as usize];
+        coefs[0] = Vec3::ONE;
+        grid.store_sh_coefs(CENTER + IVec3::X, coefs);
+
+        let normal = Vec3::new(1.0, 1.0, 2.0).normalize();
+        let reflected = radiance(
+            &grid,
+            Voxel {
+                reflectance: Vec3::ONE,
+                normal,
+                intersections: 1.0,
+                coverage: 1.0,
+                ..Default::default()
+            },
+            CENTER,
+            0,
+        );
+
+        // every band is scaled alike, the odd first one points away from the surface
+        let cos_lobe = dir_to_cosine_lobe(-normal);
+        let scale = reflected[0].x / cos_lobe[0];
+        assert!(scale > 0.0);
+        for (coef, cos_lobe) in reflected.iter().zip(cos_lobe) {
+            assert!(
+                (coef.x - scale * cos_lobe).abs() < 1e-6,
+                "{} != {}",
+                coef.x,
+                scale * cos_lobe
+            );
+        }
+    }
+
+    #[test]
+    fn walls_block_light() {
+        // a wall facing the emitter in the center
+        let wall = Voxel {
+            reflectance: Vec3::splat(0.5),
+            normal: Vec3::NEG_X,
+            intersections: 1.0,
+            coverage: 1.0,
+            ..Default::default()
+        };
+        let emitter = Voxel {
+            emittance: Vec3::ONE,
+            intersections: 1.0,
+            coverage: 1.0,
+            ..Default::default()
+        };
+
+        let mut voxels = empty();
+        for (voxel, iil) in voxels.iter_mut().zip(Grid::new(SIZE).positions()) {
+            if iil == CENTER {
+                *voxel = emitter;
+            } else if iil.x == CENTER.x + 1 {
+                *voxel = wall;
+            }
+        }
+
+        let mut grid = Grid::new(SIZE);
+        for _ in 0..2 * SIZE {
+            grid = iterate(&grid, &voxels, 0);
+        }
+
+        let behind = |grid: &Grid| {
+            grid.positions()
+                .filter(|iil| iil.x > CENTER.x + 1)
+                .map(|iil| grid.load_sh_coefs(iil)[0].x)
+                .sum::<f32>()
+        };
+        let lit = grid.load_sh_coefs(CENTER - IVec3::X)[0].x;
+        assert!(
+            behind(&grid) < 1e-6 * lit,
+            "{} behind the wall",
+            behind(&grid)
+        );
+        // but reflects towards the emitter, which receives it from +X
+        assert!(grid.load_sh_coefs(CENTER + IVec3::X)[0].x > 0.0);
+        assert!(grid.load_sh_coefs(CENTER + IVec3::X)[3].x > 0.0);
+
+        let open = (0..2 * SIZE).fold(Grid::new(SIZE), |grid, _| {
+            let mut voxels = empty();
+            voxels[grid.positions().position(|iil| iil == CENTER).unwrap()] = emitter;
+            iterate(&grid, &voxels, 0)
+        });
+        assert!(behind(&open) > 0.01 * lit);
+    }
+}
@@ -0,0 +1,821 @@
+//! Mirror of the voxelization in `radiancePrecalc.glsl` and the voxel helpers of
+//! `includes_general.glsl`
+//!
+//! Textures are not sampled, so only scenes with untextured materials match the GPU exactly.
+//! The triangles are found by looping over the instances like the shader did before the BVH,
+//! which also makes this a check of the acceleration structures, which `bvh_triangles` and
+//! `brick_triangles` mirror.
+
+use std::cmp::Ordering;
+
+use glam::*;
+
+use crate::{
+    half::f16_to_f32,
+    scene::{SceneParts, NO_MATERIAL},
+    shaders::{self, PackedVoxel, BRICK_SIZE, RADIANCE_SIZE, RADIANCE_UNIT},
+};
+
+pub const EPSILON: f32 = 1e-4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Voxel {
+    pub emittance: Vec3,
+    pub reflectance: Vec3,
+    pub normal: Vec3,
+    pub intersections: f32,
+    pub coverage: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub center: Vec3,
+    pub half_extents: Vec3,
+}
+
+pub fn rad_unit_size_layer(layer: u32) -> f32 {
+    (1 << layer) as f32 * RADIANCE_UNIT
+}
+
+pub fn rad_origin_at_corner(corner: IVec3, layer: u32) -> Vec3 {
+    (corner + RADIANCE_SIZE as i32 / 2).as_vec3() * rad_unit_size_layer(layer)
+}
+
+pub fn pos_at_rad_index(index: IVec3, layer: u32, origin: Vec3) -> Vec3 {
+    origin
+        + ((index - IVec3::splat(RADIANCE_SIZE as i32 / 2)).as_vec3() + 0.5)
+            * rad_unit_size_layer(layer)
+}
+
+pub fn pack_voxel(v: Voxel) -> PackedVoxel {
+    PackedVoxel {
+        emittance: [
+            pack_half_2x16(v.emittance.truncate()),
+            pack_half_2x16(Vec2::new(v.emittance.z, 0.0)),
+        ],
+        reflectanceAndCoverage: pack_unorm_4x8(v.reflectance.extend(v.coverage)),
+        normalAndIntersections: pack_snorm_4x8(v.normal.extend(1.0 / (v.intersections + 1.0))),
+    }
+}
+
+pub fn unpack_voxel(v: PackedVoxel) -> Voxel {
+    let normal_and_intersections = unpack_snorm_4x8(v.normalAndIntersections);
+    let reflectance_and_coverage = unpack_unorm_4x8(v.reflectanceAndCoverage);
+
+    Voxel {
+        emittance: unpack_half_2x16(v.emittance[0]).extend(unpack_half_2x16(v.emittance[1]).x),
+        reflectance: reflectance_and_coverage.truncate(),
+        normal: normal_and_intersections.truncate(),
+        intersections: 1.0 / normal_and_intersections.w - 1.0,
+        coverage: reflectance_and_coverage.w,
+    }
+}
+
+/// Barycentric coordinates of the point in the triangle closest to the projection of `p` onto
+/// its plane, which are clamped to the triangle's edges
+pub fn closest_barycentric(tri: [Vec3; 3], p: Vec3) -> Vec3 {
+    let ab = tri[1] - tri[0];
+    let ac = tri[2] - tri[0];
+    let ap = p - tri[0];
+
+    let d00 = ab.dot(ab);
+    let d01 = ab.dot(ac);
+    let d11 = ac.dot(ac);
+    let d20 = ap.dot(ab);
+    let d21 = ap.dot(ac);
+    let denom = d00 * d11 - d01 * d01;
+
+    let v = (d11 * d20 - d01 * d21) / denom;
+    let w = (d00 * d21 - d01 * d20) / denom;
+    let weights = Vec3::new(1.0 - v - w, v, w).max(Vec3::ZERO);
+    weights / (weights.x + weights.y + weights.z)
+}
+
+fn intersect_aabb_triangle_sat(tri: [Vec3; 3], aabb_half_extents: Vec3, axis: Vec3) -> bool {
+    let [p0, p1, p2] = tri.map(|v| v.dot(axis));
+
+    let min_p = p0.min(p1).min(p2);
+    let max_p = p0.max(p1).max(p2);
+
+    // NaN axes of degenerate triangles don't separate, like in the shader
+    let separation = (-max_p)
+        .max(min_p)
+        .partial_cmp(&aabb_half_extents.dot(axis.abs()));
+    separation != Some(Ordering::Greater)
+}
+
+/// Separating axis test of a triangle and a box
+pub fn intersect_aabb_triangle(tri: [Vec3; 3], aabb: Aabb) -> bool {
+    let tri = tri.map(|v| v - aabb.center);
+
+    let ab = (tri[1] - tri[0]).normalize();
+    let bc = (tri[2] - tri[1]).normalize();
+    let ca = (tri[0] - tri[2]).normalize();
+
+    let triangle_normal = ab.cross(bc).normalize();
+
+    [Vec3::X, Vec3::Y, Vec3::Z]
+        .into_iter()
+        .flat_map(|axis| [axis.cross(ab), axis.cross(bc), axis.cross(ca)])
+        .chain([Vec3::X, Vec3::Y, Vec3::Z, triangle_normal])
+        .all(|axis| intersect_aabb_triangle_sat(tri, aabb.half_extents, axis))
+}
+
+/// Area and center of mass of the part of the triangle inside of the box
+pub fn clip_triangle_to_aabb(tri: [Vec3; 3], aabb: Aabb) -> (f32, Vec3) {
+    let mut polygon = tri.map(|v| v - aabb.center).to_vec();
+
+    for plane in 0..6 {
+        let axis = plane / 2;
+        let side = if plane % 2 == 0 { -1.0 } else { 1.0 };
+
+        let mut clipped = Vec::with_capacity(polygon.len() + 1);
+        for (i, &a) in polygon.iter().enumerate() {
+            let b = polygon[(i + 1) % polygon.len()];
+            // negative inside of the plane
+            let dist_a = side * a[axis] - aabb.half_extents[axis];
+            let dist_b = side * b[axis] - aabb.half_extents[axis];
+
+            if dist_a <= 0.0 {
+                clipped.push(a);
+            }
+            if (dist_a <= 0.0) != (dist_b <= 0.0) {
+                clipped.push(a.lerp(b, dist_a / (dist_a - dist_b)));
+            }
+        }
+
+        if clipped.len() < 3 {
+            return (0.0, aabb.center);
+        }
+        polygon = clipped;
+    }
+
+    // triangle fan around the first vertex
+    let (area_vector, weighted_center) = polygon[1..]
+        .windows(2)
+        .map(|edge| {
+            let fan_area = (edge[0] - polygon[0]).cross(edge[1] - polygon[0]);
+            (
+                fan_area,
+                fan_area.length() * (polygon[0] + edge[0] + edge[1]) / 3.0,
+            )
+        })
+        .fold((Vec3::ZERO, Vec3::ZERO), |(a, c), (fan_a, fan_c)| {
+            (a + fan_a, c + fan_c)
+        });
+
+    let area = 0.5 * area_vector.length();
+    match area > 0.0 {
+        true => (area, aabb.center + weighted_center / (2.0 * area)),
+        false => (0.0, aabb.center),
+    }
+}
+
+fn world_triangle(scene: &SceneParts, instance: &shaders::Instance, i: usize) -> [Vec3; 3] {
+    let transform = Mat4::from_cols_array_2d(&instance.transform);
+    [i, i + 1, i + 2].map(|i| {
+        let vertex = scene.vertices[scene.vertex_idxs[i] as usize];
+        transform.transform_point3(Vec4::from(vertex).truncate())
+    })
+}
+
+fn add_triangle(
+    voxel: &mut Voxel,
+    scene: &SceneParts,
+    aabb: Aabb,
+    instance: &shaders::Instance,
+    i: usize,
+) {
+    let indices = [i, i + 1, i + 2].map(|i| scene.vertex_idxs[i] as usize);
+    let tri = world_triangle(scene, instance, i);
+
+    if !intersect_aabb_triangle(tri, aabb) {
+        return;
+    }
+
+    // triangles only touching the voxel don't contribute
+    let (area, center) = clip_triangle_to_aabb(tri, aabb);
+    if area <= 0.0 {
+        return;
+    }
+
+    let material = match instance.material {
+        NO_MATERIAL => scene.material_idxs[i / 3],
+        material => material,
+    };
+    let material = scene.materials[material as usize];
+
+    let weights = closest_barycentric(tri, center);
+    let normal = indices
+        .iter()
+        .zip(weights.to_array())
+        .map(|(&index, weight)| weight * Vec4::from(scene.normals[index]).truncate())
+        .sum::<Vec3>();
+    let normal_matrix = Mat3::from_cols_array_2d(&instance.normalMatrix.map(|column| *column));
+
+    voxel.emittance += area * Vec3::from(material.emittance);
+    voxel.reflectance += area * Vec3::from(material.reflectance);
+    voxel.normal += area * (normal_matrix * normal).normalize();
+    voxel.intersections += 1.0;
+    voxel.coverage += area; // total area until normalized
+}
+
+fn overlaps_aabb(min: [f32; 3], max: [f32; 3], aabb: Aabb) -> bool {
+    Vec3::from(min).cmple(aabb.center + aabb.half_extents).all()
+        && Vec3::from(max).cmpge(aabb.center - aabb.half_extents).all()
+}
+
+/// Leaves of the nodes below `root` that overlap the box, like the traversals of
+/// `intersectBvh` and `intersectMesh`
+fn traverse(scene: &SceneParts, root: u32, aabb: Aabb) -> Vec<u32> {
+    let mut leaves = Vec::new();
+    let mut stack = vec![root];
+    while let Some(index) = stack.pop() {
+        let node = scene.bvh.nodes[index as usize];
+        if !overlaps_aabb(node.boundsMin, node.boundsMax, aabb) {
+            continue;
+        }
+        match node.leafCount {
+            0 => stack.extend([node.offset, index + 1]),
+            count => leaves.extend(&scene.bvh.leaves[node.offset as usize..][..count as usize]),
+        }
+    }
+    leaves
+}
+
+/// Instance and first vertex index of the triangles that the BVH finds near the box
+pub fn bvh_triangles(scene: &SceneParts, aabb: Aabb) -> Vec<[u32; 2]> {
+    traverse(scene, 0, aabb)
+        .into_iter()
+        .filter(|&index| {
+            let instance = &scene.instances[index as usize];
+            overlaps_aabb(instance.boundsMin, instance.boundsMax, aabb)
+        })
+        .flat_map(|index| {
+            let instance = &scene.instances[index as usize];
+            // the box in the local space of the mesh
+            let inverse = Mat4::from_cols_array_2d(&instance.inverseTransform);
+            let local = Aabb {
+                center: inverse.transform_point3(aabb.center),
+                half_extents: inverse.x_axis.truncate().abs() * aabb.half_extents.x
+                    + inverse.y_axis.truncate().abs() * aabb.half_extents.y
+                    + inverse.z_axis.truncate().abs() * aabb.half_extents.z,
+            };
+            traverse(scene, instance.bvhRoot, local)
+                .into_iter()
+                .map(move |i| [index, i])
+        })
+        .collect()
+}
+
+/// Indices into `SceneParts::triangles` of the triangles that `triangleBinning.glsl` lists in
+/// `brick` of `layer` of a volume without a previous one, where every brick is exposed
+pub fn brick_triangles(scene: &SceneParts, layer: u32, origin: Vec3, brick: IVec3) -> Vec<u32> {
+    let bricks = (RADIANCE_SIZE / BRICK_SIZE) as i32;
+    let brick_unit = rad_unit_size_layer(layer) * BRICK_SIZE as f32;
+    // inverse of pos_at_rad_index, in bricks
+    let brick_at = |pos: Vec3| ((pos - origin) / brick_unit + (bricks / 2) as f32).floor();
+
+    (0..scene.triangles.len() as u32)
+        .filter(|&t| {
+            let [instance, i] = scene.triangles[t as usize];
+            let tri = world_triangle(scene, &scene.instances[instance as usize], i as usize);
+            let min = tri[0].min(tri[1]).min(tri[2]) - EPSILON;
+            let max = tri[0].max(tri[1]).max(tri[2]) + EPSILON;
+            if brick.as_vec3().cmplt(brick_at(min)).any()
+                || brick.as_vec3().cmpgt(brick_at(max)).any()
+            {
+                return false;
+            }
+
+            let aabb = Aabb {
+                center: origin + ((brick - bricks / 2).as_vec3() + 0.5) * brick_unit,
+                half_extents: Vec3::splat(brick_unit * 0.5 + EPSILON),
+            };
+            intersect_aabb_triangle(tri, aabb)
+        })
+        .collect()
+}
+
+/// The voxel at `index` in `layer`, like `calculateIntersect`
+pub fn calculate_intersect(scene: &SceneParts, index: IVec3, layer: u32, origin: Vec3) -> Voxel {
+    let mut voxel = Voxel::default();
+
+    let unit = rad_unit_size_layer(layer);
+    let aabb = Aabb {
+        center: pos_at_rad_index(index, layer, origin),
+        half_extents: Vec3::splat(unit * 0.5 + EPSILON),
+    };
+
+    for instance in &scene.instances {
+        if !overlaps_aabb(instance.boundsMin, instance.boundsMax, aabb) {
+            continue;
+        }
+
+        let first = instance.firstIndex as usize;
+        for i in (first..first + instance.indexCount as usize).step_by(3) {
+            add_triangle(&mut voxel, scene, aabb, instance, i);
+        }
+    }
+
+    if voxel.coverage > 0.0 {
+        voxel.emittance /= voxel.coverage;
+        voxel.reflectance /= voxel.coverage;
+        voxel.normal = voxel.normal.normalize();
+        voxel.coverage = (voxel.coverage / (unit * unit)).min(1.0);
+    }
+
+    voxel
+}
+
+/// All voxels of a layer in the layout of `RadianceBuffer::voxels[layer]`, which can be compared
+/// with a readback of the GPU's
+pub fn voxelize_layer(scene: &SceneParts, layer: u32, origin: Vec3) -> Vec<PackedVoxel> {
+    let size = RADIANCE_SIZE as i32;
+    (0..size)
+        .flat_map(|x| (0..size).flat_map(move |y| (0..size).map(move |z| IVec3::new(x, y, z))))
+        .map(|index| pack_voxel(calculate_intersect(scene, index, layer, origin)))
+        .collect()
+}
+
+fn pack_half_2x16(v: Vec2) -> u32 {
+    f32_to_f16(v.x) as u32 | (f32_to_f16(v.y) as u32) << 16
+}
+
+fn unpack_half_2x16(v: u32) -> Vec2 {
+    Vec2::new(f16_to_f32(v as u16), f16_to_f32((v >> 16) as u16))
+}
+
+fn pack_unorm_4x8(v: Vec4) -> u32 {
+    v.to_array()
+        .iter()
+        .enumerate()
+        .map(|(i, c)| ((c.clamp(0.0, 1.0) * 255.0).round() as u32) << (8 * i))
+        .sum()
+}
+
+fn unpack_unorm_4x8(v: u32) -> Vec4 {
+    Vec4::from_array([0, 1, 2, 3].map(|i| ((v >> (8 * i)) & 0xff) as f32 / 255.0))
+}
+
+fn pack_snorm_4x8(v: Vec4) -> u32 {
+    v.to_array()
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (((c.clamp(-1.0, 1.0) * 127.0).round() as i8 as u8) as u32) << (8 * i))
+        .sum()
+}
+
+fn unpack_snorm_4x8(v: u32) -> Vec4 {
+    Vec4::from_array(
+        [0, 1, 2, 3].map(|i| ((v >> (8 * i)) as u8 as i8 as f32 / 127.0).clamp(-1.0, 1.0)),
+    )
+}
+
+/// Half precision bits of `value`, rounded to the nearest even value
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    let (half, shift) = match exponent {
+        // too small for a subnormal
+        ..=-11 => return sign,
+        // subnormal, with the implicit leading bit
+        -10..=0 => {
+            let shift = (14 - exponent) as u32;
+            ((mantissa | 0x80_0000) >> shift, shift)
+        }
+        _ => (((exponent as u32) << 10) | (mantissa >> 13), 13),
+    };
+
+    let rest = (mantissa | 0x80_0000) & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    let round_up = rest > halfway || (rest == halfway && half & 1 == 1);
+    // a carry into the exponent is correct, even when it rounds to infinity
+    sign | (half + round_up as u32) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        headless::HeadlessVolume,
+        scene::{self, Bvh, MeshCleanup, MeshDraw, NO_TEXTURE},
+        shaders::LM_LAYERS,
+    };
+
+    fn material(reflectance: f32, emittance: f32) -> shaders::Material {
+        shaders::Material {
+            reflectance: [reflectance; 3],
+            reflectanceTexture: NO_TEXTURE,
+            emittance: [emittance; 3],
+            emittanceTexture: NO_TEXTURE,
+        }
+    }
+
+    /// One instance of axis aligned quads in the plane z = `z`, given by their minimum and
+    /// maximum x and y, and facing +Z
+    fn quads(z: f32, quads: &[(Vec2, Vec2, shaders::Material)]) -> SceneParts {
+        let mut scene = SceneParts {
+            vertices: vec![],
+            normals: vec![],
+            uvs: vec![],
+            vertex_idxs: vec![],
+            material_idxs: vec![],
+            materials: vec![],
+            textures: vec![],
+            instances: vec![],
+            draws: vec![],
+            bvh: Bvh::default(),
+            triangles: vec![],
+            environment: Default::default(),
+        };
+
+        for &(min, max, material) in quads {
+            let first = scene.vertices.len() as u32;
+            for corner in [min, Vec2::new(max.x, min.y), max, Vec2::new(min.x, max.y)] {
+                scene.vertices.push([corner.x, corner.y, z, 0.0]);
+                scene.normals.push([0.0, 0.0, 1.0, 0.0]);
+                scene.uvs.push([0.0; 2]);
+            }
+            scene
+                .vertex_idxs
+                .extend([0, 1, 2, 2, 3, 0].map(|i| first + i));
+            scene
+                .material_idxs
+                .extend([scene.materials.len() as u32; 2]);
+            scene.materials.push(material);
+        }
+
+        let (min, max) = scene.vertices.iter().fold(
+            (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+            |(min, max), &v| {
+                (
+                    min.min(Vec4::from(v).truncate()),
+                    max.max(Vec4::from(v).truncate()),
+                )
+            },
+        );
+        let index_count = scene.vertex_idxs.len() as u32;
+        scene.instances.push(shaders::Instance {
+            transform: Mat4::IDENTITY.to_cols_array_2d(),
+            inverseTransform: Mat4::IDENTITY.to_cols_array_2d(),
+            normalMatrix: Mat3::IDENTITY.to_cols_array_2d().map(Into::into),
+            boundsMin: min.to_array(),
+            firstIndex: 0,
+            boundsMax: max.to_array(),
+            indexCount: index_count,
+            material: NO_MATERIAL,
+            bvhRoot: 0,
+        });
+        scene.draws.push(MeshDraw {
+            first_index: 0,
+            index_count,
+            first_instance: 0,
+            instance_count: 1,
+        });
+        scene
+    }
+
+    /// Voxel of layer 0 that spans [0, 2] on every axis
+    const VOXEL: IVec3 = IVec3::splat(RADIANCE_SIZE as i32 / 2);
+
+    #[test]
+    fn half_floats_round_to_nearest_even() {
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.5), 0xc100);
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(65520.0), 0x7c00);
+        assert_eq!(f32_to_f16(2f32.powi(-24)), 0x0001);
+        // halfway between 1 and the next half float, which is odd
+        assert_eq!(f32_to_f16(1.0 + 2f32.powi(-11)), 0x3c00);
+
+        for value in [0.0, 1.0, -2.5, 0.1, 1e-5, 300.0] {
+            let round_trip = f16_to_f32(f32_to_f16(value));
+            assert!((round_trip - value).abs() <= value.abs() * 1e-3 + 1e-7);
+        }
+    }
+
+    #[test]
+    fn packing_keeps_voxels_within_precision() {
+        let voxel = Voxel {
+            emittance: Vec3::new(0.5, 12.0, 100.0),
+            reflectance: Vec3::new(0.2, 0.4, 0.8),
+            normal: Vec3::new(0.6, 0.0, -0.8),
+            intersections: 3.0,
+            coverage: 0.25,
+        };
+        let unpacked = unpack_voxel(pack_voxel(voxel));
+
+        assert!(unpacked.emittance.abs_diff_eq(voxel.emittance, 0.1));
+        assert!(unpacked
+            .reflectance
+            .abs_diff_eq(voxel.reflectance, 1.0 / 255.0));
+        assert!(unpacked.normal.abs_diff_eq(voxel.normal, 1.0 / 127.0));
+        assert!((unpacked.intersections - voxel.intersections).abs() < 0.05);
+        assert!((unpacked.coverage - voxel.coverage).abs() <= 1.0 / 255.0);
+
+        assert_eq!(unpack_voxel(pack_voxel(Voxel::default())), Voxel::default());
+    }
+
+    #[test]
+    fn voxel_positions_are_centered_on_the_origin() {
+        let half = IVec3::splat(RADIANCE_SIZE as i32 / 2);
+        assert_eq!(
+            pos_at_rad_index(half, 0, Vec3::ZERO),
+            Vec3::splat(0.5 * RADIANCE_UNIT)
+        );
+        assert_eq!(
+            pos_at_rad_index(half - 1, 2, Vec3::ONE),
+            Vec3::ONE - 0.5 * rad_unit_size_layer(2)
+        );
+    }
+
+    #[test]
+    fn triangles_are_clipped_to_the_box() {
+        let aabb = Aabb {
+            center: Vec3::ZERO,
+            half_extents: Vec3::ONE,
+        };
+
+        let inside = [
+            Vec3::ZERO,
+            Vec3::new(0.5, 0.0, 0.0),
+            Vec3::new(0.0, 0.5, 0.0),
+        ];
+        let (area, _) = clip_triangle_to_aabb(inside, aabb);
+        assert!((area - 0.125).abs() < 1e-6);
+
+        // covers the whole cross section at z = 0.5
+        let large = [
+            Vec3::new(-10.0, -10.0, 0.5),
+            Vec3::new(30.0, -10.0, 0.5),
+            Vec3::new(-10.0, 30.0, 0.5),
+        ];
+        let (area, center) = clip_triangle_to_aabb(large, aabb);
+        assert!((area - 4.0).abs() < 1e-4);
+        assert!(center.abs_diff_eq(Vec3::new(0.0, 0.0, 0.5), 1e-5));
+
+        // a quarter of the box, the corner of the triangle is at the center
+        let corner = [
+            Vec3::ZERO,
+            Vec3::new(10.0, 0.0, 0.0),
+            Vec3::new(0.0, 10.0, 0.0),
+        ];
+        let (area, center) = clip_triangle_to_aabb(corner, aabb);
+        assert!((area - 1.0).abs() < 1e-4);
+        assert!(center.abs_diff_eq(Vec3::new(0.5, 0.5, 0.0), 1e-5));
+
+        let outside = inside.map(|v| v + Vec3::splat(3.0));
+        assert_eq!(clip_triangle_to_aabb(outside, aabb).0, 0.0);
+        assert!(!intersect_aabb_triangle(outside, aabb));
+        assert!(intersect_aabb_triangle(inside, aabb));
+        assert!(intersect_aabb_triangle(large, aabb));
+    }
+
+    #[test]
+    fn covered_voxels_take_the_material() {
+        let scene = quads(
+            1.0,
+            &[(Vec2::splat(-10.0), Vec2::splat(10.0), material(0.5, 2.0))],
+        );
+        let voxel = calculate_intersect(&scene, VOXEL, 0, Vec3::ZERO);
+
+        assert!((voxel.coverage - 1.0).abs() < 1e-3);
+        assert!(voxel.emittance.abs_diff_eq(Vec3::splat(2.0), 1e-5));
+        assert!(voxel.reflectance.abs_diff_eq(Vec3::splat(0.5), 1e-5));
+        assert!(voxel.normal.abs_diff_eq(Vec3::Z, 1e-5));
+
+        let empty = calculate_intersect(&scene, VOXEL + IVec3::Z, 0, Vec3::ZERO);
+        assert_eq!(empty, Voxel::default());
+    }
+
+    #[test]
+    fn thin_strips_only_partially_cover_voxels() {
+        let scene = quads(
+            1.0,
+            &[(
+                Vec2::new(0.9, -10.0),
+                Vec2::new(1.1, 10.0),
+                material(0.0, 5.0),
+            )],
+        );
+        let voxel = calculate_intersect(&scene, VOXEL, 0, Vec3::ZERO);
+
+        // 0.2 of the voxel's width of 2
+        assert!((voxel.coverage - 0.1).abs() < 1e-3);
+        assert!(voxel.emittance.abs_diff_eq(Vec3::splat(5.0), 1e-5));
+    }
+
+    #[test]
+    fn materials_are_weighted_by_area() {
+        // three quarters and one quarter of the voxel's cross section
+        let scene = quads(
+            1.0,
+            &[
+                (
+                    Vec2::new(-10.0, -10.0),
+                    Vec2::new(1.5, 10.0),
+                    material(0.8, 0.0),
+                ),
+                (
+                    Vec2::new(1.5, -10.0),
+                    Vec2::new(10.0, 10.0),
+                    material(0.4, 4.0),
+                ),
+            ],
+        );
+        let voxel = calculate_intersect(&scene, VOXEL, 0, Vec3::ZERO);
+
+        assert!(voxel.reflectance.abs_diff_eq(Vec3::splat(0.7), 1e-3));
+        assert!(voxel.emittance.abs_diff_eq(Vec3::splat(1.0), 1e-3));
+        assert!((voxel.coverage - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn layers_are_stored_like_the_radiance_buffer() {
+        let scene = quads(
+            1.0,
+            &[(Vec2::splat(-10.0), Vec2::splat(10.0), material(0.5, 0.0))],
+        );
+        let voxels = voxelize_layer(&scene, 0, Vec3::ZERO);
+        let size = RADIANCE_SIZE as i32;
+
+        // the quad covers 10 by 10 voxels of width 2, their neighbours only overlap by EPSILON
+        let covered = (0..voxels.len())
+            .filter(|&i| unpack_voxel(voxels[i]).coverage > 0.5)
+            .map(|i| {
+                IVec3::new(
+                    i as i32 / (size * size),
+                    i as i32 / size % size,
+                    i as i32 % size,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(covered.len(), 100);
+        assert!(covered.iter().all(|index| index.z == VOXEL.z));
+        assert_eq!(
+            voxels[((VOXEL.x * size + VOXEL.y) * size + VOXEL.z) as usize].reflectanceAndCoverage,
+            pack_voxel(calculate_intersect(&scene, VOXEL, 0, Vec3::ZERO)).reflectanceAndCoverage
+        );
+    }
+
+    /// Instanced, rotated and scaled primitives, which the two levels of the BVH have to handle
+    const INSTANCES: &str = r#"(
+        materials: { "white": (reflectance: (0.5, 0.5, 0.5)) },
+        objects: [
+            (shape: Sphere(radius: 5.0, segments: 8), position: (3.0, -2.0, 1.0), material: "white"),
+            (shape: Cube(2.0), position: (-8.0, 4.0, 0.5), rotation: (30.0, 0.0, 45.0), material: "white"),
+            (shape: Cube(2.0), position: (10.0, 9.0, -3.0), scale: (1.0, 3.0, 0.5), material: "white"),
+            (shape: Torus(major_radius: 6.0, minor_radius: 1.5, segments: 12), position: (0.0, 0.0, -6.0), rotation: (90.0, 20.0, 0.0), material: "white"),
+            (shape: Plane(half_extents: (20.0, 20.0)), position: (0.0, 0.0, -12.0), material: "white"),
+        ],
+    )"#;
+
+    fn load(name: &str, description: &str) -> SceneParts {
+        let path = std::env::temp_dir().join(format!("bound_engine_{}.ron", name));
+        std::fs::write(&path, description).unwrap();
+        scene::load(&path, &MeshCleanup::default()).unwrap()
+    }
+
+    /// Triangles of the instances that intersect the box, found without acceleration structures
+    fn intersecting(scene: &SceneParts, triangles: &[[u32; 2]], aabb: Aabb) -> Vec<[u32; 2]> {
+        let mut triangles = triangles
+            .iter()
+            .copied()
+            .filter(|&[instance, i]| {
+                let tri = world_triangle(scene, &scene.instances[instance as usize], i as usize);
+                intersect_aabb_triangle(tri, aabb)
+            })
+            .collect::<Vec<_>>();
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn acceleration_structures_find_the_triangles_of_the_brute_force_loop() {
+        let scene = load("instances", INSTANCES);
+        assert!(scene.instances.len() > scene.draws.len());
+
+        let mut checked = 0;
+        for layer in 0..3 {
+            let unit = rad_unit_size_layer(layer);
+            let center = IVec3::splat(RADIANCE_SIZE as i32 / 2);
+            for x in (-16..16).step_by(2) {
+                for y in (-16..16).step_by(2) {
+                    for z in (-16..16).step_by(2) {
+                        let index = center + IVec3::new(x, y, z);
+                        let aabb = Aabb {
+                            center: pos_at_rad_index(index, layer, Vec3::ZERO),
+                            half_extents: Vec3::splat(unit * 0.5 + EPSILON),
+                        };
+
+                        let expected = intersecting(&scene, &scene.triangles, aabb);
+                        let bvh = intersecting(&scene, &bvh_triangles(&scene, aabb), aabb);
+                        assert_eq!(bvh, expected, "BVH of voxel {} of layer {}", index, layer);
+
+                        let listed =
+                            brick_triangles(&scene, layer, Vec3::ZERO, index / BRICK_SIZE as i32)
+                                .into_iter()
+                                .map(|t| scene.triangles[t as usize])
+                                .collect::<Vec<_>>();
+                        let listed = intersecting(&scene, &listed, aabb);
+                        assert_eq!(
+                            listed, expected,
+                            "brick of voxel {} of layer {}",
+                            index, layer
+                        );
+
+                        checked += !expected.is_empty() as u32;
+                    }
+                }
+            }
+        }
+        assert!(checked > 100, "only {} voxels contain triangles", checked);
+    }
+
+    fn packed_voxel(bytes: &[u8]) -> PackedVoxel {
+        let word = |i: usize| u32::from_le_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap());
+        PackedVoxel {
+            emittance: [word(0), word(1)],
+            reflectanceAndCoverage: word(2),
+            normalAndIntersections: word(3),
+        }
+    }
+
+    fn matches(gpu: Voxel, cpu: Voxel) -> bool {
+        let unorm = 2.0 / 255.0;
+        if (gpu.coverage - cpu.coverage).abs() > unorm {
+            return false;
+        }
+        cpu.coverage == 0.0
+            || (gpu.reflectance.abs_diff_eq(cpu.reflectance, unorm)
+                && gpu.normal.abs_diff_eq(cpu.normal, 0.05)
+                && (gpu.emittance - cpu.emittance).abs().max_element()
+                    <= 1e-2 * (1.0 + cpu.emittance.abs().max_element()))
+    }
+
+    /// Run with `cargo test -- --ignored` on a machine with a Vulkan driver
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn gpu_voxels_match_the_reference() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/test.ron");
+        let scene = scene::load(&path, &MeshCleanup::default()).unwrap();
+        let mut volume = HeadlessVolume::new(scene::load(&path, &MeshCleanup::default()).unwrap());
+        volume.iterate();
+        let voxels = volume.read_back(0).voxels;
+
+        let size = RADIANCE_SIZE as i32;
+        let voxel_size = std::mem::size_of::<PackedVoxel>();
+        for (layer, corner) in (0..LM_LAYERS).zip(volume.clipmap.corners()) {
+            let reference = voxelize_layer(&scene, layer, rad_origin_at_corner(corner, layer));
+
+            let (mut occupied, mut mismatches) = (0, 0);
+            for (i, &cpu) in reference.iter().enumerate() {
+                let i = i as i32;
+                let index = IVec3::new(i / (size * size), i / size % size, i % size);
+                // radTexelAtIndex
+                let texel = (index + corner) & (size - 1);
+                let offset = (((layer as i32 * size + texel.x) * size + texel.y) * size + texel.z)
+                    as usize
+                    * voxel_size;
+
+                let (gpu, cpu) = (
+                    unpack_voxel(packed_voxel(&voxels[offset..offset + voxel_size])),
+                    unpack_voxel(cpu),
+                );
+                occupied += (cpu.coverage > 0.0) as u32;
+                if !matches(gpu, cpu) {
+                    mismatches += 1;
+                    if mismatches <= 8 {
+                        eprintln!("layer {} voxel {}: {:?} != {:?}", layer, index, gpu, cpu);
+                    }
+                }
+            }
+
+            // triangles that only touch a voxel may fall on either side of it
+            assert!(occupied > 0);
+            assert!(
+                mismatches * 1000 <= occupied,
+                "{} of {} voxels of layer {} differ",
+                mismatches,
+                occupied,
+                layer
+            );
+        }
+    }
+}
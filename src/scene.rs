@@ -1,82 +1,357 @@
-use crate::shaders::{self, MAX_MATERIALS};
+use crate::shaders;
 
 use glam::*;
+use itertools::Itertools;
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+use vulkano::padded::Padded;
 
-// TODO: loading from file
-pub fn load() -> (Vec<[f32; 4]>, Vec<u32>, Vec<u32>, Vec<shaders::Material>) {
-    let mut materials = vec![
-        CpuMaterial {
-            reflectance: Vec3::splat(0.0),
-            emittance: Vec3::splat(100.0),
-        },
-        CpuMaterial {
-            reflectance: Vec3::splat(0.99),
-            emittance: Vec3::splat(0.0),
-        },
-        CpuMaterial {
-            reflectance: Vec3::new(0.99, 0.0, 0.0),
-            emittance: Vec3::splat(0.0),
-        },
-        CpuMaterial {
-            reflectance: Vec3::new(0.0, 0.99, 0.0),
-            emittance: Vec3::splat(0.0),
-        },
-        CpuMaterial {
-            reflectance: Vec3::new(0.0, 0.0, 0.99),
-            emittance: Vec3::splat(0.0),
-        },
-        CpuMaterial {
-            reflectance: Vec3::new(0.99, 0.99, 0.0),
-            emittance: Vec3::splat(0.0),
-        },
-    ];
+mod bvh;
+mod cleanup;
+mod description;
+mod environment;
+mod gltf;
+mod graph;
+mod normals;
+mod obj;
+mod primitives;
+mod validation;
+
+pub use bvh::Bvh;
+pub use cleanup::MeshCleanup;
+pub use environment::Environment;
+pub use graph::{MeshInstance, SceneGraph, SceneNode};
+pub use validation::ValidationReport;
+
+/// Scene loaded when none is given on the command line, see [default_scene]
+const DEFAULT_SCENE: &str = "scenes/test.ron";
+
+/// [DEFAULT_SCENE] in the working directory, or else next to the executable or in one of its
+/// parent directories, which finds it when running from `target`
+pub fn default_scene() -> PathBuf {
+    let relative = PathBuf::from(DEFAULT_SCENE);
+    if relative.exists() {
+        return relative;
+    }
+
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| {
+            exe.ancestors()
+                .skip(1)
+                .map(|dir| dir.join(DEFAULT_SCENE))
+                .find(|path| path.exists())
+        })
+        .unwrap_or(relative)
+}
+
+/// Flattened scene data in the layout [crate::buffer::Buffers] uploads
+pub struct SceneParts {
+    /// vertices of all meshes in local space
+    pub vertices: Vec<[f32; 4]>,
+    /// normal of every vertex in local space
+    pub normals: Vec<[f32; 4]>,
+    /// texture coordinates of every vertex, zero for meshes without them
+    pub uvs: Vec<[f32; 2]>,
+    pub vertex_idxs: Vec<u32>,
+    /// material index of every triangle
+    pub material_idxs: Vec<u32>,
+    pub materials: Vec<shaders::Material>,
+    /// images referenced by the materials' texture indices
+    pub textures: Vec<::image::RgbaImage>,
+    /// placements of the meshes, sorted by mesh
+    pub instances: Vec<shaders::Instance>,
+    /// one instanced draw call per mesh
+    pub draws: Vec<MeshDraw>,
+    pub bvh: Bvh,
+    /// instance and first vertex index of the triangles of every instance, which the triangle
+    /// binning lists per brick
+    pub triangles: Vec<[u32; 2]>,
+    /// lights what is outside of the radiance volume
+    pub environment: Environment,
+}
+
+/// Ranges of indices and instances drawn by one instanced draw call
+#[derive(Clone, Copy, Debug)]
+pub struct MeshDraw {
+    pub first_index: u32,
+    pub index_count: u32,
+    pub first_instance: u32,
+    pub instance_count: u32,
+}
+
+/// Material of instances that keep the materials of their mesh's triangles
+pub const NO_MATERIAL: u32 = u32::MAX;
+
+/// Texture index of materials without a texture
+pub const NO_TEXTURE: u32 = u32::MAX;
+
+/// Image shared by the materials that use it
+pub type Texture = Rc<::image::RgbaImage>;
+
+#[derive(Debug)]
+pub enum LoadError {
+    UnsupportedFormat(PathBuf),
+    Io(PathBuf, io::Error),
+    Ron(PathBuf, ron::error::SpannedError),
+    Obj(tobj::LoadError),
+    Gltf(::gltf::Error),
+    Image(PathBuf, ::image::ImageError),
+    /// error in a mesh file referenced by a scene description
+    Mesh(PathBuf, Box<LoadError>),
+    UnknownMaterial {
+        object: String,
+        name: String,
+    },
+    MissingMaterial(String),
+    /// the scene loaded, but has errors that would break rendering
+    Validation(ValidationReport),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedFormat(path) => {
+                write!(f, "unsupported scene format: {}", path.display())
+            }
+            Self::Io(path, err) => write!(f, "failed to read {}: {}", path.display(), err),
+            Self::Ron(path, err) => write!(f, "{}:{}", path.display(), err),
+            Self::Obj(err) => write!(f, "failed to load OBJ file: {}", err),
+            Self::Gltf(err) => write!(f, "failed to load glTF file: {}", err),
+            Self::Image(path, err) => {
+                write!(f, "failed to load image {}: {}", path.display(), err)
+            }
+            Self::Mesh(path, err) => write!(f, "in mesh {}: {}", path.display(), err),
+            Self::UnknownMaterial { object, name } => {
+                write!(f, "object {} uses unknown material \"{}\"", object, name)
+            }
+            Self::MissingMaterial(object) => {
+                write!(f, "object {} is a primitive and needs a material", object)
+            }
+            Self::Validation(report) => write!(f, "invalid scene:\n{}", report),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
 
-    materials.resize(
-        MAX_MATERIALS,
-        CpuMaterial {
-            reflectance: Vec3::splat(0.0),
-            emittance: Vec3::splat(0.0),
+/// Loads a scene description (.ron) or a single mesh file
+pub fn load(path: &Path, cleanup: &MeshCleanup) -> Result<SceneParts, LoadError> {
+    let (graph, materials, environment) = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("ron") => description::load(path)?,
+        _ => {
+            let (graph, materials) = load_mesh(path)?;
+            (graph, materials, Environment::default())
+        }
+    };
+    let (meshes, mut instances) = graph.flatten();
+
+    let report = validation::validate(&meshes, &instances, &materials);
+    if report.has_errors() {
+        return Err(LoadError::Validation(report));
+    }
+    for warning in report.warnings() {
+        eprintln!("warning: {}", warning);
+    }
+
+    let meshes = meshes
+        .into_iter()
+        .map(|mesh| mesh.cleaned(cleanup))
+        .map(|mut mesh| {
+            if mesh.normals.is_empty() {
+                mesh.generate_normals(cleanup.smoothing_angle);
+            }
+            mesh
+        })
+        .collect::<Vec<_>>();
+
+    instances.sort_by_key(|instance| instance.mesh);
+
+    let bounds = meshes.iter().map(CpuObject::bounds).collect::<Vec<_>>();
+    let index_ranges = meshes
+        .iter()
+        .scan(0, |first_index, mesh| {
+            let range = (*first_index, mesh.indices.len() as u32);
+            *first_index += range.1;
+            Some(range)
+        })
+        .collect::<Vec<_>>();
+
+    let draws = instances
+        .iter()
+        .group_by(|instance| instance.mesh)
+        .into_iter()
+        .scan(0, |first_instance, (mesh, group)| {
+            let instance_count = group.count() as u32;
+            let (first_index, index_count) = index_ranges[mesh];
+            let draw = MeshDraw {
+                first_index,
+                index_count,
+                first_instance: *first_instance,
+                instance_count,
+            };
+            *first_instance += instance_count;
+            Some(draw)
+        })
+        .collect();
+
+    let mut instances: Vec<_> = instances
+        .into_iter()
+        .map(|instance| {
+            let (first_index, index_count) = index_ranges[instance.mesh];
+            let (bounds_min, bounds_max) =
+                transform_bounds(instance.transform, bounds[instance.mesh]);
+            shaders::Instance {
+                transform: instance.transform.to_cols_array_2d(),
+                inverseTransform: instance.transform.inverse().to_cols_array_2d(),
+                normalMatrix: normal_matrix(instance.transform),
+                boundsMin: bounds_min.to_array(),
+                firstIndex: first_index,
+                boundsMax: bounds_max.to_array(),
+                indexCount: index_count,
+                material: instance.material.unwrap_or(NO_MATERIAL),
+                bvhRoot: 0, // set by `Bvh::build`
+            }
+        })
+        .collect();
+
+    let (vertices, normals, uvs, vertex_idxs, material_idxs) =
+        CpuObject::flatten_parts(meshes.into_iter().map(|obj| obj.into_parts()));
+    let bvh = Bvh::build(&mut instances, &vertices, &vertex_idxs);
+    let triangles = instances
+        .iter()
+        .enumerate()
+        .flat_map(|(index, instance)| {
+            let first = instance.firstIndex;
+            (first..first + instance.indexCount)
+                .step_by(3)
+                .map(move |i| [index as u32, i])
+        })
+        .collect();
+
+    // textures are deduplicated by identity, materials share them through `Rc`s
+    let mut textures: Vec<Texture> = vec![];
+    let mut texture_index = |texture: &Option<Texture>| match texture {
+        Some(texture) => match textures.iter().position(|t| Rc::ptr_eq(t, texture)) {
+            Some(index) => index as u32,
+            None => {
+                textures.push(texture.clone());
+                textures.len() as u32 - 1
+            }
         },
-    );
+        None => NO_TEXTURE,
+    };
+    let materials = materials
+        .into_iter()
+        .map(|mat| shaders::Material {
+            reflectance: mat.reflectance.to_array(),
+            reflectanceTexture: texture_index(&mat.reflectance_texture),
+            emittance: mat.emittance.to_array(),
+            emittanceTexture: texture_index(&mat.emittance_texture),
+        })
+        .collect();
+    let textures = textures
+        .into_iter()
+        .map(|texture| Rc::try_unwrap(texture).unwrap_or_else(|rc| (*rc).clone()))
+        .collect();
+
+    Ok(SceneParts {
+        vertices,
+        normals,
+        uvs,
+        vertex_idxs,
+        material_idxs,
+        materials,
+        textures,
+        instances,
+        draws,
+        bvh,
+        triangles,
+        environment,
+    })
+}
 
-    let objects: Vec<CpuObject> = vec![
-        CpuObject::cuboid(
-            Vec3::new(0.0, 0.0, -20.0),
-            Vec3::new(1000.0, 1000.0, 10.0),
-            1,
-        ),
-        CpuObject::cube(Vec3::new(-10.0, 30.0, -5.0), 5.0, 2),
-        CpuObject::cube(Vec3::new(35.0, 20.0, -3.0), 7.0, 3),
-        CpuObject::cube(Vec3::new(20.0, -30.0, -7.0), 3.0, 4),
-        CpuObject::cube(Vec3::new(20.0, 40.0, -4.0), 6.0, 5),
-        CpuObject::cube(Vec3::new(0.0, 0.0, 20.0), 1.0, 0),
-    ];
+/// Inverse transpose of the linear part of `transform`, which transforms normals, in the layout
+/// of a `mat3`
+fn normal_matrix(transform: Mat4) -> [Padded<[f32; 3], 4>; 3] {
+    Mat3::from_mat4(transform)
+        .inverse()
+        .transpose()
+        .to_cols_array_2d()
+        .map(Padded::from)
+}
+
+/// Returns the world space bounding box of a local space bounding box
+fn transform_bounds(transform: Mat4, (min, max): (Vec3, Vec3)) -> (Vec3, Vec3) {
+    let center = transform.transform_point3(0.5 * (min + max));
+    let half_extents = 0.5 * (max - min);
+    let half_extents = transform.x_axis.truncate().abs() * half_extents.x
+        + transform.y_axis.truncate().abs() * half_extents.y
+        + transform.z_axis.truncate().abs() * half_extents.z;
+    (center - half_extents, center + half_extents)
+}
 
-    let (vertices, vertex_idxs, material_idxs) =
-        CpuObject::flatten_parts(objects.into_iter().map(|obj| obj.into_parts()));
-    let materials = materials.into_iter().map(|mat| mat.into()).collect();
+/// Loads the objects and materials of a mesh file (.obj, .gltf or .glb)
+fn load_mesh(path: &Path) -> Result<(SceneGraph, Vec<CpuMaterial>), LoadError> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("obj") => obj::load(path),
+        Some("gltf" | "glb") => gltf::load(path),
+        _ => Err(LoadError::UnsupportedFormat(path.to_owned())),
+    }
+}
+
+/// Converts from the Y-up convention used by most file formats to the Z-up convention used
+/// by the engine
+fn y_up_to_z_up() -> Mat4 {
+    Mat4::from_rotation_x(std::f32::consts::FRAC_PI_2)
+}
 
-    (vertices, vertex_idxs, material_idxs, materials)
+/// Loads an image file as a texture
+fn load_texture(path: &Path) -> Result<Texture, LoadError> {
+    ::image::open(path)
+        .map(|image| Rc::new(image.into_rgba8()))
+        .map_err(|err| LoadError::Image(path.to_owned(), err))
 }
 
+/// The textures are multiplied with the colors
 #[derive(Clone, Debug)]
 pub struct CpuMaterial {
     reflectance: Vec3,
     emittance: Vec3,
+    reflectance_texture: Option<Texture>,
+    emittance_texture: Option<Texture>,
 }
 
-impl From<CpuMaterial> for shaders::Material {
-    fn from(value: CpuMaterial) -> Self {
+impl CpuMaterial {
+    const fn new(reflectance: Vec3, emittance: Vec3) -> Self {
         Self {
-            reflectance: value.reflectance.to_array().into(),
-            emittance: value.emittance.to_array(),
+            reflectance,
+            emittance,
+            reflectance_texture: None,
+            emittance_texture: None,
         }
     }
 }
 
+/// Vertices, normals, texture coordinates, vertex indices and material indices
+type MeshParts = (
+    Vec<[f32; 4]>,
+    Vec<[f32; 4]>,
+    Vec<[f32; 2]>,
+    Vec<u32>,
+    Vec<u32>,
+);
+
 #[derive(Clone, Debug)]
 pub struct CpuObject {
     vertices: Vec<Vec3>,
+    /// one for every vertex, or empty if the mesh has none and they have to be generated
+    normals: Vec<Vec3>,
+    /// one for every vertex, or empty if the mesh isn't textured
+    uvs: Vec<Vec2>,
     indices: Vec<u32>,
     materials: Vec<u32>,
 }
@@ -121,35 +396,56 @@ impl CpuObject {
                 3, 2, 6,
                 6, 7, 3,
             ],
+            normals: vec![],
+            uvs: vec![],
             materials: vec![material; 12],
         }
     }
 }
 
 impl CpuObject {
-    fn into_parts(self) -> (Vec<[f32; 4]>, Vec<u32>, Vec<u32>) {
+    /// Returns the minimum and maximum corner of the bounding box
+    fn bounds(&self) -> (Vec3, Vec3) {
+        self.vertices.iter().fold(
+            (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+            |(min, max), &v| (min.min(v), max.max(v)),
+        )
+    }
+
+    fn into_parts(self) -> MeshParts {
+        let uvs = match self.uvs.is_empty() {
+            true => vec![[0.0; 2]; self.vertices.len()],
+            false => self.uvs.into_iter().map(|uv| uv.to_array()).collect(),
+        };
         (
             self.vertices
                 .into_iter()
                 .map(|v| v.extend(0.0).to_array())
                 .collect(),
+            self.normals
+                .into_iter()
+                .map(|n| n.extend(0.0).to_array())
+                .collect(),
+            uvs,
             self.indices,
             self.materials,
         )
     }
 
-    fn flatten_parts<I>(iter: I) -> (Vec<[f32; 4]>, Vec<u32>, Vec<u32>)
+    fn flatten_parts<I>(iter: I) -> MeshParts
     where
-        I: IntoIterator<Item = (Vec<[f32; 4]>, Vec<u32>, Vec<u32>)>,
+        I: IntoIterator<Item = MeshParts>,
     {
         iter.into_iter().fold(
-            (vec![], vec![], vec![]),
-            |(mut acc_v, mut acc_i, mut acc_mi), (v, is, mi)| {
+            (vec![], vec![], vec![], vec![], vec![]),
+            |(mut acc_v, mut acc_n, mut acc_uv, mut acc_i, mut acc_mi), (v, n, uv, is, mi)| {
                 acc_i.extend(is.into_iter().map(|i| acc_v.len() as u32 + i));
                 acc_v.extend(v);
+                acc_n.extend(n);
+                acc_uv.extend(uv);
                 acc_mi.extend(mi);
 
-                (acc_v, acc_i, acc_mi)
+                (acc_v, acc_n, acc_uv, acc_i, acc_mi)
             },
         )
     }
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+use glam::*;
+
+use crate::shaders;
+
+/// Instances or triangles per leaf, larger leaves make the tree smaller but test more of them per
+/// voxel
+const MAX_LEAF_SIZE: usize = 4;
+
+/// Two level bounding volume hierarchy, which the voxelizer traverses to only test the triangles
+/// near a voxel
+///
+/// The top level is built over the world space bounds of the instances and its leaves list
+/// instances. The bottom levels are built over the triangles of every mesh in its local space and
+/// are shared by all instances of the mesh, so the hierarchy grows with the number of instances
+/// and the number of distinct triangles rather than with their product.
+#[derive(Default)]
+pub struct Bvh {
+    /// the top level followed by the bottom level of every mesh, all depth first with the first
+    /// child of an inner node directly following it
+    ///
+    /// An empty level is a single leaf without contents and with inverted bounds.
+    pub nodes: Vec<shaders::BvhNode>,
+    /// contents of the leaves, instance indices in the top level and first vertex indices of
+    /// triangles in the bottom levels
+    pub leaves: Vec<u32>,
+}
+
+struct Primitive {
+    min: Vec3,
+    max: Vec3,
+    centroid: Vec3,
+    /// instance index or first vertex index of the triangle
+    leaf: u32,
+}
+
+impl Primitive {
+    fn new(min: Vec3, max: Vec3, leaf: u32) -> Self {
+        Self {
+            min,
+            max,
+            centroid: 0.5 * (min + max),
+            leaf,
+        }
+    }
+}
+
+impl Bvh {
+    /// Builds both levels and points every instance at the bottom level of its mesh
+    ///
+    /// Every level is split at the median of the centroids along the longest axis, which keeps
+    /// it balanced and the traversal stacks of the shader small.
+    pub(super) fn build(
+        instances: &mut [shaders::Instance],
+        vertices: &[[f32; 4]],
+        vertex_idxs: &[u32],
+    ) -> Self {
+        let mut bvh = Self::default();
+
+        let mut primitives = instances
+            .iter()
+            .enumerate()
+            .map(|(index, instance)| {
+                Primitive::new(
+                    Vec3::from(instance.boundsMin),
+                    Vec3::from(instance.boundsMax),
+                    index as u32,
+                )
+            })
+            .collect::<Vec<_>>();
+        bvh.build_node(&mut primitives);
+
+        let mut roots = HashMap::new();
+        for instance in instances {
+            let mesh = (instance.firstIndex, instance.indexCount);
+            instance.bvhRoot = *roots.entry(mesh).or_insert_with(|| {
+                let root = bvh.nodes.len() as u32;
+                bvh.build_node(&mut mesh_primitives(mesh, vertices, vertex_idxs));
+                root
+            });
+        }
+
+        bvh
+    }
+
+    fn build_node(&mut self, primitives: &mut [Primitive]) {
+        let (min, max) = primitives.iter().fold(
+            (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+            |(min, max), p| (min.min(p.min), max.max(p.max)),
+        );
+
+        let index = self.nodes.len();
+        self.nodes.push(shaders::BvhNode {
+            boundsMin: min.to_array(),
+            offset: 0,
+            boundsMax: max.to_array(),
+            leafCount: 0,
+        });
+
+        if primitives.len() <= MAX_LEAF_SIZE {
+            self.nodes[index].offset = self.leaves.len() as u32;
+            self.nodes[index].leafCount = primitives.len() as u32;
+            self.leaves.extend(primitives.iter().map(|p| p.leaf));
+            return;
+        }
+
+        let (centroid_min, centroid_max) = primitives.iter().fold(
+            (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+            |(min, max), p| (min.min(p.centroid), max.max(p.centroid)),
+        );
+        let extent = centroid_max - centroid_min;
+        let axis = match extent.max_element() {
+            e if e == extent.x => 0,
+            e if e == extent.y => 1,
+            _ => 2,
+        };
+
+        let middle = primitives.len() / 2;
+        primitives
+            .select_nth_unstable_by(middle, |a, b| a.centroid[axis].total_cmp(&b.centroid[axis]));
+        let (left, right) = primitives.split_at_mut(middle);
+
+        self.build_node(left);
+        self.nodes[index].offset = self.nodes.len() as u32;
+        self.build_node(right);
+    }
+}
+
+/// Triangles of the mesh with the index range `(first_index, index_count)` in its local space
+fn mesh_primitives(
+    (first_index, index_count): (u32, u32),
+    vertices: &[[f32; 4]],
+    vertex_idxs: &[u32],
+) -> Vec<Primitive> {
+    (first_index..first_index + index_count)
+        .step_by(3)
+        .map(|i| {
+            let [a, b, c] = [i, i + 1, i + 2]
+                .map(|i| Vec4::from(vertices[vertex_idxs[i as usize] as usize]).truncate());
+            Primitive::new(a.min(b).min(c), a.max(b).max(c), i)
+        })
+        .collect()
+}
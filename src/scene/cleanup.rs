@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+
+use glam::*;
+
+use super::{validation::is_degenerate, CpuObject};
+
+/// Optional fixes applied to every mesh before it is uploaded, which shrink the vertex buffer
+/// and the triangle loops of the shaders
+#[derive(Clone, Debug)]
+pub struct MeshCleanup {
+    /// vertices closer than this are merged, must be positive
+    ///
+    /// Vertices with different normals or texture coordinates are kept apart.
+    pub weld_tolerance: Option<f32>,
+    pub remove_unused: bool,
+    /// removes triangles with (nearly) zero area, they have no normal
+    pub drop_degenerate: bool,
+    /// sorts the triangles spatially and numbers the vertices in the order they are first used
+    ///
+    /// The direct pass doesn't use indexed draws, so there is no post-transform cache to
+    /// optimize for. Instead this keeps neighbouring triangles and their vertices close in
+    /// memory for the voxelizer.
+    pub reorder: bool,
+    /// maximum angle in degrees between two faces that share normals, for meshes without
+    /// normals, 0 gives flat shading
+    pub smoothing_angle: f32,
+}
+
+impl Default for MeshCleanup {
+    fn default() -> Self {
+        Self {
+            weld_tolerance: Some(1e-5),
+            remove_unused: true,
+            drop_degenerate: true,
+            reorder: false,
+            smoothing_angle: 30.0,
+        }
+    }
+}
+
+impl CpuObject {
+    /// Applies `cleanup`, the mesh must have passed validation
+    pub(super) fn cleaned(mut self, cleanup: &MeshCleanup) -> Self {
+        if let Some(tolerance) = cleanup.weld_tolerance {
+            let (welded, remap) = weld(&self.vertices, &self.normals, &self.uvs, tolerance);
+            self.keep_vertices(&welded);
+            self.indices
+                .iter_mut()
+                .for_each(|i| *i = remap[*i as usize]);
+        }
+
+        if cleanup.drop_degenerate {
+            self.drop_degenerate();
+        }
+
+        if cleanup.reorder {
+            self.sort_triangles();
+        }
+
+        // numbering by first use also removes unused vertices
+        if cleanup.remove_unused || cleanup.reorder {
+            self.renumber_vertices();
+        }
+
+        self
+    }
+
+    fn drop_degenerate(&mut self) {
+        let (indices, materials) = self
+            .indices
+            .chunks_exact(3)
+            .zip(&self.materials)
+            .filter(|(tri, _)| {
+                let [a, b, c] = [tri[0], tri[1], tri[2]].map(|i| self.vertices[i as usize]);
+                !is_degenerate(a, b, c)
+            })
+            .fold(
+                (vec![], vec![]),
+                |(mut indices, mut materials), (tri, &mat)| {
+                    indices.extend_from_slice(tri);
+                    materials.push(mat);
+                    (indices, materials)
+                },
+            );
+
+        self.indices = indices;
+        self.materials = materials;
+    }
+
+    /// Sorts the triangles along a Morton curve through their centroids
+    fn sort_triangles(&mut self) {
+        let centroids = self
+            .indices
+            .chunks_exact(3)
+            .map(|tri| tri.iter().map(|&i| self.vertices[i as usize]).sum::<Vec3>() / 3.0)
+            .collect::<Vec<_>>();
+
+        let (min, max) = centroids.iter().fold(
+            (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+            |(min, max), &c| (min.min(c), max.max(c)),
+        );
+        let scale = 1023.0 / (max - min).max(Vec3::splat(f32::EPSILON));
+
+        let mut order = (0..centroids.len()).collect::<Vec<_>>();
+        order.sort_by_cached_key(|&tri| morton(((centroids[tri] - min) * scale).as_uvec3()));
+
+        self.indices = order
+            .iter()
+            .flat_map(|&tri| &self.indices[3 * tri..3 * tri + 3])
+            .copied()
+            .collect();
+        self.materials = order.iter().map(|&tri| self.materials[tri]).collect();
+    }
+
+    /// Numbers the vertices in the order they are first used, unused vertices are removed
+    fn renumber_vertices(&mut self) {
+        let mut remap = vec![u32::MAX; self.vertices.len()];
+        let mut order = vec![];
+
+        for index in self.indices.iter_mut() {
+            let new = &mut remap[*index as usize];
+            if *new == u32::MAX {
+                *new = order.len() as u32;
+                order.push(*index);
+            }
+            *index = *new;
+        }
+
+        self.keep_vertices(&order);
+    }
+
+    /// Keeps the vertices (and their attributes) at `indices` in that order
+    fn keep_vertices(&mut self, indices: &[u32]) {
+        self.vertices = indices.iter().map(|&i| self.vertices[i as usize]).collect();
+        if !self.normals.is_empty() {
+            self.normals = indices.iter().map(|&i| self.normals[i as usize]).collect();
+        }
+        if !self.uvs.is_empty() {
+            self.uvs = indices.iter().map(|&i| self.uvs[i as usize]).collect();
+        }
+    }
+}
+
+/// Merges vertices closer than `tolerance` with equal normals and texture coordinates (if
+/// there are any), returns the old indices of the kept vertices and the new index of every old
+/// vertex
+fn weld(vertices: &[Vec3], normals: &[Vec3], uvs: &[Vec2], tolerance: f32) -> (Vec<u32>, Vec<u32>) {
+    // every vertex within the tolerance is in the same or a neighbouring cell, as 64 bit
+    // integers, which far away vertices and small tolerances don't overflow
+    let cell = |v: Vec3| (v / tolerance).floor().to_array().map(|c| c as i64);
+
+    let mut grid = HashMap::<[i64; 3], Vec<u32>>::new();
+    let same_attributes = |a: usize, b: usize| {
+        (normals.is_empty() || normals[a].distance_squared(normals[b]) <= 1e-6)
+            && (uvs.is_empty() || uvs[a].distance_squared(uvs[b]) <= 1e-10)
+    };
+
+    let mut welded = Vec::<u32>::new();
+    let mut remap = Vec::with_capacity(vertices.len());
+
+    for (old, &v) in vertices.iter().enumerate() {
+        let center = cell(v);
+        let existing = (-1..=1)
+            .flat_map(|x| (-1..=1).flat_map(move |y| (-1..=1).map(move |z| [x, y, z])))
+            .filter_map(|offset: [i64; 3]| {
+                // saturated cells of vertices beyond the range of i64 are their own neighbours
+                grid.get(&[0, 1, 2].map(|i| center[i].saturating_add(offset[i])))
+            })
+            .flatten()
+            .find(|&&i| {
+                let kept = welded[i as usize] as usize;
+                vertices[kept].distance(v) <= tolerance && same_attributes(kept, old)
+            })
+            .copied();
+
+        let index = existing.unwrap_or_else(|| {
+            welded.push(old as u32);
+            let index = welded.len() as u32 - 1;
+            grid.entry(center).or_default().push(index);
+            index
+        });
+        remap.push(index);
+    }
+
+    (welded, remap)
+}
+
+/// Interleaves the lower 10 bits of every component
+fn morton(v: UVec3) -> u32 {
+    fn spread(mut x: u32) -> u32 {
+        x &= 0x3ff;
+        x = (x | (x << 16)) & 0x030000ff;
+        x = (x | (x << 8)) & 0x0300f00f;
+        x = (x | (x << 4)) & 0x030c30c3;
+        x = (x | (x << 2)) & 0x09249249;
+        x
+    }
+
+    spread(v.x) | (spread(v.y) << 1) | (spread(v.z) << 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two quads sharing an edge, with every triangle's vertices listed separately and an unused
+    /// vertex at the end
+    fn quads() -> CpuObject {
+        let corners = [
+            Vec3::ZERO,
+            Vec3::X,
+            Vec3::new(1.0, 1.0, 0.0),
+            Vec3::Y,
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(2.0, 1.0, 0.0),
+        ];
+        let triangles = [[0, 1, 2], [2, 3, 0], [1, 4, 5], [5, 2, 1]];
+        let mut vertices = triangles
+            .iter()
+            .flatten()
+            .map(|&i| corners[i])
+            .collect::<Vec<_>>();
+        vertices.push(Vec3::splat(5.0));
+
+        CpuObject {
+            normals: vec![Vec3::Z; vertices.len()],
+            uvs: vec![],
+            indices: (0..12).collect(),
+            materials: vec![0, 1, 2, 3],
+            vertices,
+        }
+    }
+
+    /// Triangles as their vertex positions and material, which cleanup must preserve
+    fn triangles(object: &CpuObject) -> Vec<([Vec3; 3], u32)> {
+        object
+            .indices
+            .chunks_exact(3)
+            .map(|tri| [tri[0], tri[1], tri[2]].map(|i| object.vertices[i as usize]))
+            .zip(object.materials.iter().copied())
+            .collect()
+    }
+
+    fn cleanup() -> MeshCleanup {
+        MeshCleanup {
+            weld_tolerance: None,
+            remove_unused: false,
+            drop_degenerate: false,
+            reorder: false,
+            smoothing_angle: 0.0,
+        }
+    }
+
+    #[test]
+    fn close_vertices_are_welded() {
+        let object = quads();
+        let welded = object.clone().cleaned(&MeshCleanup {
+            weld_tolerance: Some(1e-5),
+            ..cleanup()
+        });
+
+        // the 6 corners and the unused vertex
+        assert_eq!(welded.vertices.len(), 7);
+        assert_eq!(welded.normals.len(), 7);
+        assert_eq!(welded.indices.len(), 12);
+        assert_eq!(triangles(&welded), triangles(&object));
+    }
+
+    #[test]
+    fn vertices_with_other_normals_are_kept_apart() {
+        let mut object = quads();
+        object.normals[3] = Vec3::X; // corner 2 of the second triangle
+        let welded = object.clone().cleaned(&MeshCleanup {
+            weld_tolerance: Some(1e-5),
+            ..cleanup()
+        });
+
+        assert_eq!(welded.vertices.len(), 8);
+        assert_eq!(triangles(&welded), triangles(&object));
+    }
+
+    #[test]
+    fn far_away_vertices_are_welded() {
+        // cells beyond the range of i32, and beyond the range of i64
+        for offset in [1e12, f32::MAX / 2.0] {
+            // far enough apart that the corners stay distinct in single precision
+            let spacing = offset * 1e-3;
+            let mut object = quads();
+            object
+                .vertices
+                .iter_mut()
+                .for_each(|v| *v = *v * spacing + Vec3::splat(offset));
+            let welded = object.clone().cleaned(&MeshCleanup {
+                weld_tolerance: Some(1e-5),
+                ..cleanup()
+            });
+
+            // the 6 corners and the unused vertex, like close by
+            assert_eq!(welded.vertices.len(), 7);
+            assert_eq!(welded.indices.len(), 12);
+            for (old, &new) in object.indices.iter().zip(&welded.indices) {
+                let moved = object.vertices[*old as usize].distance(welded.vertices[new as usize]);
+                assert!(moved <= 1e-5);
+            }
+            assert_eq!(triangles(&welded), triangles(&object));
+        }
+    }
+
+    #[test]
+    fn unused_vertices_are_removed() {
+        let object = quads();
+        let cleaned = object.clone().cleaned(&MeshCleanup {
+            remove_unused: true,
+            ..cleanup()
+        });
+
+        assert_eq!(cleaned.vertices.len(), 12);
+        assert_eq!(cleaned.normals.len(), 12);
+        assert_eq!(triangles(&cleaned), triangles(&object));
+    }
+
+    #[test]
+    fn degenerate_triangles_are_dropped() {
+        let mut object = quads();
+        let first = object.vertices.len() as u32;
+        object.vertices.extend([Vec3::ZERO, Vec3::X, 2.0 * Vec3::X]);
+        object.normals.extend([Vec3::Z; 3]);
+        object.indices.extend([first, first + 1, first + 2]);
+        object.materials.push(4);
+
+        let cleaned = object.clone().cleaned(&MeshCleanup {
+            drop_degenerate: true,
+            ..cleanup()
+        });
+
+        assert_eq!(cleaned.indices.len(), 12);
+        assert_eq!(triangles(&cleaned), triangles(&object)[..4]);
+    }
+
+    #[test]
+    fn reordering_keeps_the_triangles() {
+        let object = quads();
+        let reordered = object.clone().cleaned(&MeshCleanup {
+            reorder: true,
+            ..cleanup()
+        });
+
+        // the triangles below the diagonals come first, as y has the higher bits of the codes
+        assert_eq!(reordered.materials, [0, 2, 1, 3]);
+
+        // vertices are numbered by first use
+        assert_eq!(reordered.vertices.len(), 12);
+        let mut next = 0;
+        for &index in &reordered.indices {
+            assert!(index <= next);
+            next = next.max(index + 1);
+        }
+
+        let mut sorted = triangles(&reordered);
+        sorted.sort_by_key(|&(_, material)| material);
+        assert_eq!(sorted, triangles(&object));
+    }
+
+    #[test]
+    fn morton_codes_interleave_the_bits() {
+        assert_eq!(morton(UVec3::new(1, 0, 0)), 0b001);
+        assert_eq!(morton(UVec3::new(0, 1, 0)), 0b010);
+        assert_eq!(morton(UVec3::new(0, 0, 1)), 0b100);
+        assert_eq!(morton(UVec3::new(3, 0, 1)), 0b001_101);
+        assert_eq!(morton(UVec3::splat(1023)), (1 << 30) - 1);
+    }
+}
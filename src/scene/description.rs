@@ -0,0 +1,410 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use glam::*;
+use petgraph::graph::NodeIndex;
+use ron::extensions::Extensions;
+use serde::Deserialize;
+
+use super::{
+    CpuMaterial, CpuObject, Environment, LoadError, MeshInstance, SceneGraph, SceneNode, Texture,
+};
+
+/// Text scene format, written in RON
+///
+/// ```ron
+/// (
+///     materials: {
+///         "light": (emittance: (100.0, 100.0, 100.0)),
+///         "white": (reflectance: (0.99, 0.99, 0.99)),
+///         "brick": (reflectance_texture: "textures/brick.png"),
+///     },
+///     objects: [
+///         (shape: Cuboid((1000.0, 1000.0, 10.0)), position: (0.0, 0.0, -20.0), material: "white"),
+///         (shape: Cube(1.0), position: (0.0, 0.0, 20.0), material: "light"),
+///         (shape: Sphere(radius: 5.0, segments: 64), position: (0.0, 20.0, 0.0), material: "white"),
+///         (shape: Torus(major_radius: 4.0, minor_radius: 1.0), rotation: (90.0, 0.0, 0.0), material: "white"),
+///         (shape: Mesh("models/crate.obj"), rotation: (0.0, 0.0, 45.0), scale: (2.0, 2.0, 2.0)),
+///         (
+///             position: (10.0, 0.0, 0.0),
+///             children: [(shape: Cube(1.0), position: (0.0, 0.0, 1.0), material: "white")],
+///         ),
+///     ],
+///     environment: Sky(zenith: (0.2, 0.4, 1.0), horizon: (0.8, 0.9, 1.0), ground: (0.1, 0.1, 0.1)),
+/// )
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDescription {
+    materials: BTreeMap<String, MaterialDescription>,
+    objects: Vec<ObjectDescription>,
+    /// black by default
+    #[serde(default)]
+    environment: Environment,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDescription {
+    /// defaults to white with a texture and black without one
+    #[serde(default)]
+    reflectance: Option<Vec3>,
+    #[serde(default)]
+    emittance: Vec3,
+    /// image files relative to the scene file, multiplied with the colors
+    #[serde(default)]
+    reflectance_texture: Option<PathBuf>,
+    #[serde(default)]
+    emittance_texture: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ObjectDescription {
+    /// objects without a shape only group their children
+    #[serde(default)]
+    shape: Option<Shape>,
+    #[serde(default)]
+    position: Vec3,
+    /// rotation in degrees around the X, Y and Z axes, applied in that order
+    #[serde(default)]
+    rotation: Vec3,
+    #[serde(default = "unit_scale")]
+    scale: Vec3,
+    /// required for primitives, overrides the file's materials for meshes
+    #[serde(default)]
+    material: Option<String>,
+    /// objects positioned relative to this one
+    #[serde(default)]
+    children: Vec<ObjectDescription>,
+}
+
+fn unit_scale() -> Vec3 {
+    Vec3::ONE
+}
+
+/// Shapes other than meshes are centered on the object's position, round shapes have their
+/// axis along Z
+#[derive(Clone, Deserialize, PartialEq)]
+enum Shape {
+    /// half extent
+    Cube(f32),
+    /// half extents
+    Cuboid(Vec3),
+    Sphere {
+        radius: f32,
+        #[serde(default = "default_segments")]
+        segments: u32,
+    },
+    Cylinder {
+        radius: f32,
+        half_height: f32,
+        #[serde(default = "default_segments")]
+        segments: u32,
+    },
+    Cone {
+        radius: f32,
+        half_height: f32,
+        #[serde(default = "default_segments")]
+        segments: u32,
+    },
+    Torus {
+        major_radius: f32,
+        minor_radius: f32,
+        #[serde(default = "default_segments")]
+        segments: u32,
+    },
+    /// faces +Z
+    Plane {
+        half_extents: Vec2,
+        #[serde(default = "default_subdivisions")]
+        subdivisions: u32,
+    },
+    Capsule {
+        radius: f32,
+        half_height: f32,
+        #[serde(default = "default_segments")]
+        segments: u32,
+    },
+    /// path to a mesh file, relative to the scene file
+    Mesh(PathBuf),
+}
+
+fn default_segments() -> u32 {
+    32
+}
+
+fn default_subdivisions() -> u32 {
+    1
+}
+
+impl ObjectDescription {
+    fn transform(&self) -> Mat4 {
+        let rotation = Quat::from_euler(
+            EulerRot::ZYX,
+            self.rotation.z.to_radians(),
+            self.rotation.y.to_radians(),
+            self.rotation.x.to_radians(),
+        );
+        Mat4::from_scale_rotation_translation(self.scale, rotation, self.position)
+    }
+}
+
+/// Loads a scene description and the mesh files it references
+pub fn load(path: &Path) -> Result<(SceneGraph, Vec<CpuMaterial>, Environment), LoadError> {
+    let text = fs::read_to_string(path).map_err(|err| LoadError::Io(path.to_owned(), err))?;
+    let description: SceneDescription = ron::Options::default()
+        .with_default_extension(Extensions::IMPLICIT_SOME)
+        .from_str(&text)
+        .map_err(|err| LoadError::Ron(path.to_owned(), err))?;
+
+    let material_idxs = description
+        .materials
+        .keys()
+        .enumerate()
+        .map(|(index, name)| (name.as_str(), index as u32))
+        .collect::<BTreeMap<_, _>>();
+
+    let directory = path.parent().unwrap_or(Path::new(""));
+
+    let mut textures = HashMap::<PathBuf, Texture>::new();
+    let mut texture = |path: &Option<PathBuf>| -> Result<Option<Texture>, LoadError> {
+        let Some(path) = path else {
+            return Ok(None);
+        };
+        let path = directory.join(path);
+        if let Some(texture) = textures.get(&path) {
+            return Ok(Some(texture.clone()));
+        }
+        let texture = super::load_texture(&path)?;
+        textures.insert(path, texture.clone());
+        Ok(Some(texture))
+    };
+
+    let materials = description
+        .materials
+        .values()
+        .map(|mat| {
+            let reflectance_texture = texture(&mat.reflectance_texture)?;
+            let default_reflectance = match reflectance_texture {
+                Some(_) => Vec3::ONE,
+                None => Vec3::ZERO,
+            };
+
+            Ok(CpuMaterial {
+                reflectance: mat.reflectance.unwrap_or(default_reflectance),
+                emittance: mat.emittance,
+                reflectance_texture,
+                emittance_texture: texture(&mat.emittance_texture)?,
+            })
+        })
+        .collect::<Result<_, LoadError>>()?;
+
+    let mut loader = Loader {
+        directory,
+        material_idxs,
+        materials,
+        graph: SceneGraph::new(),
+        prefabs: HashMap::new(),
+        unit_cube: None,
+        primitives: vec![],
+    };
+
+    for (index, object) in description.objects.iter().enumerate() {
+        loader.add_object(None, &index.to_string(), object)?;
+    }
+
+    Ok((loader.graph, loader.materials, description.environment))
+}
+
+struct Loader<'a> {
+    /// directory mesh paths are relative to
+    directory: &'a Path,
+    material_idxs: BTreeMap<&'a str, u32>,
+    materials: Vec<CpuMaterial>,
+    graph: SceneGraph,
+    /// mesh files that have already been loaded, with their meshes moved to `graph`
+    prefabs: HashMap<PathBuf, Rc<SceneGraph>>,
+    unit_cube: Option<usize>,
+    /// meshes of the other primitive shapes, shared by objects with equal shapes
+    primitives: Vec<(Shape, usize)>,
+}
+
+impl Loader<'_> {
+    /// Adds `object` and its children to the graph, `name` identifies it in errors
+    fn add_object(
+        &mut self,
+        parent: Option<NodeIndex>,
+        name: &str,
+        object: &ObjectDescription,
+    ) -> Result<(), LoadError> {
+        let material = match &object.material {
+            Some(material) => {
+                Some(*self.material_idxs.get(material.as_str()).ok_or_else(|| {
+                    LoadError::UnknownMaterial {
+                        object: name.to_owned(),
+                        name: material.clone(),
+                    }
+                })?)
+            }
+            None => None,
+        };
+        let primitive_material =
+            || material.ok_or_else(|| LoadError::MissingMaterial(name.to_owned()));
+
+        let index = self
+            .graph
+            .add_node(parent, SceneNode::new(object.transform(), None));
+
+        match &object.shape {
+            Some(Shape::Cube(half_extent)) => {
+                self.add_cube(index, Vec3::splat(*half_extent), primitive_material()?)
+            }
+            Some(Shape::Cuboid(half_extents)) => {
+                self.add_cube(index, *half_extents, primitive_material()?)
+            }
+            Some(Shape::Mesh(mesh_path)) => {
+                let mesh_path = self.directory.join(mesh_path);
+                let prefab = self.prefab(&mesh_path)?;
+                self.graph.attach(Some(index), &prefab, material);
+            }
+            Some(shape) => self.add_primitive(index, shape, primitive_material()?),
+            None => {}
+        }
+
+        for (child_index, child) in object.children.iter().enumerate() {
+            self.add_object(Some(index), &format!("{}.{}", name, child_index), child)?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds a cuboid as a child of `parent`, all cuboids share the same unit cube mesh
+    fn add_cube(&mut self, parent: NodeIndex, half_extents: Vec3, material: u32) {
+        let mesh = *self.unit_cube.get_or_insert_with(|| {
+            self.graph
+                .add_mesh(CpuObject::cube(Vec3::ZERO, 1.0, material))
+        });
+
+        self.graph.add_node(
+            Some(parent),
+            SceneNode::new(
+                Mat4::from_scale(half_extents),
+                Some(MeshInstance {
+                    mesh,
+                    material: Some(material),
+                }),
+            ),
+        );
+    }
+
+    /// Adds a primitive other than a cuboid as a child of `parent`
+    fn add_primitive(&mut self, parent: NodeIndex, shape: &Shape, material: u32) {
+        let cached = self
+            .primitives
+            .iter()
+            .find(|(other, _)| other == shape)
+            .map(|&(_, mesh)| mesh);
+
+        let mesh = match cached {
+            Some(mesh) => mesh,
+            None => {
+                let (position, rotation) = (Vec3::ZERO, Quat::IDENTITY);
+                let object = match *shape {
+                    Shape::Sphere { radius, segments } => {
+                        CpuObject::sphere(position, rotation, radius, segments, material)
+                    }
+                    Shape::Cylinder {
+                        radius,
+                        half_height,
+                        segments,
+                    } => CpuObject::cylinder(
+                        position,
+                        rotation,
+                        radius,
+                        half_height,
+                        segments,
+                        material,
+                    ),
+                    Shape::Cone {
+                        radius,
+                        half_height,
+                        segments,
+                    } => {
+                        CpuObject::cone(position, rotation, radius, half_height, segments, material)
+                    }
+                    Shape::Torus {
+                        major_radius,
+                        minor_radius,
+                        segments,
+                    } => CpuObject::torus(
+                        position,
+                        rotation,
+                        major_radius,
+                        minor_radius,
+                        segments,
+                        material,
+                    ),
+                    Shape::Plane {
+                        half_extents,
+                        subdivisions,
+                    } => CpuObject::plane(position, rotation, half_extents, subdivisions, material),
+                    Shape::Capsule {
+                        radius,
+                        half_height,
+                        segments,
+                    } => CpuObject::capsule(
+                        position,
+                        rotation,
+                        radius,
+                        half_height,
+                        segments,
+                        material,
+                    ),
+                    Shape::Cube(_) | Shape::Cuboid(_) | Shape::Mesh(_) => unreachable!(),
+                };
+                let mesh = self.graph.add_mesh(object);
+                self.primitives.push((shape.clone(), mesh));
+                mesh
+            }
+        };
+
+        self.graph.add_node(
+            Some(parent),
+            SceneNode::new(
+                Mat4::IDENTITY,
+                Some(MeshInstance {
+                    mesh,
+                    material: Some(material),
+                }),
+            ),
+        );
+    }
+
+    /// Returns the graph of a mesh file, which is only loaded on first use
+    fn prefab(&mut self, path: &Path) -> Result<Rc<SceneGraph>, LoadError> {
+        if let Some(prefab) = self.prefabs.get(path) {
+            return Ok(prefab.clone());
+        }
+
+        let (mut prefab, mesh_materials) = super::load_mesh(path)
+            .map_err(|err| LoadError::Mesh(path.to_owned(), Box::new(err)))?;
+
+        let offset = self.materials.len() as u32;
+        self.materials.extend(mesh_materials);
+        prefab
+            .meshes_mut()
+            .flat_map(|mesh| mesh.materials.iter_mut())
+            .for_each(|mat| *mat += offset);
+
+        self.graph.take_meshes(&mut prefab);
+
+        let prefab = Rc::new(prefab);
+        self.prefabs.insert(path.to_owned(), prefab.clone());
+        Ok(prefab)
+    }
+}
@@ -0,0 +1,149 @@
+use glam::*;
+use serde::Deserialize;
+
+use crate::{
+    sh,
+    shaders::{self, SH_CS},
+};
+
+/// Directions per axis of the projection of skies
+const SKY_SAMPLES: u32 = 64;
+
+/// Radiance arriving from outside of the radiance volume, which surfaces beyond its last layer
+/// are lit by
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub enum Environment {
+    /// the same from every direction
+    Ambient(Vec3),
+    /// gradient from the horizon to the zenith above it and to the ground below it
+    Sky {
+        zenith: Vec3,
+        horizon: Vec3,
+        ground: Vec3,
+    },
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::Ambient(Vec3::ZERO)
+    }
+}
+
+impl Environment {
+    /// Radiance arriving from `dir`
+    pub fn radiance(&self, dir: Vec3) -> Vec3 {
+        match *self {
+            Self::Ambient(color) => color,
+            Self::Sky {
+                zenith,
+                horizon,
+                ground,
+            } => match dir.z >= 0.0 {
+                true => horizon.lerp(zenith, dir.z),
+                false => horizon.lerp(ground, -dir.z),
+            },
+        }
+    }
+
+    /// Spherical harmonics coefficients of the radiance, in the layout of `EnvironmentBuffer`
+    ///
+    /// Like the radiance volume, they are evaluated in the direction the light travels, so the
+    /// light arriving from `dir` is found at `-dir`.
+    pub fn buffer(&self) -> shaders::EnvironmentBuffer {
+        let coefs: [Vec3; SH_CS as usize] = match *self {
+            Self::Ambient(color) => {
+                let mut coefs = [Vec3::ZERO; SH_CS as usize];
+                coefs[0] = color / sh::NORM_C0;
+                coefs
+            }
+            Self::Sky { .. } => sh::project_environment(SKY_SAMPLES, |dir| self.radiance(-dir)),
+        };
+
+        shaders::EnvironmentBuffer {
+            coefs: std::array::from_fn(|i| coefs[i].extend(0.0).to_array()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIRECTIONS: [Vec3; 6] = [
+        Vec3::X,
+        Vec3::NEG_X,
+        Vec3::Y,
+        Vec3::NEG_Y,
+        Vec3::Z,
+        Vec3::NEG_Z,
+    ];
+
+    /// Radiance arriving at a surface facing `normal`, which `direct.frag` looks up at `-normal`
+    fn incoming(environment: &Environment, normal: Vec3) -> Vec3 {
+        let coefs = environment
+            .buffer()
+            .coefs
+            .map(|coef| Vec4::from(coef).truncate());
+        sh::evaluate_rgb_spherical_harmonics(-normal, &coefs)
+    }
+
+    #[test]
+    fn ambient_is_the_same_everywhere() {
+        let color = Vec3::new(0.2, 0.5, 1.5);
+        let environment = Environment::Ambient(color);
+
+        let diagonal = Vec3::new(1.0, -2.0, 0.5).normalize();
+        for normal in DIRECTIONS.into_iter().chain([diagonal]) {
+            let radiance = incoming(&environment, normal);
+            assert!(
+                radiance.abs_diff_eq(color, 1e-5),
+                "{} != {}",
+                radiance,
+                color
+            );
+        }
+    }
+
+    #[test]
+    fn floors_are_lit_by_the_zenith() {
+        // linear in height, which the L1 coefficients represent exactly
+        let environment = Environment::Sky {
+            zenith: Vec3::new(1.0, 0.8, 0.6),
+            horizon: Vec3::splat(0.5),
+            ground: Vec3::new(0.0, 0.2, 0.4),
+        };
+
+        // by the normals of a floor, a ceiling and walls
+        let expected = [
+            (Vec3::Z, Vec3::new(1.0, 0.8, 0.6)),
+            (Vec3::NEG_Z, Vec3::new(0.0, 0.2, 0.4)),
+            (Vec3::X, Vec3::splat(0.5)),
+            (Vec3::NEG_Y, Vec3::splat(0.5)),
+        ];
+        for (normal, color) in expected {
+            let radiance = incoming(&environment, normal);
+            assert!(
+                radiance.abs_diff_eq(color, 1e-2),
+                "{} != {}",
+                radiance,
+                color
+            );
+        }
+    }
+    #[test]
+    fn floors_are_lit_by_the_sky_above_them() {
+        let zenith = Vec3::new(0.2, 0.4, 1.0);
+        let ground = Vec3::splat(0.1);
+        let environment = Environment::Sky {
+            zenith,
+            horizon: Vec3::new(0.8, 0.9, 1.0),
+            ground,
+        };
+
+        let floor = incoming(&environment, Vec3::Z);
+        let ceiling = incoming(&environment, Vec3::NEG_Z);
+        assert!(floor.distance(zenith) < floor.distance(ground));
+        assert!(ceiling.distance(ground) < ceiling.distance(zenith));
+        assert!(floor.cmpgt(ceiling).all());
+    }
+}
@@ -0,0 +1,212 @@
+use std::{collections::HashMap, path::Path, rc::Rc};
+
+use ::gltf::{buffer, image, mesh::Mode, texture, Mesh, Node};
+use ::image::{DynamicImage, ImageBuffer};
+use glam::*;
+use petgraph::graph::NodeIndex;
+
+use super::{y_up_to_z_up, CpuMaterial, CpuObject, LoadError, MeshInstance, SceneGraph, SceneNode};
+
+/// Material for primitives without one, matches the glTF default material
+const DEFAULT_MATERIAL: CpuMaterial = CpuMaterial::new(Vec3::splat(1.0), Vec3::splat(0.0));
+
+/// Loads the default scene (or the first scene) of a glTF 2.0 file (.gltf or .glb)
+pub fn load(path: &Path) -> Result<(SceneGraph, Vec<CpuMaterial>), LoadError> {
+    let (document, buffers, images) = ::gltf::import(path).map_err(LoadError::Gltf)?;
+
+    let images = images
+        .iter()
+        .map(|data| rgba_image(data).map(Rc::new))
+        .collect::<Vec<_>>();
+    let texture = |info: Option<texture::Info>| {
+        let info = info?;
+        if info.tex_coord() != 0 {
+            eprintln!(
+                "skipping glTF texture using texture coordinate set {}",
+                info.tex_coord()
+            );
+            return None;
+        }
+        images[info.texture().source().index()].clone()
+    };
+
+    let mut importer = Importer {
+        buffers: &buffers,
+        materials: document
+            .materials()
+            .map(|mat| CpuMaterial {
+                reflectance: Vec4::from_array(mat.pbr_metallic_roughness().base_color_factor())
+                    .truncate(),
+                emittance: Vec3::from_array(mat.emissive_factor())
+                    * mat.emissive_strength().unwrap_or(1.0),
+                reflectance_texture: texture(mat.pbr_metallic_roughness().base_color_texture()),
+                emittance_texture: texture(mat.emissive_texture()),
+            })
+            .collect(),
+        default_material: None,
+        meshes: HashMap::new(),
+        graph: SceneGraph::new(),
+    };
+
+    let root = importer
+        .graph
+        .add_node(None, SceneNode::new(y_up_to_z_up(), None));
+
+    if let Some(scene) = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    {
+        for node in scene.nodes() {
+            importer.add_node(&node, root);
+        }
+    }
+
+    Ok((importer.graph, importer.materials))
+}
+
+struct Importer<'a> {
+    buffers: &'a [buffer::Data],
+    materials: Vec<CpuMaterial>,
+    default_material: Option<u32>,
+    /// graph meshes of every glTF mesh, one for each of its primitives
+    meshes: HashMap<usize, Vec<usize>>,
+    graph: SceneGraph,
+}
+
+impl Importer<'_> {
+    /// Adds `node` and its children to the graph as children of `parent`
+    fn add_node(&mut self, node: &Node, parent: NodeIndex) {
+        let transform = Mat4::from_cols_array_2d(&node.transform().matrix());
+        let index = self
+            .graph
+            .add_node(Some(parent), SceneNode::new(transform, None));
+
+        if let Some(mesh) = node.mesh() {
+            // every primitive becomes a child node, as a glTF mesh can have several materials
+            for mesh in self.mesh(&mesh) {
+                self.graph.add_node(
+                    Some(index),
+                    SceneNode::new(
+                        Mat4::IDENTITY,
+                        Some(MeshInstance {
+                            mesh,
+                            material: None,
+                        }),
+                    ),
+                );
+            }
+        }
+
+        for child in node.children() {
+            self.add_node(&child, index);
+        }
+    }
+
+    /// Returns the graph meshes of a glTF mesh, which are only loaded on first use
+    fn mesh(&mut self, mesh: &Mesh) -> Vec<usize> {
+        if let Some(meshes) = self.meshes.get(&mesh.index()) {
+            return meshes.clone();
+        }
+
+        let mut meshes = vec![];
+
+        for primitive in mesh.primitives() {
+            if primitive.mode() != Mode::Triangles {
+                eprintln!(
+                    "skipping glTF primitive with unsupported mode {:?}",
+                    primitive.mode()
+                );
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()]));
+
+            let Some(positions) = reader.read_positions() else {
+                continue;
+            };
+            let vertices = positions.map(Vec3::from_array).collect::<Vec<_>>();
+            let normals = reader
+                .read_normals()
+                .map(|normals| normals.map(Vec3::from_array).collect())
+                .unwrap_or_default();
+            let uvs = reader
+                .read_tex_coords(0)
+                .map(|uvs| uvs.into_f32().map(Vec2::from_array).collect())
+                .unwrap_or_default();
+
+            let indices = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..vertices.len() as u32).collect::<Vec<_>>(),
+            };
+
+            let material = match primitive.material().index() {
+                Some(index) => index as u32,
+                None => *self.default_material.get_or_insert_with(|| {
+                    self.materials.push(DEFAULT_MATERIAL);
+                    self.materials.len() as u32 - 1
+                }),
+            };
+
+            meshes.push(self.graph.add_mesh(CpuObject {
+                vertices,
+                normals,
+                uvs,
+                materials: vec![material; indices.len() / 3],
+                indices,
+            }));
+        }
+
+        self.meshes.insert(mesh.index(), meshes.clone());
+        meshes
+    }
+}
+
+/// Converts a decoded glTF image to 8 bit RGBA
+fn rgba_image(data: &image::Data) -> Option<::image::RgbaImage> {
+    use image::Format;
+
+    let (width, height) = (data.width, data.height);
+    let pixels = data.pixels.clone();
+    let u16s = || {
+        data.pixels
+            .chunks_exact(2)
+            .map(|c| u16::from_ne_bytes([c[0], c[1]]))
+            .collect::<Vec<_>>()
+    };
+    let f32s = || {
+        data.pixels
+            .chunks_exact(4)
+            .map(|c| f32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+            .collect::<Vec<_>>()
+    };
+
+    let image = match data.format {
+        Format::R8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        Format::R8G8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8),
+        Format::R8G8B8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+        Format::R8G8B8A8 => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8)
+        }
+        Format::R16 => ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageLuma16),
+        Format::R16G16 => {
+            ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageLumaA16)
+        }
+        Format::R16G16B16 => {
+            ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageRgb16)
+        }
+        Format::R16G16B16A16 => {
+            ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageRgba16)
+        }
+        Format::R32G32B32FLOAT => {
+            ImageBuffer::from_raw(width, height, f32s()).map(DynamicImage::ImageRgb32F)
+        }
+        Format::R32G32B32A32FLOAT => {
+            ImageBuffer::from_raw(width, height, f32s()).map(DynamicImage::ImageRgba32F)
+        }
+    };
+
+    if image.is_none() {
+        eprintln!("skipping glTF image with too little data for its size");
+    }
+    image.map(|image| image.into_rgba8())
+}
@@ -0,0 +1,145 @@
+use glam::*;
+use petgraph::{
+    graph::{DiGraph, NodeIndex},
+    Direction,
+};
+
+use super::CpuObject;
+
+/// Reference to a mesh of a [SceneGraph]
+#[derive(Clone, Copy, Debug)]
+pub struct MeshInstance {
+    pub mesh: usize,
+    /// overrides the materials of the mesh's triangles
+    pub material: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SceneNode {
+    /// transform relative to the parent node
+    pub transform: Mat4,
+    pub mesh: Option<MeshInstance>,
+}
+
+impl SceneNode {
+    pub fn new(transform: Mat4, mesh: Option<MeshInstance>) -> Self {
+        Self { transform, mesh }
+    }
+}
+
+/// Mesh placed in the world by a [SceneGraph]
+#[derive(Clone, Copy, Debug)]
+pub struct Instance {
+    pub transform: Mat4,
+    pub mesh: usize,
+    pub material: Option<u32>,
+}
+
+/// Hierarchy of nodes where every node has at most one parent
+///
+/// Meshes are stored once in local space and can be referenced by any number of nodes.
+#[derive(Clone, Debug, Default)]
+pub struct SceneGraph {
+    graph: DiGraph<SceneNode, ()>,
+    meshes: Vec<CpuObject>,
+}
+
+impl SceneGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_mesh(&mut self, mesh: CpuObject) -> usize {
+        self.meshes.push(mesh);
+        self.meshes.len() - 1
+    }
+
+    /// Adds a node as a child of `parent`, or as a root if there is no parent
+    pub fn add_node(&mut self, parent: Option<NodeIndex>, node: SceneNode) -> NodeIndex {
+        let index = self.graph.add_node(node);
+        if let Some(parent) = parent {
+            self.graph.add_edge(parent, index, ());
+        }
+        index
+    }
+
+    /// Moves the meshes of `other` into this graph, so it can be attached any number of times
+    /// while sharing them
+    pub fn take_meshes(&mut self, other: &mut SceneGraph) {
+        let offset = self.meshes.len();
+        self.meshes.append(&mut other.meshes);
+        other
+            .graph
+            .node_weights_mut()
+            .filter_map(|node| node.mesh.as_mut())
+            .for_each(|instance| instance.mesh += offset);
+    }
+
+    /// Adds all nodes of `other`, its roots become children of `parent`
+    ///
+    /// The meshes of `other` must have been moved to this graph with [Self::take_meshes].
+    /// If `material` is given, it overrides the material of every attached instance.
+    pub fn attach(&mut self, parent: Option<NodeIndex>, other: &SceneGraph, material: Option<u32>) {
+        debug_assert!(other.meshes.is_empty());
+
+        let offset = self.graph.node_count();
+        for node in other.graph.node_weights() {
+            let mut node = node.clone();
+            if let Some(instance) = node.mesh.as_mut() {
+                instance.material = material.or(instance.material);
+            }
+            self.graph.add_node(node);
+        }
+        for edge in other.graph.raw_edges() {
+            self.graph.add_edge(
+                NodeIndex::new(edge.source().index() + offset),
+                NodeIndex::new(edge.target().index() + offset),
+                (),
+            );
+        }
+        if let Some(parent) = parent {
+            for root in other.roots() {
+                self.graph
+                    .add_edge(parent, NodeIndex::new(root.index() + offset), ());
+            }
+        }
+    }
+
+    pub fn meshes_mut(&mut self) -> impl Iterator<Item = &mut CpuObject> {
+        self.meshes.iter_mut()
+    }
+
+    fn roots(&self) -> impl Iterator<Item = NodeIndex> + '_ {
+        self.graph.externals(Direction::Incoming)
+    }
+
+    /// Returns the meshes and every placement of them with world transforms
+    pub fn flatten(self) -> (Vec<CpuObject>, Vec<Instance>) {
+        let mut instances = vec![];
+        let mut stack = self
+            .roots()
+            .map(|root| (root, Mat4::IDENTITY))
+            .collect::<Vec<_>>();
+
+        while let Some((index, parent_transform)) = stack.pop() {
+            let node = &self.graph[index];
+            let transform = parent_transform * node.transform;
+
+            if let Some(MeshInstance { mesh, material }) = node.mesh {
+                instances.push(Instance {
+                    transform,
+                    mesh,
+                    material,
+                });
+            }
+
+            stack.extend(
+                self.graph
+                    .neighbors_directed(index, Direction::Outgoing)
+                    .map(|child| (child, transform)),
+            );
+        }
+
+        (self.meshes, instances)
+    }
+}
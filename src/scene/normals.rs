@@ -0,0 +1,162 @@
+use glam::*;
+
+use super::CpuObject;
+
+impl CpuObject {
+    /// Generates vertex normals for a mesh without them
+    ///
+    /// The triangles around a vertex are grouped across their shared edges, unless the angle
+    /// between them is larger than `smoothing_angle` degrees. Every group gets its own copy of
+    /// the vertex with the area weighted average normal of its triangles.
+    pub(super) fn generate_normals(&mut self, smoothing_angle: f32) {
+        let min_cos = smoothing_angle.to_radians().cos();
+
+        // not normalized, so larger triangles have a larger influence
+        let face_normals = self
+            .indices
+            .chunks_exact(3)
+            .map(|tri| {
+                let [a, b, c] = [tri[0], tri[1], tri[2]].map(|i| self.vertices[i as usize]);
+                (b - a).cross(c - a)
+            })
+            .collect::<Vec<_>>();
+
+        let mut vertex_corners = vec![vec![]; self.vertices.len()];
+        for (corner, &index) in self.indices.iter().enumerate() {
+            vertex_corners[index as usize].push(corner);
+        }
+
+        let mut vertices = vec![];
+        let mut normals = vec![];
+        let mut uvs = vec![];
+        let mut indices = self.indices.clone();
+
+        for (vertex, corners) in vertex_corners.iter().enumerate() {
+            let mut parents = (0..corners.len()).collect::<Vec<_>>();
+
+            for a in 0..corners.len() {
+                for b in a + 1..corners.len() {
+                    let (face_a, face_b) = (corners[a] / 3, corners[b] / 3);
+                    let smooth = face_normals[face_a]
+                        .normalize_or_zero()
+                        .dot(face_normals[face_b].normalize_or_zero())
+                        >= min_cos;
+
+                    if smooth && self.share_edge(face_a, face_b, vertex as u32) {
+                        let (root_a, root_b) = (find(&mut parents, a), find(&mut parents, b));
+                        parents[root_a] = root_b;
+                    }
+                }
+            }
+
+            // new vertex of every group, indexed by its root
+            let mut group_vertices = vec![u32::MAX; corners.len()];
+            for (i, &corner) in corners.iter().enumerate() {
+                let root = find(&mut parents, i);
+                if group_vertices[root] == u32::MAX {
+                    group_vertices[root] = vertices.len() as u32;
+                    vertices.push(self.vertices[vertex]);
+                    normals.push(Vec3::ZERO);
+                    if !self.uvs.is_empty() {
+                        uvs.push(self.uvs[vertex]);
+                    }
+                }
+                normals[group_vertices[root] as usize] += face_normals[corner / 3];
+                indices[corner] = group_vertices[root];
+            }
+        }
+
+        self.vertices = vertices;
+        self.uvs = uvs;
+        self.indices = indices;
+        self.normals = normals.into_iter().map(Vec3::normalize_or_zero).collect();
+    }
+
+    /// Whether two triangles share an edge that goes through `vertex`
+    fn share_edge(&self, a: usize, b: usize, vertex: u32) -> bool {
+        let tri_a = &self.indices[3 * a..3 * a + 3];
+        let tri_b = &self.indices[3 * b..3 * b + 3];
+        tri_a
+            .iter()
+            .filter(|&&i| i != vertex)
+            .any(|i| tri_b.contains(i))
+    }
+}
+
+/// Returns the root of a union-find set
+fn find(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Normal of every corner of every triangle
+    fn corner_normals(object: &CpuObject) -> Vec<Vec3> {
+        object
+            .indices
+            .iter()
+            .map(|&i| object.normals[i as usize])
+            .collect()
+    }
+
+    #[test]
+    fn sharp_edges_are_split() {
+        let mut cube = CpuObject::cube(Vec3::ZERO, 1.0, 0);
+        cube.uvs = vec![Vec2::ONE; 8];
+        cube.generate_normals(30.0);
+
+        // every corner of the cube is in three faces
+        assert_eq!(cube.vertices.len(), 24);
+        assert_eq!(cube.normals.len(), 24);
+        assert_eq!(cube.uvs, vec![Vec2::ONE; 24]);
+        assert_eq!(cube.indices.len(), 36);
+
+        for (tri, normals) in cube
+            .indices
+            .chunks_exact(3)
+            .zip(corner_normals(&cube).chunks_exact(3))
+        {
+            let [a, b, c] = [tri[0], tri[1], tri[2]].map(|i| cube.vertices[i as usize]);
+            let face_normal = (b - a).cross(c - a).normalize();
+            // outward, the cube is centred on the origin
+            assert!(face_normal.dot(a + b + c) > 0.0);
+            for normal in normals {
+                assert!(normal.abs_diff_eq(face_normal, 1e-6));
+            }
+        }
+    }
+
+    #[test]
+    fn smooth_edges_share_vertices() {
+        let mut cube = CpuObject::cube(Vec3::ZERO, 1.0, 0);
+        cube.generate_normals(100.0);
+
+        assert_eq!(cube.vertices.len(), 8);
+        for (vertex, normal) in cube.vertices.iter().zip(&cube.normals) {
+            assert!((normal.length() - 1.0).abs() < 1e-6);
+            // points away from the corner's faces
+            assert!((*vertex * *normal).min_element() > 0.0);
+        }
+    }
+
+    #[test]
+    fn flat_surfaces_keep_their_vertices() {
+        let mut quad = CpuObject {
+            vertices: vec![Vec3::ZERO, Vec3::X, Vec3::new(1.0, 1.0, 0.0), Vec3::Y],
+            normals: vec![],
+            uvs: vec![],
+            indices: vec![0, 1, 2, 2, 3, 0],
+            materials: vec![0; 2],
+        };
+        quad.generate_normals(0.0);
+
+        assert_eq!(quad.vertices.len(), 4);
+        assert_eq!(quad.normals, vec![Vec3::Z; 4]);
+    }
+}
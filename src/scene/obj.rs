@@ -0,0 +1,112 @@
+use std::{collections::HashMap, path::Path};
+
+use glam::*;
+
+use super::{
+    load_texture, y_up_to_z_up, CpuMaterial, CpuObject, LoadError, MeshInstance, SceneGraph,
+    SceneNode, Texture,
+};
+
+/// Material for meshes that don't reference one in the MTL file
+const DEFAULT_MATERIAL: CpuMaterial = CpuMaterial::new(Vec3::splat(0.8), Vec3::splat(0.0));
+
+/// Loads a Wavefront OBJ file and the MTL files it references
+pub fn load(path: &Path) -> Result<(SceneGraph, Vec<CpuMaterial>), LoadError> {
+    let (models, materials) = tobj::load_obj(
+        path,
+        &tobj::LoadOptions {
+            triangulate: true,
+            single_index: true,
+            ..Default::default()
+        },
+    )
+    .map_err(LoadError::Obj)?;
+
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let mut textures = HashMap::<String, Option<Texture>>::new();
+
+    // a missing or broken MTL or texture file should not prevent the geometry from loading
+    let mut texture = |name: Option<&String>| {
+        let name = name?;
+        textures
+            .entry(name.clone())
+            .or_insert_with(|| {
+                load_texture(&directory.join(name))
+                    .map_err(|err| eprintln!("{}", err))
+                    .ok()
+            })
+            .clone()
+    };
+
+    let mut materials = materials
+        .unwrap_or_else(|err| {
+            eprintln!("failed to load MTL for {}: {}", path.display(), err);
+            vec![]
+        })
+        .into_iter()
+        .map(|mat| {
+            let reflectance_texture = texture(mat.diffuse_texture.as_ref());
+            // the texture holds the color if there is no separate one
+            let default_diffuse = if reflectance_texture.is_some() {
+                1.0
+            } else {
+                0.8
+            };
+
+            CpuMaterial {
+                reflectance: Vec3::from_array(mat.diffuse.unwrap_or([default_diffuse; 3])),
+                emittance: Vec3::from_array(mat.emissive.unwrap_or([0.0; 3])),
+                reflectance_texture,
+                emittance_texture: texture(mat.unknown_param.get("map_Ke")),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut default_material = None;
+
+    let mut graph = SceneGraph::new();
+    let root = graph.add_node(None, SceneNode::new(y_up_to_z_up(), None));
+
+    for model in models {
+        let mesh = model.mesh;
+
+        let material = match mesh.material_id.filter(|&id| id < materials.len()) {
+            Some(id) => id as u32,
+            None => *default_material.get_or_insert_with(|| {
+                materials.push(DEFAULT_MATERIAL);
+                materials.len() as u32 - 1
+            }),
+        };
+
+        let object = CpuObject {
+            vertices: mesh
+                .positions
+                .chunks_exact(3)
+                .map(Vec3::from_slice)
+                .collect(),
+            normals: mesh.normals.chunks_exact(3).map(Vec3::from_slice).collect(),
+            // OBJ has the origin of textures in the bottom left corner, Vulkan in the top left
+            uvs: mesh
+                .texcoords
+                .chunks_exact(2)
+                .map(|uv| Vec2::new(uv[0], 1.0 - uv[1]))
+                .collect(),
+            materials: vec![material; mesh.indices.len() / 3],
+            indices: mesh.indices,
+        };
+
+        let mesh = graph.add_mesh(object);
+        graph.add_node(
+            Some(root),
+            SceneNode::new(
+                Mat4::IDENTITY,
+                Some(MeshInstance {
+                    mesh,
+                    material: None,
+                }),
+            ),
+        );
+    }
+
+    Ok((graph, materials))
+}
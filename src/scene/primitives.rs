@@ -0,0 +1,352 @@
+use std::f32::consts::{FRAC_PI_2, PI, TAU};
+
+use glam::*;
+
+use super::CpuObject;
+
+/// Procedural meshes, tessellated around their local Z axis
+///
+/// `segments` is the number of subdivisions around the axis, the other directions are
+/// subdivided proportionally. Every primitive is rotated by `rotation` and then moved to
+/// `position`.
+impl CpuObject {
+    pub(super) fn sphere(
+        position: Vec3,
+        rotation: Quat,
+        radius: f32,
+        segments: u32,
+        material: u32,
+    ) -> Self {
+        let rings = (segments / 2).max(2);
+        let profile = (0..=rings)
+            .map(|i| {
+                let angle = i as f32 / rings as f32 * PI - FRAC_PI_2;
+                radius * Vec2::new(angle.cos(), angle.sin())
+            })
+            .collect::<Vec<_>>();
+
+        Self::revolution(position, rotation, &profile, segments, material)
+    }
+
+    /// Closed cylinder with its caps at -`half_height` and `half_height`
+    pub(super) fn cylinder(
+        position: Vec3,
+        rotation: Quat,
+        radius: f32,
+        half_height: f32,
+        segments: u32,
+        material: u32,
+    ) -> Self {
+        let profile = [
+            Vec2::new(0.0, -half_height),
+            Vec2::new(radius, -half_height),
+            Vec2::new(radius, half_height),
+            Vec2::new(0.0, half_height),
+        ];
+
+        Self::revolution(position, rotation, &profile, segments, material)
+    }
+
+    /// Closed cone with its base at -`half_height` and its tip at `half_height`
+    pub(super) fn cone(
+        position: Vec3,
+        rotation: Quat,
+        radius: f32,
+        half_height: f32,
+        segments: u32,
+        material: u32,
+    ) -> Self {
+        let profile = [
+            Vec2::new(0.0, -half_height),
+            Vec2::new(radius, -half_height),
+            Vec2::new(0.0, half_height),
+        ];
+
+        Self::revolution(position, rotation, &profile, segments, material)
+    }
+
+    /// Torus around the Z axis, `minor_radius` is the radius of the tube
+    pub(super) fn torus(
+        position: Vec3,
+        rotation: Quat,
+        major_radius: f32,
+        minor_radius: f32,
+        segments: u32,
+        material: u32,
+    ) -> Self {
+        let tube_segments = (segments / 2).max(3);
+        let profile = (0..=tube_segments)
+            .map(|i| {
+                let angle = i as f32 / tube_segments as f32 * TAU - PI;
+                Vec2::new(major_radius, 0.0) + minor_radius * Vec2::new(angle.cos(), angle.sin())
+            })
+            .collect::<Vec<_>>();
+
+        Self::revolution(position, rotation, &profile, segments, material)
+    }
+
+    /// Cylinder of `half_height` with hemispheres of `radius` on both ends
+    pub(super) fn capsule(
+        position: Vec3,
+        rotation: Quat,
+        radius: f32,
+        half_height: f32,
+        segments: u32,
+        material: u32,
+    ) -> Self {
+        let rings = (segments / 4).max(1);
+        let hemisphere = |offset: f32, start: f32| {
+            (0..=rings).map(move |i| {
+                let angle = start + i as f32 / rings as f32 * FRAC_PI_2;
+                Vec2::new(0.0, offset) + radius * Vec2::new(angle.cos(), angle.sin())
+            })
+        };
+        let profile = hemisphere(-half_height, -FRAC_PI_2)
+            .chain(hemisphere(half_height, 0.0))
+            .collect::<Vec<_>>();
+
+        Self::revolution(position, rotation, &profile, segments, material)
+    }
+
+    /// Single sided plane facing +Z, split into `subdivisions` squared quads
+    pub(super) fn plane(
+        position: Vec3,
+        rotation: Quat,
+        half_extents: Vec2,
+        subdivisions: u32,
+        material: u32,
+    ) -> Self {
+        let n = subdivisions.max(1);
+
+        let grid = (0..=n)
+            .flat_map(|y| (0..=n).map(move |x| UVec2::new(x, y).as_vec2() / n as f32))
+            .collect::<Vec<_>>();
+        let vertices = grid
+            .iter()
+            .map(|&xy| position + rotation * ((2.0 * xy - 1.0) * half_extents).extend(0.0))
+            .collect();
+        let uvs = grid.iter().map(|xy| Vec2::new(xy.x, 1.0 - xy.y)).collect();
+
+        let vertex = |x: u32, y: u32| y * (n + 1) + x;
+        let indices = (0..n)
+            .flat_map(|y| (0..n).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                [
+                    vertex(x, y),
+                    vertex(x + 1, y),
+                    vertex(x + 1, y + 1),
+                    vertex(x + 1, y + 1),
+                    vertex(x, y + 1),
+                    vertex(x, y),
+                ]
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            vertices,
+            normals: vec![],
+            uvs,
+            materials: vec![material; indices.len() / 3],
+            indices,
+        }
+    }
+
+    /// Rotates a profile of (radius, height) points around the Z axis
+    ///
+    /// The profile goes from bottom to top for the triangles to face outwards. Points with a
+    /// radius of (nearly) zero lie on the axis and only get the triangles that are not
+    /// degenerate. Textures wrap around the axis once, from the top to the bottom of the
+    /// profile.
+    fn revolution(
+        position: Vec3,
+        rotation: Quat,
+        profile: &[Vec2],
+        segments: u32,
+        material: u32,
+    ) -> Self {
+        let segments = segments.max(3);
+
+        // the first column is repeated at the end, so the seam has its own vertices
+        let vertices = profile
+            .iter()
+            .flat_map(|p| {
+                (0..=segments).map(move |j| {
+                    let angle = j as f32 / segments as f32 * TAU;
+                    Vec3::new(p.x * angle.cos(), p.x * angle.sin(), p.y)
+                })
+            })
+            .map(|v| position + rotation * v)
+            .collect();
+        let uvs = (0..profile.len())
+            .flat_map(|i| {
+                let v = 1.0 - i as f32 / (profile.len() - 1) as f32;
+                (0..=segments).map(move |j| Vec2::new(j as f32 / segments as f32, v))
+            })
+            .collect();
+
+        // the poles of spheres are off by the rounding error of the cosine
+        let max_radius = profile.iter().fold(0.0f32, |acc, p| acc.max(p.x.abs()));
+        let on_axis = |p: Vec2| p.x.abs() <= 1e-6 * max_radius;
+
+        let vertex = |i: usize, j: u32| i as u32 * (segments + 1) + j;
+        let mut indices = vec![];
+
+        for (i, rings) in profile.windows(2).enumerate() {
+            for j in 0..segments {
+                if !on_axis(rings[0]) {
+                    indices.extend([vertex(i, j), vertex(i, j + 1), vertex(i + 1, j + 1)]);
+                }
+                if !on_axis(rings[1]) {
+                    indices.extend([vertex(i + 1, j + 1), vertex(i + 1, j), vertex(i, j)]);
+                }
+            }
+        }
+
+        Self {
+            vertices,
+            normals: vec![],
+            uvs,
+            materials: vec![material; indices.len() / 3],
+            indices,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const POSITION: Vec3 = Vec3::new(1.0, -2.0, 3.0);
+    const SEGMENTS: u32 = 16;
+
+    fn rotation() -> Quat {
+        Quat::from_euler(EulerRot::XYZ, 0.3, -0.7, 1.1)
+    }
+
+    /// Edges that only one triangle uses, by the positions of their vertices as the seams and
+    /// poles have their own vertices
+    fn boundary_edges(mesh: &CpuObject) -> usize {
+        let key = |i: u32| {
+            (mesh.vertices[i as usize] * 1e3)
+                .round()
+                .to_array()
+                .map(|c| c as i64)
+        };
+
+        let mut edges = HashMap::new();
+        for tri in mesh.indices.chunks_exact(3) {
+            for (a, b) in [(0, 1), (1, 2), (2, 0)] {
+                *edges.entry((key(tri[a]), key(tri[b]))).or_insert(0) += 1;
+            }
+        }
+
+        // manifold, with consistently oriented triangles
+        assert!(edges.values().all(|&count| count == 1));
+        edges
+            .keys()
+            .filter(|(a, b)| !edges.contains_key(&(*b, *a)))
+            .count()
+    }
+
+    /// Checks the primitive made by `build` at `POSITION` with `rotation`, `outward` points away
+    /// from the surface at a point in local space
+    fn check(
+        build: impl Fn(Vec3, Quat) -> CpuObject,
+        triangles: usize,
+        boundary: usize,
+        outward: impl Fn(Vec3) -> Vec3,
+    ) {
+        let local = build(Vec3::ZERO, Quat::IDENTITY);
+        let mut mesh = build(POSITION, rotation());
+
+        assert_eq!(mesh.indices.len(), 3 * triangles);
+        assert_eq!(mesh.materials, vec![7; triangles]);
+        assert!(mesh.uvs.is_empty() || mesh.uvs.len() == mesh.vertices.len());
+        assert!(mesh
+            .indices
+            .iter()
+            .all(|&i| (i as usize) < mesh.vertices.len()));
+        assert_eq!(boundary_edges(&mesh), boundary);
+
+        // rotated around the position
+        for (vertex, local) in mesh.vertices.iter().zip(&local.vertices) {
+            assert!(vertex.abs_diff_eq(POSITION + rotation() * *local, 1e-4));
+        }
+
+        mesh.generate_normals(30.0);
+        for &i in &mesh.indices {
+            let normal = mesh.normals[i as usize];
+            assert!((normal.length() - 1.0).abs() < 1e-5);
+
+            let local = rotation().inverse() * (mesh.vertices[i as usize] - POSITION);
+            assert!((rotation().inverse() * normal).dot(outward(local)) > 0.0);
+        }
+    }
+
+    #[test]
+    fn spheres_are_closed() {
+        let rings = SEGMENTS as usize / 2;
+        check(
+            |position, rotation| CpuObject::sphere(position, rotation, 2.0, SEGMENTS, 7),
+            (2 * rings - 2) * SEGMENTS as usize,
+            0,
+            |v| v,
+        );
+    }
+
+    #[test]
+    fn cylinders_are_closed() {
+        check(
+            |position, rotation| CpuObject::cylinder(position, rotation, 2.0, 3.0, SEGMENTS, 7),
+            4 * SEGMENTS as usize,
+            0,
+            |v| v,
+        );
+    }
+
+    #[test]
+    fn cones_are_closed() {
+        check(
+            |position, rotation| CpuObject::cone(position, rotation, 2.0, 3.0, SEGMENTS, 7),
+            2 * SEGMENTS as usize,
+            0,
+            |v| v,
+        );
+    }
+
+    #[test]
+    fn tori_are_closed() {
+        let tube_segments = SEGMENTS as usize / 2;
+        check(
+            |position, rotation| CpuObject::torus(position, rotation, 4.0, 1.0, SEGMENTS, 7),
+            2 * tube_segments * SEGMENTS as usize,
+            0,
+            // away from the circle through the centre of the tube
+            |v| v - 4.0 * v.truncate().normalize().extend(0.0),
+        );
+    }
+
+    #[test]
+    fn capsules_are_closed() {
+        let rings = SEGMENTS as usize / 4;
+        check(
+            |position, rotation| CpuObject::capsule(position, rotation, 1.0, 2.0, SEGMENTS, 7),
+            4 * rings * SEGMENTS as usize,
+            0,
+            |v| v,
+        );
+    }
+
+    #[test]
+    fn planes_face_up() {
+        let half_extents = Vec2::new(2.0, 3.0);
+        check(
+            |position, rotation| CpuObject::plane(position, rotation, half_extents, 3, 7),
+            2 * 3 * 3,
+            4 * 3,
+            |_| Vec3::Z,
+        );
+    }
+}
@@ -0,0 +1,528 @@
+use std::fmt;
+
+use glam::*;
+
+use crate::shaders::{LM_LAYERS, RADIANCE_SIZE, RADIANCE_UNIT};
+
+use super::{graph::Instance, CpuMaterial, CpuObject};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// the scene loads, but may look wrong
+    Warning,
+    /// the scene can't be uploaded to the GPU
+    Error,
+}
+
+/// Problem found in a loaded scene, meshes and materials are referred to by index
+#[derive(Clone, Debug)]
+pub enum Issue {
+    IndexCount {
+        mesh: usize,
+        count: usize,
+    },
+    MaterialCount {
+        mesh: usize,
+        triangles: usize,
+        materials: usize,
+    },
+    NormalCount {
+        mesh: usize,
+        normals: usize,
+        vertices: usize,
+    },
+    UvCount {
+        mesh: usize,
+        uvs: usize,
+        vertices: usize,
+    },
+    VertexIndexOutOfRange {
+        mesh: usize,
+        index: u32,
+        vertices: usize,
+    },
+    MaterialIndexOutOfRange {
+        mesh: usize,
+        index: u32,
+    },
+    InstanceMaterialOutOfRange {
+        instance: usize,
+        index: u32,
+    },
+    NonFiniteVertices {
+        mesh: usize,
+        count: usize,
+    },
+    DegenerateTriangles {
+        mesh: usize,
+        count: usize,
+        /// first degenerate triangle
+        triangle: usize,
+    },
+    /// materials reflecting at least as much light as they receive add energy to the scene
+    Reflectance {
+        material: usize,
+        reflectance: Vec3,
+    },
+    /// the instance never fits in the radiance volume, wherever it is centred, and the parts
+    /// outside of it don't contribute to lighting
+    LargerThanRadianceVolume {
+        instance: usize,
+        size: Vec3,
+    },
+    /// no instance has any triangles, which leaves the GPU buffers empty
+    EmptyScene,
+}
+
+impl Issue {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Self::IndexCount { .. }
+            | Self::MaterialCount { .. }
+            | Self::NormalCount { .. }
+            | Self::UvCount { .. }
+            | Self::VertexIndexOutOfRange { .. }
+            | Self::MaterialIndexOutOfRange { .. }
+            | Self::InstanceMaterialOutOfRange { .. }
+            | Self::NonFiniteVertices { .. }
+            | Self::EmptyScene => Severity::Error,
+            Self::DegenerateTriangles { .. }
+            | Self::Reflectance { .. }
+            | Self::LargerThanRadianceVolume { .. } => Severity::Warning,
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IndexCount { mesh, count } => write!(
+                f,
+                "mesh {} has {} indices, which is not a multiple of 3",
+                mesh, count
+            ),
+            Self::MaterialCount {
+                mesh,
+                triangles,
+                materials,
+            } => write!(
+                f,
+                "mesh {} has {} triangles but {} triangle materials",
+                mesh, triangles, materials
+            ),
+            Self::NormalCount {
+                mesh,
+                normals,
+                vertices,
+            } => write!(
+                f,
+                "mesh {} has {} normals for {} vertices",
+                mesh, normals, vertices
+            ),
+            Self::UvCount {
+                mesh,
+                uvs,
+                vertices,
+            } => write!(
+                f,
+                "mesh {} has {} texture coordinates for {} vertices",
+                mesh, uvs, vertices
+            ),
+            Self::VertexIndexOutOfRange {
+                mesh,
+                index,
+                vertices,
+            } => write!(
+                f,
+                "mesh {} references vertex {} but only has {} vertices",
+                mesh, index, vertices
+            ),
+            Self::MaterialIndexOutOfRange { mesh, index } => {
+                write!(f, "mesh {} references unknown material {}", mesh, index)
+            }
+            Self::InstanceMaterialOutOfRange { instance, index } => {
+                write!(f, "instance {} uses unknown material {}", instance, index)
+            }
+            Self::NonFiniteVertices { mesh, count } => write!(
+                f,
+                "mesh {} has {} vertex attributes with NaN or infinite values",
+                mesh, count
+            ),
+            Self::DegenerateTriangles {
+                mesh,
+                count,
+                triangle,
+            } => write!(
+                f,
+                "mesh {} has {} zero-area triangles (the first is triangle {}), they have no normal",
+                mesh, count, triangle
+            ),
+            Self::Reflectance {
+                material,
+                reflectance,
+            } => write!(
+                f,
+                "material {} has a reflectance of {}, values of 1 or more amplify light",
+                material, reflectance
+            ),
+            Self::LargerThanRadianceVolume { instance, size } => write!(
+                f,
+                "instance {} has a size of {}, which is larger than the radiance volume",
+                instance, size
+            ),
+            Self::EmptyScene => write!(f, "the scene has no triangles"),
+        }
+    }
+}
+
+/// Issues found by [validate]
+#[derive(Clone, Debug, Default)]
+pub struct ValidationReport {
+    pub issues: Vec<Issue>,
+}
+
+impl ValidationReport {
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Issue> {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity() == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Issue> {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity() == Severity::Warning)
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, issue) in self.issues.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let severity = match issue.severity() {
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            };
+            write!(f, "{}: {}", severity, issue)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks a flattened scene for data that would break rendering
+pub(super) fn validate(
+    meshes: &[CpuObject],
+    instances: &[Instance],
+    materials: &[CpuMaterial],
+) -> ValidationReport {
+    let mut issues = vec![];
+
+    let triangles = instances
+        .iter()
+        .filter_map(|instance| meshes.get(instance.mesh))
+        .map(|object| object.indices.len() / 3)
+        .sum::<usize>();
+    if triangles == 0 {
+        issues.push(Issue::EmptyScene);
+    }
+
+    for (mesh, object) in meshes.iter().enumerate() {
+        validate_mesh(mesh, object, materials.len(), &mut issues);
+    }
+
+    for (index, material) in materials.iter().enumerate() {
+        if material.reflectance.max_element() >= 1.0 {
+            issues.push(Issue::Reflectance {
+                material: index,
+                reflectance: material.reflectance,
+            });
+        }
+    }
+
+    // width of the coarsest layer of the radiance volume, which follows the camera
+    let volume_size = RADIANCE_SIZE as f32 * (1 << (LM_LAYERS - 1)) as f32 * RADIANCE_UNIT;
+
+    for (index, instance) in instances.iter().enumerate() {
+        if let Some(material) = instance
+            .material
+            .filter(|&mat| mat as usize >= materials.len())
+        {
+            issues.push(Issue::InstanceMaterialOutOfRange {
+                instance: index,
+                index: material,
+            });
+        }
+
+        let Some(object) = meshes.get(instance.mesh) else {
+            continue;
+        };
+        if object.vertices.is_empty() {
+            continue;
+        }
+        let (min, max) = super::transform_bounds(instance.transform, object.bounds());
+        let size = max - min;
+        if size.max_element() > volume_size {
+            issues.push(Issue::LargerThanRadianceVolume {
+                instance: index,
+                size,
+            });
+        }
+    }
+
+    ValidationReport { issues }
+}
+
+fn validate_mesh(mesh: usize, object: &CpuObject, material_count: usize, issues: &mut Vec<Issue>) {
+    if object.indices.len() % 3 != 0 {
+        issues.push(Issue::IndexCount {
+            mesh,
+            count: object.indices.len(),
+        });
+    }
+
+    let triangles = object.indices.len() / 3;
+    if object.materials.len() != triangles {
+        issues.push(Issue::MaterialCount {
+            mesh,
+            triangles,
+            materials: object.materials.len(),
+        });
+    }
+
+    if !object.normals.is_empty() && object.normals.len() != object.vertices.len() {
+        issues.push(Issue::NormalCount {
+            mesh,
+            normals: object.normals.len(),
+            vertices: object.vertices.len(),
+        });
+    }
+
+    if !object.uvs.is_empty() && object.uvs.len() != object.vertices.len() {
+        issues.push(Issue::UvCount {
+            mesh,
+            uvs: object.uvs.len(),
+            vertices: object.vertices.len(),
+        });
+    }
+
+    if let Some(&index) = object
+        .indices
+        .iter()
+        .find(|&&i| i as usize >= object.vertices.len())
+    {
+        issues.push(Issue::VertexIndexOutOfRange {
+            mesh,
+            index,
+            vertices: object.vertices.len(),
+        });
+        // the triangles can't be checked
+        return;
+    }
+
+    if let Some(&index) = object
+        .materials
+        .iter()
+        .find(|&&mat| mat as usize >= material_count)
+    {
+        issues.push(Issue::MaterialIndexOutOfRange { mesh, index });
+    }
+
+    let non_finite = object
+        .vertices
+        .iter()
+        .chain(&object.normals)
+        .filter(|v| !v.is_finite())
+        .count()
+        + object.uvs.iter().filter(|uv| !uv.is_finite()).count();
+    if non_finite > 0 {
+        issues.push(Issue::NonFiniteVertices {
+            mesh,
+            count: non_finite,
+        });
+        return;
+    }
+
+    let mut degenerate = object
+        .indices
+        .chunks_exact(3)
+        .map(|tri| [tri[0], tri[1], tri[2]].map(|i| object.vertices[i as usize]))
+        .enumerate()
+        .filter(|(_, [a, b, c])| is_degenerate(*a, *b, *c))
+        .map(|(triangle, _)| triangle);
+
+    if let Some(triangle) = degenerate.next() {
+        issues.push(Issue::DegenerateTriangles {
+            mesh,
+            count: degenerate.count() + 1,
+            triangle,
+        });
+    }
+}
+
+/// Whether the triangle's area is zero up to rounding errors, which includes collinear and
+/// repeated vertices
+pub(super) fn is_degenerate(a: Vec3, b: Vec3, c: Vec3) -> bool {
+    let longest_edge = (b - a)
+        .length_squared()
+        .max((c - b).length_squared())
+        .max((a - c).length_squared());
+    (b - a).cross(c - a).length() <= f32::EPSILON * longest_edge
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::{self, LoadError, MeshCleanup};
+
+    fn triangle() -> CpuObject {
+        CpuObject {
+            vertices: vec![Vec3::ZERO, Vec3::X, Vec3::Y],
+            normals: vec![Vec3::Z; 3],
+            uvs: vec![],
+            indices: vec![0, 1, 2],
+            materials: vec![0],
+        }
+    }
+
+    fn gray() -> CpuMaterial {
+        CpuMaterial::new(Vec3::splat(0.5), Vec3::ZERO)
+    }
+
+    /// Issues of a scene with a single instance of `object`
+    fn issues_of(object: CpuObject, material: CpuMaterial) -> Vec<Issue> {
+        let instance = Instance {
+            transform: Mat4::IDENTITY,
+            mesh: 0,
+            material: None,
+        };
+        validate(&[object], &[instance], &[material]).issues
+    }
+
+    #[test]
+    fn valid_meshes_have_no_issues() {
+        assert!(issues_of(triangle(), gray()).is_empty());
+    }
+
+    #[test]
+    fn empty_scenes_are_errors() {
+        let issues = validate(&[], &[], &[gray()]).issues;
+        assert!(matches!(issues[..], [Issue::EmptyScene]));
+        assert_eq!(issues[0].severity(), Severity::Error);
+
+        let path = std::env::temp_dir().join("bound_engine_empty_scene.ron");
+        std::fs::write(&path, "(materials: {}, objects: [])").unwrap();
+        let result = scene::load(&path, &MeshCleanup::default());
+        assert!(matches!(
+            result,
+            Err(LoadError::Validation(report))
+                if matches!(report.issues[..], [Issue::EmptyScene])
+        ));
+    }
+
+    #[test]
+    fn out_of_range_indices_are_errors() {
+        let mut object = triangle();
+        object.indices[2] = 5;
+        let issues = issues_of(object, gray());
+
+        assert!(matches!(
+            issues[..],
+            [Issue::VertexIndexOutOfRange {
+                mesh: 0,
+                index: 5,
+                vertices: 3
+            }]
+        ));
+        assert_eq!(issues[0].severity(), Severity::Error);
+
+        let mut object = triangle();
+        object.materials[0] = 1;
+        let issues = issues_of(object, gray());
+        assert!(matches!(
+            issues[..],
+            [Issue::MaterialIndexOutOfRange { mesh: 0, index: 1 }]
+        ));
+        assert_eq!(issues[0].severity(), Severity::Error);
+    }
+
+    #[test]
+    fn index_counts_have_to_be_multiples_of_3() {
+        let mut object = triangle();
+        object.indices.push(0);
+        let issues = issues_of(object, gray());
+
+        assert!(matches!(
+            issues[..],
+            [Issue::IndexCount { mesh: 0, count: 4 }]
+        ));
+        assert_eq!(issues[0].severity(), Severity::Error);
+    }
+
+    #[test]
+    fn degenerate_triangles_are_warnings() {
+        let mut object = triangle();
+        object.vertices.push(2.0 * Vec3::X);
+        object.normals.push(Vec3::Z);
+        object.indices.extend([0, 1, 3]);
+        object.materials.push(0);
+        let issues = issues_of(object, gray());
+
+        assert!(matches!(
+            issues[..],
+            [Issue::DegenerateTriangles {
+                mesh: 0,
+                count: 1,
+                triangle: 1
+            }]
+        ));
+        assert_eq!(issues[0].severity(), Severity::Warning);
+    }
+
+    #[test]
+    fn non_finite_vertices_are_errors() {
+        let mut object = triangle();
+        object.vertices[1].x = f32::NAN;
+        object.normals[2].z = f32::INFINITY;
+        let issues = issues_of(object, gray());
+
+        assert!(matches!(
+            issues[..],
+            [Issue::NonFiniteVertices { mesh: 0, count: 2 }]
+        ));
+        assert_eq!(issues[0].severity(), Severity::Error);
+    }
+
+    #[test]
+    fn reflectances_of_1_or_more_are_warnings() {
+        let material = CpuMaterial::new(Vec3::new(0.5, 1.0, 0.5), Vec3::ZERO);
+        let issues = issues_of(triangle(), material);
+
+        assert!(matches!(
+            issues[..],
+            [Issue::Reflectance { material: 0, .. }]
+        ));
+        assert_eq!(issues[0].severity(), Severity::Warning);
+    }
+
+    #[test]
+    fn instances_larger_than_the_volume_are_warnings() {
+        let object = CpuObject::cuboid(Vec3::new(1e4, 0.0, 0.0), Vec3::new(1.0, 2e3, 1.0), 0);
+        let issues = issues_of(object, gray());
+
+        assert!(matches!(
+            issues[..],
+            [Issue::LargerThanRadianceVolume { instance: 0, .. }]
+        ));
+        assert_eq!(issues[0].severity(), Severity::Warning);
+
+        // far away, but small enough to be inside of the volume when the camera is near it
+        let object = CpuObject::cuboid(Vec3::new(1e4, 0.0, 0.0), Vec3::ONE, 0);
+        assert!(issues_of(object, gray()).is_empty());
+    }
+}
@@ -0,0 +1,360 @@
+//! Real spherical harmonics up to L2, in the coefficient order of the shaders
+//!
+//! The functions are generic over the number of coefficients, which is [L1] or [L2]. Directions
+//! are normalized and coefficients of colors are projected per channel. The renderer only projects
+//! environments, the rest mirrors the shaders for the tests.
+
+use std::f32::consts::PI;
+
+use glam::*;
+
+/// Coefficients of the bands up to L1
+pub const L1: usize = 4;
+/// Coefficients of the bands up to L2
+pub const L2: usize = 9;
+
+pub const NORM_C0: f32 = 0.282_094_8; // 1 / (2 sqrt(pi))
+pub const NORM_C1: f32 = 0.488_602_5; // sqrt(3) / (2 sqrt(pi))
+pub const NORM_C2: f32 = 1.092_548_4; // sqrt(15) / (2 sqrt(pi))
+pub const NORM_C3: f32 = 0.315_391_57; // sqrt(5) / (4 sqrt(pi))
+pub const NORM_C4: f32 = 0.546_274_2; // sqrt(15) / (4 sqrt(pi))
+
+/// Zonal coefficients of the clamped cosine lobe around +Z
+#[cfg(test)]
+pub const COS_LOBE_C0: f32 = 0.886_226_95; // sqrt(pi) / 2
+#[cfg(test)]
+pub const COS_LOBE_C1: f32 = 1.023_326_8; // sqrt(pi / 3)
+#[cfg(test)]
+pub const COS_LOBE_C2: f32 = 0.495_415_9; // sqrt(5 pi) / 8
+
+/// Scale of each band's basis functions in the cosine lobe, the convolution with the clamped
+/// cosine
+#[cfg(test)]
+const COS_LOBE_BANDS: [f32; 3] = [PI, 2.0 * PI / 3.0, PI / 4.0];
+
+#[cfg(test)]
+fn band(coef: usize) -> usize {
+    match coef {
+        0 => 0,
+        1..=3 => 1,
+        _ => 2,
+    }
+}
+
+fn check_len<const N: usize>() {
+    assert!(
+        N == L1 || N == L2,
+        "{} is not the coefficient count of L1 or L2",
+        N
+    );
+}
+
+/// Basis functions evaluated in `dir`
+pub fn basis<const N: usize>(dir: Vec3) -> [f32; N] {
+    check_len::<N>();
+    let Vec3 { x, y, z } = dir;
+
+    let all = [
+        NORM_C0,
+        NORM_C1 * y,
+        NORM_C1 * z,
+        NORM_C1 * x,
+        NORM_C2 * x * y,
+        NORM_C2 * y * z,
+        NORM_C3 * (3.0 * z * z - 1.0),
+        NORM_C2 * x * z,
+        NORM_C4 * (x * x - y * y),
+    ];
+    std::array::from_fn(|i| all[i])
+}
+
+/// Like `evaluateRGBSphericalHarmonics`
+#[cfg(test)]
+pub fn evaluate_rgb_spherical_harmonics<const N: usize>(dir: Vec3, coefs: &[Vec3; N]) -> Vec3 {
+    basis::<N>(dir)
+        .iter()
+        .zip(coefs)
+        .map(|(basis, coef)| *basis * *coef)
+        .sum()
+}
+
+/// Clamped cosine lobe around `dir`, like `dirToCosineLobe`
+#[cfg(test)]
+pub fn dir_to_cosine_lobe<const N: usize>(dir: Vec3) -> [f32; N] {
+    let basis = basis::<N>(dir);
+    std::array::from_fn(|i| COS_LOBE_BANDS[band(i)] * basis[i])
+}
+
+/// A single direction of `color`, the limit of a narrow lobe
+#[cfg(test)]
+pub fn project_direction<const N: usize>(dir: Vec3, color: Vec3) -> [Vec3; N] {
+    basis::<N>(dir).map(|basis| basis * color)
+}
+
+/// Projects the radiance of an environment by integrating over `samples` by `samples`
+/// directions, which are spaced evenly in height and angle and so cover equal areas
+pub fn project_environment<const N: usize>(
+    samples: u32,
+    radiance: impl Fn(Vec3) -> Vec3,
+) -> [Vec3; N] {
+    let weight = 4.0 * PI / (samples * samples) as f32;
+    let mut coefs = [Vec3::ZERO; N];
+
+    for i in 0..samples {
+        let z = 1.0 - 2.0 * (i as f32 + 0.5) / samples as f32;
+        let radius = (1.0 - z * z).sqrt();
+
+        for j in 0..samples {
+            let angle = 2.0 * PI * (j as f32 + 0.5) / samples as f32;
+            let dir = Vec3::new(radius * angle.cos(), radius * angle.sin(), z);
+            let color = radiance(dir);
+
+            for (coef, basis) in coefs.iter_mut().zip(basis::<N>(dir)) {
+                *coef += weight * basis * color;
+            }
+        }
+    }
+    coefs
+}
+
+/// Coefficients that `sh_rotation.glsl` negates, as it includes the Condon-Shortley phase
+#[cfg(test)]
+const CONDON_SHORTLEY: [usize; 4] = [1, 3, 5, 7];
+
+/// Rotates the coefficients of a function `f` to those of `f(rotation⁻¹ * dir)`, so a lobe
+/// around `dir` moves to `rotation * dir`
+#[cfg(test)]
+pub fn rotate<const N: usize>(coefs: &[Vec3; N], rotation: Mat3) -> [Vec3; N] {
+    check_len::<N>();
+    // the GLSL rotates by the transpose of its argument, which is the inverse of a rotation
+    let mat = rotation.transpose().to_cols_array_2d();
+
+    let mut rotated = *coefs;
+    for channel in 0..3 {
+        let mut src = [0.0; L2];
+        for (src, coef) in src.iter_mut().zip(coefs) {
+            *src = coef[channel];
+        }
+        for i in CONDON_SHORTLEY {
+            src[i] = -src[i];
+        }
+
+        let mut dst = rotate_order3(src, mat);
+        for i in CONDON_SHORTLEY {
+            dst[i] = -dst[i];
+        }
+        for (rotated, dst) in rotated.iter_mut().zip(dst) {
+            rotated[channel] = dst;
+        }
+    }
+    rotated
+}
+
+/// `rotateOrder3` of `sh_rotation.glsl`, the L2 band of L1 coefficients is zero and rotates to
+/// zero
+#[cfg(test)]
+fn rotate_order3(src: [f32; L2], mat: [[f32; 3]; 3]) -> [f32; L2] {
+    let band1 = rotate_band1([src[1], src[2], src[3]], mat);
+    let band2 = rotate_band2([src[4], src[5], src[6], src[7], src[8]], mat);
+
+    [
+        src[0], band1[0], band1[1], band1[2], band2[0], band2[1], band2[2], band2[3], band2[4],
+    ]
+}
+
+/// `rotateBand1` of `sh_rotation.glsl`, `mat` is indexed by column and row like a GLSL `mat3`
+#[cfg(test)]
+fn rotate_band1(src: [f32; 3], mat: [[f32; 3]; 3]) -> [f32; 3] {
+    [
+        mat[1][1] * src[0] - mat[1][2] * src[1] + mat[1][0] * src[2],
+        -mat[2][1] * src[0] + mat[2][2] * src[1] - mat[2][0] * src[2],
+        mat[0][1] * src[0] - mat[0][2] * src[1] + mat[0][0] * src[2],
+    ]
+}
+
+/// `rotateBand2` of `sh_rotation.glsl` by John Hable, which is public domain
+#[cfg(test)]
+fn rotate_band2(x: [f32; 5], mat: [[f32; 3]; 3]) -> [f32; 5] {
+    const C3: f32 = 0.946_174_7; // 3 sqrt(5) / (4 sqrt(pi))
+    const C4: f32 = -0.315_391_57; // -sqrt(5) / (4 sqrt(pi))
+    const C5: f32 = 0.546_274_2; // sqrt(15) / (4 sqrt(pi))
+    const SCALE: f32 = 1.0 / 0.915_291_2;
+    const SCALE_INV: f32 = 0.915_291_2;
+    const RC2: f32 = 1.585_331 * SCALE;
+    const C4_DIV_C3: f32 = C4 / C3;
+    const C4_DIV_C3_X2: f32 = 2.0 * C4 / C3;
+
+    let [[m00, m01, m02], [m10, m11, m12], [m20, m21, m22]] = mat;
+
+    // sparse matrix multiply
+    let sh = [
+        x[3] + x[4] + x[4] - x[1],
+        x[0] + RC2 * x[2] + x[3] + x[4],
+        x[0],
+        -x[3],
+        -x[1],
+    ];
+
+    // rotations, the first two use the raw matrix columns
+    let columns = [
+        (Vec3::new(m00, m10, m20), C4_DIV_C3),
+        (Vec3::new(m02, m12, m22), C4_DIV_C3),
+        (Vec3::new(m00 + m01, m10 + m11, m20 + m21), C4_DIV_C3_X2),
+        (Vec3::new(m00 + m02, m10 + m12, m20 + m22), C4_DIV_C3_X2),
+        (Vec3::new(m01 + m02, m11 + m12, m21 + m22), C4_DIV_C3_X2),
+    ];
+
+    let mut d = [0.0; 5];
+    for (sh, (r, offset)) in sh.into_iter().zip(columns) {
+        let sh_x = sh * r.x;
+        let sh_y = sh * r.y;
+        d[0] += sh_x * r.y;
+        d[1] += sh_y * r.z;
+        d[2] += sh * (r.z * r.z + offset);
+        d[3] += sh_x * r.z;
+        d[4] += sh_x * r.x - sh_y * r.y;
+    }
+
+    [
+        d[0],
+        -d[1],
+        d[2] * C3 * SCALE_INV,
+        -d[3],
+        d[4] * C5 * SCALE_INV,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: u32 = 256;
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!((a - b).abs() <= tolerance, "{} != {}", a, b);
+    }
+
+    fn directions() -> [Vec3; 4] {
+        [
+            Vec3::Z,
+            Vec3::new(1.0, -2.0, 0.5).normalize(),
+            Vec3::new(-0.3, 0.1, -1.0).normalize(),
+            Vec3::X,
+        ]
+    }
+
+    /// `#define`s of `includes_general.glsl`
+    fn shader_define(name: &str) -> f32 {
+        include_str!("../shaders/includes_general.glsl")
+            .lines()
+            .find_map(|line| {
+                let mut words = line.strip_prefix("#define ")?.split_whitespace();
+                (words.next()? == name).then(|| words.next().unwrap().parse().unwrap())
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn shader_constants_match_analytic_values() {
+        assert_close(shader_define("SH_cosLobe_C0"), PI.sqrt() / 2.0, 1e-6);
+        assert_close(shader_define("SH_cosLobe_C1"), (PI / 3.0).sqrt(), 1e-6);
+        assert_close(
+            shader_define("SH_cosLobe_C2"),
+            (5.0 * PI).sqrt() / 8.0,
+            1e-6,
+        );
+        assert_close(shader_define("SH_norm_C0"), 0.5 / PI.sqrt(), 1e-6);
+
+        assert_close(COS_LOBE_C0, PI.sqrt() / 2.0, 1e-6);
+        assert_close(COS_LOBE_C1, (PI / 3.0).sqrt(), 1e-6);
+        assert_close(COS_LOBE_C2, (5.0 * PI).sqrt() / 8.0, 1e-6);
+        assert_close(NORM_C1, 3f32.sqrt() / (2.0 * PI.sqrt()), 1e-6);
+        assert_close(NORM_C2, 15f32.sqrt() / (2.0 * PI.sqrt()), 1e-6);
+        assert_close(NORM_C3, 5f32.sqrt() / (4.0 * PI.sqrt()), 1e-6);
+        assert_close(NORM_C4, 15f32.sqrt() / (4.0 * PI.sqrt()), 1e-6);
+
+        // the zonal coefficients are the lobe around +Z
+        let lobe = dir_to_cosine_lobe::<L2>(Vec3::Z);
+        assert_close(lobe[0], COS_LOBE_C0, 1e-6);
+        assert_close(lobe[2], COS_LOBE_C1, 1e-6);
+        assert_close(lobe[6], COS_LOBE_C2, 1e-6);
+    }
+
+    #[test]
+    fn basis_is_orthonormal() {
+        for i in 0..L2 {
+            let coefs = project_environment::<L2>(SAMPLES, |dir| Vec3::splat(basis::<L2>(dir)[i]));
+            for (j, coef) in coefs.iter().enumerate() {
+                assert_close(coef.x, (i == j) as u32 as f32, 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn cosine_lobe_matches_its_projection() {
+        for normal in directions() {
+            let projected =
+                project_environment::<L2>(SAMPLES, |dir| Vec3::splat(normal.dot(dir).max(0.0)));
+            for (lobe, projected) in dir_to_cosine_lobe::<L2>(normal).iter().zip(projected) {
+                assert_close(*lobe, projected.x, 2e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn constant_environments_give_pi_irradiance() {
+        let environment = project_environment::<L1>(SAMPLES, |_| Vec3::ONE);
+        for normal in directions() {
+            let lobe = dir_to_cosine_lobe::<L1>(normal);
+            let irradiance = lobe
+                .iter()
+                .zip(environment)
+                .map(|(lobe, coef)| *lobe * coef.x)
+                .sum::<f32>();
+            assert_close(irradiance, PI, 2e-3);
+        }
+    }
+
+    #[test]
+    fn projected_directions_follow_the_addition_theorem() {
+        // the sum of the squared basis functions of a band l is (2l + 1) / (4 pi)
+        for dir in directions() {
+            let l1 =
+                evaluate_rgb_spherical_harmonics(dir, &project_direction::<L1>(dir, Vec3::ONE));
+            let l2 =
+                evaluate_rgb_spherical_harmonics(dir, &project_direction::<L2>(dir, Vec3::ONE));
+            assert_close(l1.x, 4.0 / (4.0 * PI), 1e-5);
+            assert_close(l2.y, 9.0 / (4.0 * PI), 1e-5);
+        }
+    }
+
+    #[test]
+    fn rotation_moves_lobes() {
+        let rotations = [
+            Mat3::from_rotation_z(1.0),
+            Mat3::from_rotation_x(-0.4),
+            Mat3::from_quat(Quat::from_axis_angle(
+                Vec3::new(1.0, 2.0, 3.0).normalize(),
+                2.5,
+            )),
+        ];
+
+        for rotation in rotations {
+            for dir in directions() {
+                let color = Vec3::new(1.0, 0.5, 0.25);
+
+                let rotated = rotate(&project_direction::<L1>(dir, color), rotation);
+                let expected = project_direction::<L1>(rotation * dir, color);
+                for (a, b) in rotated.iter().zip(expected) {
+                    assert!(a.abs_diff_eq(b, 1e-5), "{} != {}", a, b);
+                }
+
+                let rotated = rotate(&project_direction::<L2>(dir, color), rotation);
+                let expected = project_direction::<L2>(rotation * dir, color);
+                for (a, b) in rotated.iter().zip(expected) {
+                    assert!(a.abs_diff_eq(b, 1e-4), "{} != {}", a, b);
+                }
+            }
+        }
+    }
+}
@@ -1,41 +1,64 @@
-vulkano_shaders::shader! {
-    shaders: {
-        DirectVertex: {
-            ty: "vertex",
-            path: "shaders/direct.vert",
-        },
-        DirectFragment: {
-            ty: "fragment",
-            path: "shaders/direct.frag",
-        },
-        RadiancePrecalc: {
-            ty: "compute",
-            path: "shaders/radiancePrecalc.glsl",
-        },
-        Radiance: {
-            ty: "compute",
-            path: "shaders/radiance.glsl",
-        },
-    },
-    custom_derives: [Copy, Clone, Debug],
-    include: ["includes_general.glsl", "sh_rotation.glsl"],
-    define: [
-        ("LM_LAYERS", "4"),
-        ("RADIANCE_SIZE", "128"), // image resolution
-        ("RADIANCE_UNIT", "2.0"), // unit size in the world
-        ("MAX_MATERIALS", "32"),
-        ("SH_CS", "4")
-    ], // TODO: sync defines with consts
-    vulkan_version: "1.2", // TODO: vulkan 1.3
-    spirv_version: "1.6"
+/// The shaders with `$sh_cs` spherical harmonics coefficients per voxel, which is a macro as the
+/// defines have to be literals
+macro_rules! shaders {
+    ($sh_cs:tt) => {
+        vulkano_shaders::shader! {
+            shaders: {
+                DirectVertex: {
+                    ty: "vertex",
+                    path: "shaders/direct.vert",
+                },
+                DirectFragment: {
+                    ty: "fragment",
+                    path: "shaders/direct.frag",
+                },
+                RadiancePrecalc: {
+                    ty: "compute",
+                    path: "shaders/radiancePrecalc.glsl",
+                },
+                Radiance: {
+                    ty: "compute",
+                    path: "shaders/radiance.glsl",
+                },
+                TriangleBinning: {
+                    ty: "compute",
+                    path: "shaders/triangleBinning.glsl",
+                },
+            },
+            custom_derives: [Copy, Clone, Debug],
+            include: ["includes_general.glsl", "sh_rotation.glsl"],
+            define: [
+                ("LM_LAYERS", "4"),
+                ("RADIANCE_SIZE", "128"), // image resolution
+                ("RADIANCE_UNIT", "2.0"), // unit size in the world
+                ("SH_CS", $sh_cs),
+                ("BRICK_SIZE", "8"), // voxels per axis of the bricks triangles are binned into
+                ("BRICK_LIST_CAPACITY", "4194304") // triangle entries of all bricks combined
+            ], // TODO: sync defines with consts
+            vulkan_version: "1.2", // TODO: vulkan 1.3
+            spirv_version: "1.6"
+        }
+    };
 }
 
+#[cfg(not(feature = "sh-l2"))]
+shaders!("4");
+#[cfg(feature = "sh-l2")]
+shaders!("9");
+
 pub const LM_LAYERS: u32 = 4;
 
 pub const RADIANCE_SIZE: u32 = 128;
+pub const RADIANCE_UNIT: f32 = 2.0;
+/// spherical harmonics coefficients, L1 or L2 with the `sh-l2` feature
+#[cfg(not(feature = "sh-l2"))]
 pub const SH_CS: u32 = 4;
-
-pub const MAX_MATERIALS: usize = 32;
+#[cfg(feature = "sh-l2")]
+pub const SH_CS: u32 = 9;
+pub const BRICK_SIZE: u32 = 8;
+pub const BRICK_LIST_CAPACITY: u32 = 4194304;
+/// bricks of all layers
+pub const BRICK_COUNT: u32 = LM_LAYERS * (RADIANCE_SIZE / BRICK_SIZE).pow(3);
 
 use vulkano::device::Device;
 
@@ -48,6 +71,7 @@ pub struct Shaders {
     pub direct: DirectShaders,
     pub radiance: Arc<ShaderModule>,
     pub radiance_precalc: Arc<ShaderModule>,
+    pub triangle_binning: Arc<ShaderModule>,
 }
 
 impl Shaders {
@@ -56,6 +80,7 @@ impl Shaders {
             direct: DirectShaders::load(device.clone()),
             radiance: load_radiance(device.clone()).unwrap(),
             radiance_precalc: load_radiance_precalc(device.clone()).unwrap(),
+            triangle_binning: load_triangle_binning(device.clone()).unwrap(),
         }
     }
 }
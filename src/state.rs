@@ -2,11 +2,7 @@ use std::{f32::consts::PI, sync::Arc};
 
 use glam::{Mat4, Quat, Vec2, Vec3};
 use vulkano::{
-    device::{Device, DeviceExtensions, Features, Queue},
-    instance::debug::{
-        DebugUtilsMessageSeverity, DebugUtilsMessageType, DebugUtilsMessenger,
-        DebugUtilsMessengerCreateInfo,
-    },
+    device::{Device, DeviceExtensions, Queue},
     render_pass::{Framebuffer, RenderPass},
     swapchain::Swapchain,
 };
@@ -14,20 +10,28 @@ use winit::window::Window;
 
 use crate::{
     allocator::Allocators,
+    bake::Bake,
     buffer::Buffers,
+    clipmap::Clipmap,
     command_buffer::CommandBuffers,
     descriptor_sets::DescriptorSets,
-    device::{create_device, select_physical_device},
+    device::{create_device, required_features, select_physical_device},
     fences::Fences,
     image::Images,
     instance::create_instance,
     pipeline::Pipelines,
     render_pass,
+    scene::SceneParts,
     shaders::{self, Shaders},
     swapchain::create,
     FOV,
 };
 
+#[cfg(debug_assertions)]
+use crate::instance::create_debug_messenger;
+#[cfg(debug_assertions)]
+use vulkano::instance::debug::DebugUtilsMessenger;
+
 pub struct State {
     pub device: Arc<Device>,
     pub queue: Arc<Queue>,
@@ -42,14 +46,15 @@ pub struct State {
     pub descriptor_sets: DescriptorSets,
     pub command_buffers: CommandBuffers,
     pub real_time_data: shaders::RealTimeBuffer,
+    pub clipmap: Clipmap,
     pub fences: Fences,
     #[cfg(debug_assertions)]
     _debugger: DebugUtilsMessenger,
 }
 
 impl State {
-    pub fn new(window: Arc<Window>) -> Self {
-        let instance = create_instance();
+    pub fn new(window: Arc<Window>, scene: SceneParts) -> Self {
+        let instance = create_instance(true);
 
         let surface =
             vulkano_win::create_surface_from_winit(window.clone(), instance.clone()).unwrap();
@@ -59,14 +64,11 @@ impl State {
             ..DeviceExtensions::empty()
         };
 
-        let device_features = Features {
-            shader_buffer_float32_atomic_add: true,
-            ..Features::empty()
-        };
+        let device_features = required_features();
 
         let (physical_device, queue_family_index) = select_physical_device(
             instance.clone(),
-            &surface,
+            Some(&surface),
             &device_extensions,
             &device_features,
         );
@@ -87,7 +89,8 @@ impl State {
 
         let allocators = Allocators::new(device.clone());
 
-        let buffers = Buffers::new(allocators.clone(), queue.clone());
+        let clipmap = Clipmap::new(Vec3::ZERO);
+        let buffers = Buffers::new(allocators.clone(), queue.clone(), scene, &clipmap);
 
         let images = Images::new(
             device.clone(),
@@ -142,24 +145,7 @@ impl State {
         let fences = Fences::new(images.swapchain.len());
 
         #[cfg(debug_assertions)]
-        let debugger = unsafe {
-            DebugUtilsMessenger::new(
-                // TODO: add message_type and message_severity marker to print output
-                instance,
-                DebugUtilsMessengerCreateInfo {
-                    message_severity: DebugUtilsMessageSeverity::INFO
-                        | DebugUtilsMessageSeverity::WARNING
-                        | DebugUtilsMessageSeverity::ERROR,
-                    message_type: DebugUtilsMessageType::GENERAL
-                        | DebugUtilsMessageType::VALIDATION
-                        | DebugUtilsMessageType::PERFORMANCE,
-                    ..DebugUtilsMessengerCreateInfo::user_callback(Arc::new(|msg| {
-                        println!("[DEBUG]: {:?}", msg.description);
-                    }))
-                },
-            )
-        }
-        .unwrap();
+        let debugger = create_debug_messenger(instance);
 
         Self {
             device,
@@ -175,11 +161,37 @@ impl State {
             descriptor_sets,
             command_buffers,
             real_time_data,
+            clipmap,
             fences,
             #[cfg(debug_assertions)]
             _debugger: debugger,
         }
     }
+
+    /// Continues from a bake instead of voxelizing the volume
+    pub fn restore(&mut self, bake: &Bake) {
+        bake.restore(
+            self.allocators.clone(),
+            self.queue.clone(),
+            &self.buffers,
+            &self.images.radiance,
+        );
+        self.clipmap = Clipmap::restored(bake.corners);
+        self.command_buffers.pathtraces.volume.restored();
+    }
+
+    /// Bake of the volume as of the last submitted frame, which has to be finished
+    pub fn bake(&self, key: u64) -> Bake {
+        Bake::read_back(
+            key,
+            self.allocators.clone(),
+            self.queue.clone(),
+            &self.buffers,
+            &self.images.radiance,
+            self.command_buffers.pathtraces.volume.set(),
+            &self.clipmap,
+        )
+    }
 }
 
 pub fn projection_view_matrix(position: Vec3, rotation: Quat, screen_size: Vec2) -> Mat4 {